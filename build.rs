@@ -1,5 +1,11 @@
+use std::path::PathBuf;
+use std::process::Command;
+use std::{env, fs};
+
 fn main() {
     linker_be_nice();
+    git_hash();
+    dfu_public_key();
     println!("cargo:rustc-link-arg-tests=-Tembedded-test.x");
     println!("cargo:rustc-link-arg=-Tdefmt.x");
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
@@ -52,3 +58,44 @@ fn linker_be_nice() {
         std::env::current_exe().unwrap().display()
     );
 }
+
+/// Expose the commit being built as `GIT_HASH`, for the firmware revision.
+fn git_hash() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=GIT_HASH={hash}");
+}
+
+/// Write the key firmware images must be signed with, from `DFU_PUBLIC_KEY` (64 hex digits, see
+/// `cargo sign-image`), to `dfu_public_key.rs` for `ble::dfu`. Without it every update is rejected.
+fn dfu_public_key() {
+    println!("cargo:rerun-if-env-changed=DFU_PUBLIC_KEY");
+    let key = match env::var("DFU_PUBLIC_KEY") {
+        Ok(hex) => {
+            let hex = hex.trim();
+            let bytes: Option<Vec<u8>> = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect();
+            match bytes {
+                Some(bytes) if bytes.len() == 32 => format!("Some({bytes:?})"),
+                _ => panic!("DFU_PUBLIC_KEY must be 64 hex digits"),
+            }
+        }
+        Err(_) => {
+            println!("cargo:warning=DFU_PUBLIC_KEY is not set, firmware updates will be rejected");
+            "None".to_owned()
+        }
+    };
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("dfu_public_key.rs");
+    fs::write(out, key).unwrap();
+}
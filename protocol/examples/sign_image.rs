@@ -0,0 +1,67 @@
+//! Sign firmware images for the DFU service, see README 3.10.
+//!
+//! ```text
+//! cargo sign-image public-key <secret key file>
+//! cargo sign-image sign <secret key file> <image>
+//! ```
+//!
+//! The secret key file holds the 32 byte Ed25519 secret key in hex, e.g. from
+//! `openssl rand -hex 32`. `public-key` prints the key to build the firmware with, as
+//! `DFU_PUBLIC_KEY`. `sign` prints the start request for `dfu_control` in hex.
+use std::process::ExitCode;
+use std::{env, fs};
+
+use mpu_protocol::dfu::{ImageInfo, Request, IMAGE_MAGIC};
+use mpu_protocol::ed25519::{self, SECRET_KEY_LEN};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [command, key] if command == "public-key" => {
+            read_secret_key(key).map(|key| println!("{}", hex(&ed25519::public_key(&key))))
+        }
+        [command, key, image] if command == "sign" => read_secret_key(key).and_then(|key| {
+            let image = fs::read(image).map_err(|e| format!("reading {image}: {e}"))?;
+            if image.first() != Some(&IMAGE_MAGIC) {
+                return Err(format!(
+                    "not an app image, expected it to start with {IMAGE_MAGIC:#04x}"
+                ));
+            }
+            let mut buf = [0; Request::MAX_ENCODED_LEN];
+            let len = Request::Start(ImageInfo::sign(&key, &image)).encode(&mut buf);
+            println!("{}", hex(&buf[..len]));
+            Ok(())
+        }),
+        _ => Err("usage: sign-image public-key <secret key file>\n       \
+                  sign-image sign <secret key file> <image>"
+            .to_owned()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn read_secret_key(path: &str) -> Result<[u8; SECRET_KEY_LEN], String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("reading {path}: {e}"))?;
+    let text = text.trim();
+    let mut key = [0; SECRET_KEY_LEN];
+    if text.len() != 2 * SECRET_KEY_LEN {
+        return Err(format!(
+            "{path}: expected {} hex digits",
+            2 * SECRET_KEY_LEN
+        ));
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16)
+            .map_err(|_| format!("{path}: not hex"))?;
+    }
+    Ok(key)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
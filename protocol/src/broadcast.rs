@@ -0,0 +1,94 @@
+//! Motion summary broadcast in the advertising data, as [BTHome v2](https://bthome.io) service
+//! data (UUID `0xFCD2`), so receivers decode it without connecting.
+//!
+//! | size | object                                                            |
+//! |------|-------------------------------------------------------------------|
+//! | 1    | device info `0x40`: BTHome v2, unencrypted, regular interval      |
+//! | 2    | `0x00` packet id, counts up with every new summary                |
+//! | 2    | `0x01` battery in percent, left out without a battery             |
+//! | 2    | `0x21` motion, 1 while a read window is open                      |
+//! | 3    | `0x3F` rotation: tilt from vertical in 0.1°                       |
+//! | 3    | `0x51` acceleration: RMS of the magnitude minus 1 g, in mm/s²     |
+//! | 3    | `0x52` gyroscope: RMS of the rate magnitude, in 0.001 °/s         |
+//!
+//! Object values are little endian, objects are sorted by id as BTHome requires.
+
+/// BTHome service UUID.
+pub const SERVICE_UUID: u16 = 0xFCD2;
+
+const DEVICE_INFO: u8 = 0x40;
+const PACKET_ID: u8 = 0x00;
+const BATTERY: u8 = 0x01;
+const MOTION: u8 = 0x21;
+const ROTATION: u8 = 0x3F;
+const ACCELERATION: u8 = 0x51;
+const GYROSCOPE: u8 = 0x52;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Summary {
+    pub packet_id: u8,
+    pub battery_percent: Option<u8>,
+    pub moving: bool,
+    pub tilt_decidegrees: i16,
+    pub accel_rms_mm_s2: u16,
+    pub gyro_rms_mdeg_s: u16,
+}
+
+impl Summary {
+    pub const MAX_ENCODED_LEN: usize = 16;
+
+    /// Write the service data (after the UUID) to `out` and return its length.
+    pub fn encode(&self, out: &mut [u8; Self::MAX_ENCODED_LEN]) -> usize {
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            out[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+        push(&[DEVICE_INFO, PACKET_ID, self.packet_id]);
+        if let Some(percent) = self.battery_percent {
+            push(&[BATTERY, percent]);
+        }
+        push(&[MOTION, self.moving as u8, ROTATION]);
+        push(&self.tilt_decidegrees.to_le_bytes());
+        push(&[ACCELERATION]);
+        push(&self.accel_rms_mm_s2.to_le_bytes());
+        push(&[GYROSCOPE]);
+        push(&self.gyro_rms_mdeg_s.to_le_bytes());
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_bthome_objects() {
+        let summary = Summary {
+            packet_id: 7,
+            battery_percent: Some(85),
+            moving: true,
+            tilt_decidegrees: -900,
+            accel_rms_mm_s2: 1234,
+            gyro_rms_mdeg_s: 65535,
+        };
+        let mut out = [0; Summary::MAX_ENCODED_LEN];
+        let len = summary.encode(&mut out);
+        assert_eq!(
+            out[..len],
+            [
+                0x40, 0x00, 7, 0x01, 85, 0x21, 1, 0x3F, 0x7C, 0xFC, 0x51, 0xD2, 0x04, 0x52, 0xFF,
+                0xFF
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_a_missing_battery() {
+        let mut out = [0; Summary::MAX_ENCODED_LEN];
+        let len = Summary::default().encode(&mut out);
+        assert_eq!(len, Summary::MAX_ENCODED_LEN - 2);
+        assert_eq!(out[..5], [0x40, 0x00, 0, 0x21, 0]);
+    }
+}
@@ -0,0 +1,249 @@
+//! Sensor config packet, written to the `config_packet` characteristic to change every sensor
+//! setting at once, and the ack notified on `config_ack` once it was handled.
+//!
+//! Packet:
+//!
+//! | offset | size | field                                 |
+//! |--------|------|---------------------------------------|
+//! | 0      | 1    | version, currently 1                  |
+//! | 1      | 1    | accel scale (0–3: ±2, 4, 8, 16 g)     |
+//! | 2      | 1    | gyro scale (0–3: ±250 … 2000 °/s)     |
+//! | 3      | 1    | buzz frequency mode (0–7)             |
+//! | 4      | 1    | digital low pass filter (0–6)         |
+//! | 5      | 1    | motion detection (0 = off, 1 = on)    |
+//!
+//! Ack: a status byte ([`AckStatus`]) followed by a packet holding the config in effect
+//! afterwards, which is the previous config if the packet was rejected.
+
+/// A full sensor config. The fields hold the same values as the typed characteristics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigPacket {
+    pub accel_scale: u8,
+    pub gyro_scale: u8,
+    pub buzz_frequency_mode: u8,
+    pub filter: u8,
+    pub motion_detection: bool,
+}
+
+/// Why a packet was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigError {
+    Length,
+    Version,
+    /// The field at this packet offset is out of range.
+    Field(u8),
+}
+
+impl ConfigPacket {
+    pub const VERSION: u8 = 1;
+    pub const ENCODED_LEN: usize = 6;
+
+    /// Offsets of the fields, for [`ConfigError::Field`].
+    pub const ACCEL_SCALE_OFFSET: u8 = 1;
+    pub const GYRO_SCALE_OFFSET: u8 = 2;
+    pub const BUZZ_FREQUENCY_MODE_OFFSET: u8 = 3;
+    pub const FILTER_OFFSET: u8 = 4;
+    pub const MOTION_DETECTION_OFFSET: u8 = 5;
+
+    /// Build a packet from the fields in packet order, as `SensorConfig` converts into.
+    pub fn from_fields(fields: [u8; Self::ENCODED_LEN - 1]) -> Result<ConfigPacket, ConfigError> {
+        let [accel_scale, gyro_scale, buzz_frequency_mode, filter, motion_detection] = fields;
+        let motion_detection = match motion_detection {
+            0 => false,
+            1 => true,
+            _ => return Err(ConfigError::Field(Self::MOTION_DETECTION_OFFSET)),
+        };
+        Ok(ConfigPacket {
+            accel_scale,
+            gyro_scale,
+            buzz_frequency_mode,
+            filter,
+            motion_detection,
+        })
+    }
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        [
+            Self::VERSION,
+            self.accel_scale,
+            self.gyro_scale,
+            self.buzz_frequency_mode,
+            self.filter,
+            self.motion_detection as u8,
+        ]
+    }
+
+    /// Decode a packet. Only the layout is checked here, the firmware checks each setting's range
+    /// before applying any of them.
+    pub fn decode(buf: &[u8]) -> Result<ConfigPacket, ConfigError> {
+        let (&version, fields) = buf.split_first().ok_or(ConfigError::Length)?;
+        if version != Self::VERSION {
+            return Err(ConfigError::Version);
+        }
+        Self::from_fields(fields.try_into().map_err(|_| ConfigError::Length)?)
+    }
+}
+
+/// What the buzzer frequency follows, as held by the `buzz_frequency_mode` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BuzzFrequencyMode {
+    AccelX,
+    AccelY,
+    AccelZ,
+    GyroX,
+    GyroY,
+    GyroZ,
+    AccelMagnitude,
+    GyroMagnitude,
+}
+
+impl BuzzFrequencyMode {
+    pub fn from_u8(value: u8) -> Option<BuzzFrequencyMode> {
+        match value {
+            0 => Some(BuzzFrequencyMode::AccelX),
+            1 => Some(BuzzFrequencyMode::AccelY),
+            2 => Some(BuzzFrequencyMode::AccelZ),
+            3 => Some(BuzzFrequencyMode::GyroX),
+            4 => Some(BuzzFrequencyMode::GyroY),
+            5 => Some(BuzzFrequencyMode::GyroZ),
+            6 => Some(BuzzFrequencyMode::AccelMagnitude),
+            7 => Some(BuzzFrequencyMode::GyroMagnitude),
+            _ => None,
+        }
+    }
+}
+
+impl From<BuzzFrequencyMode> for u8 {
+    fn from(mode: BuzzFrequencyMode) -> Self {
+        match mode {
+            BuzzFrequencyMode::AccelX => 0,
+            BuzzFrequencyMode::AccelY => 1,
+            BuzzFrequencyMode::AccelZ => 2,
+            BuzzFrequencyMode::GyroX => 3,
+            BuzzFrequencyMode::GyroY => 4,
+            BuzzFrequencyMode::GyroZ => 5,
+            BuzzFrequencyMode::AccelMagnitude => 6,
+            BuzzFrequencyMode::GyroMagnitude => 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum AckStatus {
+    Applied = 0,
+    InvalidLength = 1,
+    UnsupportedVersion = 2,
+    InvalidField = 3,
+}
+
+impl From<ConfigError> for AckStatus {
+    fn from(error: ConfigError) -> Self {
+        match error {
+            ConfigError::Length => AckStatus::InvalidLength,
+            ConfigError::Version => AckStatus::UnsupportedVersion,
+            ConfigError::Field(_) => AckStatus::InvalidField,
+        }
+    }
+}
+
+impl AckStatus {
+    pub fn from_u8(value: u8) -> Option<AckStatus> {
+        match value {
+            0 => Some(AckStatus::Applied),
+            1 => Some(AckStatus::InvalidLength),
+            2 => Some(AckStatus::UnsupportedVersion),
+            3 => Some(AckStatus::InvalidField),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigAck {
+    pub status: AckStatus,
+    /// The config in effect.
+    pub config: ConfigPacket,
+}
+
+impl ConfigAck {
+    pub const ENCODED_LEN: usize = 1 + ConfigPacket::ENCODED_LEN;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0u8; Self::ENCODED_LEN];
+        out[0] = self.status as u8;
+        out[1..].copy_from_slice(&self.config.encode());
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<ConfigAck> {
+        let (&status, config) = buf.split_first()?;
+        Some(ConfigAck {
+            status: AckStatus::from_u8(status)?,
+            config: ConfigPacket::decode(config).ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET: ConfigPacket = ConfigPacket {
+        accel_scale: 1,
+        gyro_scale: 3,
+        buzz_frequency_mode: 6,
+        filter: 2,
+        motion_detection: true,
+    };
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(PACKET.encode(), [1, 1, 3, 6, 2, 1]);
+        assert_eq!(ConfigPacket::decode(&PACKET.encode()), Ok(PACKET));
+        assert_eq!(ConfigPacket::from_fields([1, 3, 6, 2, 1]), Ok(PACKET));
+
+        let ack = ConfigAck {
+            status: AckStatus::InvalidField,
+            config: PACKET,
+        };
+        assert_eq!(ack.encode()[0], 3);
+        assert_eq!(ConfigAck::decode(&ack.encode()), Some(ack));
+    }
+
+    #[test]
+    fn rejects_bad_packets() {
+        let packet = PACKET.encode();
+        assert_eq!(ConfigPacket::decode(&[]), Err(ConfigError::Length));
+        assert_eq!(ConfigPacket::decode(&packet[..5]), Err(ConfigError::Length));
+        assert_eq!(
+            ConfigPacket::decode(&[2, 1, 3, 6, 2, 1]),
+            Err(ConfigError::Version)
+        );
+        assert_eq!(
+            ConfigPacket::decode(&[1, 1, 3, 6, 2, 2]),
+            Err(ConfigError::Field(ConfigPacket::MOTION_DETECTION_OFFSET))
+        );
+        assert_eq!(
+            AckStatus::from(ConfigError::Field(1)),
+            AckStatus::InvalidField
+        );
+        assert_eq!(ConfigAck::decode(&[4, 1, 1, 3, 6, 2, 1]), None);
+    }
+
+    #[test]
+    fn buzz_frequency_modes() {
+        for value in 0..8 {
+            let mode = BuzzFrequencyMode::from_u8(value).unwrap();
+            assert_eq!(u8::from(mode), value);
+        }
+        // Out of range modes are rejected rather than replaced by a default.
+        assert_eq!(BuzzFrequencyMode::from_u8(8), None);
+        assert_eq!(BuzzFrequencyMode::from_u8(0xff), None);
+    }
+}
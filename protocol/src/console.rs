@@ -0,0 +1,368 @@
+//! Line based command shell spoken over the console (Nordic UART) service.
+//!
+//! One command per line:
+//!
+//! | command                 | effect                                            |
+//! |-------------------------|---------------------------------------------------|
+//! | `help`                  | list the commands and settings                    |
+//! | `status`                | uptime, stream and link state                     |
+//! | `calibrate`             | recalibrate the sensor offsets                    |
+//! | `dump errors`           | print the most recent errors                      |
+//! | `pair`                  | open a pairing window, see `security` mode 2      |
+//! | `get <setting>`         | print a setting                                   |
+//! | `set <setting> <value>` | change a setting, like writing its characteristic |
+//!
+//! Settings are named after the typed characteristics they mirror and take the same values,
+//! written as decimal numbers (`on`/`off` or `1`/`0` for switches).
+
+use core::fmt;
+
+/// A setting that can be changed over both its characteristic and the console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Setting {
+    MotionReadDuration,
+    MotionSampleInterval,
+    ContinuousSampleInterval,
+    PlaySound,
+    AccelScale,
+    GyroScale,
+    BuzzFrequencyMode,
+    MinBuzzValue,
+    MaxBuzzValue,
+    Filter,
+    MotionDetection,
+    StreamMode,
+    /// Whether control writes need an encrypted link and connections a bond: 0 open, 1 encrypted,
+    /// 2 allow list.
+    Security,
+    /// Battery voltage over the voltage at the ADC pin.
+    BatteryDivider,
+    /// Milliseconds between broadcast summaries in the advertising data, 0 for off.
+    BroadcastInterval,
+    /// Milliseconds between periodic advertising events carrying samples, 0 for off.
+    PeriodicInterval,
+    /// Whether read windows are recorded to flash.
+    Recording,
+    /// Milliseconds of samples before the motion interrupt that start a read window, 0 for off.
+    PreTrigger,
+}
+
+/// How a setting's value is stored in its characteristic (little endian).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ValueKind {
+    Bool,
+    U8,
+    U16,
+    U64,
+    F32,
+}
+
+impl ValueKind {
+    pub const fn encoded_len(self) -> usize {
+        match self {
+            ValueKind::Bool | ValueKind::U8 => 1,
+            ValueKind::U16 => 2,
+            ValueKind::U64 => 8,
+            ValueKind::F32 => 4,
+        }
+    }
+}
+
+impl Setting {
+    pub const ALL: [Setting; 18] = [
+        Setting::MotionReadDuration,
+        Setting::MotionSampleInterval,
+        Setting::ContinuousSampleInterval,
+        Setting::PlaySound,
+        Setting::AccelScale,
+        Setting::GyroScale,
+        Setting::BuzzFrequencyMode,
+        Setting::MinBuzzValue,
+        Setting::MaxBuzzValue,
+        Setting::Filter,
+        Setting::MotionDetection,
+        Setting::StreamMode,
+        Setting::Security,
+        Setting::BatteryDivider,
+        Setting::BroadcastInterval,
+        Setting::PeriodicInterval,
+        Setting::Recording,
+        Setting::PreTrigger,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Setting::MotionReadDuration => "motion_read_duration",
+            Setting::MotionSampleInterval => "motion_sample_interval",
+            Setting::ContinuousSampleInterval => "continuous_sample_interval",
+            Setting::PlaySound => "play_sound",
+            Setting::AccelScale => "accel_scale",
+            Setting::GyroScale => "gyro_scale",
+            Setting::BuzzFrequencyMode => "buzz_frequency_mode",
+            Setting::MinBuzzValue => "min_buzz_value",
+            Setting::MaxBuzzValue => "max_buzz_value",
+            Setting::Filter => "filter",
+            Setting::MotionDetection => "motion_detection",
+            Setting::StreamMode => "stream_mode",
+            Setting::Security => "security",
+            Setting::BatteryDivider => "battery_divider",
+            Setting::BroadcastInterval => "broadcast_interval",
+            Setting::PeriodicInterval => "periodic_interval",
+            Setting::Recording => "recording",
+            Setting::PreTrigger => "pre_trigger",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Setting> {
+        Setting::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    pub const fn kind(self) -> ValueKind {
+        match self {
+            Setting::MotionReadDuration
+            | Setting::BroadcastInterval
+            | Setting::PeriodicInterval
+            | Setting::PreTrigger => ValueKind::U16,
+            Setting::MotionSampleInterval | Setting::ContinuousSampleInterval => ValueKind::U64,
+            Setting::PlaySound | Setting::MotionDetection | Setting::Recording => ValueKind::Bool,
+            Setting::AccelScale
+            | Setting::GyroScale
+            | Setting::BuzzFrequencyMode
+            | Setting::Filter
+            | Setting::StreamMode
+            | Setting::Security => ValueKind::U8,
+            Setting::MinBuzzValue | Setting::MaxBuzzValue | Setting::BatteryDivider => {
+                ValueKind::F32
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Value {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U64(u64),
+    F32(f32),
+}
+
+impl Value {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Value::Bool(_) => ValueKind::Bool,
+            Value::U8(_) => ValueKind::U8,
+            Value::U16(_) => ValueKind::U16,
+            Value::U64(_) => ValueKind::U64,
+            Value::F32(_) => ValueKind::F32,
+        }
+    }
+
+    /// Decode a characteristic value, which must be exactly as long as `kind` needs.
+    pub fn decode(kind: ValueKind, buf: &[u8]) -> Option<Value> {
+        if buf.len() != kind.encoded_len() {
+            return None;
+        }
+        Some(match kind {
+            ValueKind::Bool => Value::Bool(buf[0] != 0),
+            ValueKind::U8 => Value::U8(buf[0]),
+            ValueKind::U16 => Value::U16(u16::from_le_bytes([buf[0], buf[1]])),
+            ValueKind::U64 => Value::U64(u64::from_le_bytes(buf.try_into().ok()?)),
+            ValueKind::F32 => Value::F32(f32::from_le_bytes(buf.try_into().ok()?)),
+        })
+    }
+
+    /// Parse a value typed on the console.
+    pub fn parse(kind: ValueKind, text: &str) -> Option<Value> {
+        match kind {
+            ValueKind::Bool => match text {
+                "1" | "on" | "true" => Some(Value::Bool(true)),
+                "0" | "off" | "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            ValueKind::U8 => text.parse().ok().map(Value::U8),
+            ValueKind::U16 => text.parse().ok().map(Value::U16),
+            ValueKind::U64 => text.parse().ok().map(Value::U64),
+            ValueKind::F32 => text
+                .parse()
+                .ok()
+                .filter(|v: &f32| v.is_finite())
+                .map(Value::F32),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(v) => f.write_str(if *v { "on" } else { "off" }),
+            Value::U8(v) => write!(f, "{}", v),
+            Value::U16(v) => write!(f, "{}", v),
+            Value::U64(v) => write!(f, "{}", v),
+            Value::F32(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    Help,
+    Status,
+    Calibrate,
+    DumpErrors,
+    Pair,
+    Get(Setting),
+    Set(Setting, Value),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    UnknownCommand,
+    UnknownSetting,
+    MissingArgument,
+    UnexpectedArgument,
+    InvalidValue,
+}
+
+impl ParseError {
+    pub const fn message(self) -> &'static str {
+        match self {
+            ParseError::UnknownCommand => "unknown command, try `help`",
+            ParseError::UnknownSetting => "unknown setting, try `help`",
+            ParseError::MissingArgument => "missing argument",
+            ParseError::UnexpectedArgument => "too many arguments",
+            ParseError::InvalidValue => "invalid value",
+        }
+    }
+}
+
+impl Command {
+    /// Parse one line. Words are separated by any amount of whitespace.
+    pub fn parse(line: &str) -> Result<Command, ParseError> {
+        let mut words = line.split_ascii_whitespace();
+        let command = match words.next().ok_or(ParseError::UnknownCommand)? {
+            "help" => Command::Help,
+            "status" => Command::Status,
+            "calibrate" => Command::Calibrate,
+            "dump" => match words.next() {
+                Some("errors") => Command::DumpErrors,
+                Some(_) => return Err(ParseError::UnknownCommand),
+                None => return Err(ParseError::MissingArgument),
+            },
+            "pair" => Command::Pair,
+            "get" => Command::Get(setting(words.next())?),
+            "set" => {
+                let setting = setting(words.next())?;
+                let text = words.next().ok_or(ParseError::MissingArgument)?;
+                let value = Value::parse(setting.kind(), text).ok_or(ParseError::InvalidValue)?;
+                Command::Set(setting, value)
+            }
+            _ => return Err(ParseError::UnknownCommand),
+        };
+        match words.next() {
+            Some(_) => Err(ParseError::UnexpectedArgument),
+            None => Ok(command),
+        }
+    }
+}
+
+fn setting(word: Option<&str>) -> Result<Setting, ParseError> {
+    Setting::from_name(word.ok_or(ParseError::MissingArgument)?).ok_or(ParseError::UnknownSetting)
+}
+
+/// Split what was written to the console into non-empty lines. A line ends at a CR, an LF or the
+/// end of the write, since terminal apps differ in whether they send a line ending.
+pub fn lines(input: &str) -> impl Iterator<Item = &str> {
+    input
+        .split(['\r', '\n'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("help"), Ok(Command::Help));
+        assert_eq!(Command::parse("  status "), Ok(Command::Status));
+        assert_eq!(Command::parse("calibrate"), Ok(Command::Calibrate));
+        assert_eq!(Command::parse("dump  errors"), Ok(Command::DumpErrors));
+        assert_eq!(Command::parse("pair"), Ok(Command::Pair));
+        assert_eq!(
+            Command::parse("get accel_scale"),
+            Ok(Command::Get(Setting::AccelScale))
+        );
+        assert_eq!(
+            Command::parse("set filter 3"),
+            Ok(Command::Set(Setting::Filter, Value::U8(3)))
+        );
+        assert_eq!(
+            Command::parse("set play_sound on"),
+            Ok(Command::Set(Setting::PlaySound, Value::Bool(true)))
+        );
+        assert_eq!(
+            Command::parse("set min_buzz_value 0.25"),
+            Ok(Command::Set(Setting::MinBuzzValue, Value::F32(0.25)))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert_eq!(Command::parse(""), Err(ParseError::UnknownCommand));
+        assert_eq!(Command::parse("reboot"), Err(ParseError::UnknownCommand));
+        assert_eq!(Command::parse("dump"), Err(ParseError::MissingArgument));
+        assert_eq!(Command::parse("get"), Err(ParseError::MissingArgument));
+        assert_eq!(
+            Command::parse("get volume"),
+            Err(ParseError::UnknownSetting)
+        );
+        assert_eq!(
+            Command::parse("set filter"),
+            Err(ParseError::MissingArgument)
+        );
+        assert_eq!(
+            Command::parse("set filter 300"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            Command::parse("set max_buzz_value nan"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            Command::parse("status now"),
+            Err(ParseError::UnexpectedArgument)
+        );
+    }
+
+    #[test]
+    fn values_round_trip_through_characteristic_bytes() {
+        for setting in Setting::ALL {
+            assert_eq!(Setting::from_name(setting.name()), Some(setting));
+            let kind = setting.kind();
+            let bytes = [1u8; 9];
+            let value = Value::decode(kind, &bytes[..kind.encoded_len()]).unwrap();
+            assert_eq!(value.kind(), kind);
+            assert_eq!(Value::decode(kind, &bytes[..kind.encoded_len() + 1]), None);
+        }
+        assert_eq!(
+            Value::decode(ValueKind::U16, &[0x2C, 0x01]),
+            Some(Value::U16(300))
+        );
+    }
+
+    #[test]
+    fn splits_lines() {
+        let mut it = lines("status\r\nget filter\n\n  help  ");
+        assert_eq!(it.next(), Some("status"));
+        assert_eq!(it.next(), Some("get filter"));
+        assert_eq!(it.next(), Some("help"));
+        assert_eq!(it.next(), None);
+    }
+}
@@ -0,0 +1,756 @@
+//! Firmware update over BLE (DFU): the messages of the DFU service and the state machine writing a
+//! received image to the inactive OTA partition.
+//!
+//! An update goes:
+//!
+//! 1. The client writes a start request to `dfu_control` with the image size, CRC-32, SHA-256 and
+//!    the Ed25519 signature of the SHA-256. A signature that doesn't verify against the public key
+//!    the firmware was built with rejects the image before any of it is sent.
+//! 2. It writes the image to `dfu_data` in chunks, each prefixed with its offset in the image.
+//!    Chunks must arrive in order. After a dropped connection it reads `dfu_status` and carries on
+//!    from the offset reported there.
+//! 3. It writes a finish request. The image is checked against the CRC-32 and SHA-256, read back
+//!    from flash and hashed again, and made the boot partition. Matching the signed SHA-256 is what
+//!    ties the image to the signature.
+//!
+//! Control request:
+//!
+//! | opcode | request | payload                                                                   |
+//! |--------|---------|---------------------------------------------------------------------------|
+//! | `0x01` | start   | `u32` image size, `u32` CRC-32, 32 byte SHA-256, 64 byte Ed25519 signature |
+//! | `0x02` | finish  |                                                                           |
+//! | `0x03` | abort   |                                                                           |
+//!
+//! Data chunk: `u32` offset of the chunk in the image, then the image bytes.
+//!
+//! Status: `u8` [`State`], `u8` [`DfuError`] of the last request (0 if it succeeded), `u32` image
+//! bytes received. All integers are little endian.
+use crate::ed25519::{self, PUBLIC_KEY_LEN, SECRET_KEY_LEN, SIGNATURE_LEN};
+use crate::hash::{crc32, sha256, Crc32, Sha256};
+
+/// Size of a flash sector, the unit the image is written in.
+pub const SECTOR_SIZE: usize = 4096;
+
+/// First byte of every ESP app image.
+pub const IMAGE_MAGIC: u8 = 0xE9;
+
+const START: u8 = 0x01;
+const FINISH: u8 = 0x02;
+const ABORT: u8 = 0x03;
+
+/// The image announced by a start request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageInfo {
+    pub size: u32,
+    pub crc32: u32,
+    pub sha256: [u8; Sha256::DIGEST_LEN],
+    /// Ed25519 signature of `sha256`.
+    pub signature: [u8; SIGNATURE_LEN],
+}
+
+impl ImageInfo {
+    /// Describe and sign `image`, for the start request. This is what the host tool does.
+    pub fn sign(secret_key: &[u8; SECRET_KEY_LEN], image: &[u8]) -> ImageInfo {
+        let sha256 = sha256(image);
+        ImageInfo {
+            size: image.len() as u32,
+            crc32: crc32(image),
+            sha256,
+            signature: ed25519::sign(secret_key, &sha256),
+        }
+    }
+}
+
+/// A write to `dfu_control`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Request {
+    /// Start receiving an image, dropping any earlier one.
+    Start(ImageInfo),
+    /// Verify the received image and boot it on the next restart.
+    Finish,
+    /// Drop the image received so far.
+    Abort,
+}
+
+impl Request {
+    pub const MAX_ENCODED_LEN: usize = 1 + 4 + 4 + Sha256::DIGEST_LEN + SIGNATURE_LEN;
+
+    pub fn decode(buf: &[u8]) -> Result<Request, DfuError> {
+        match buf {
+            [START, rest @ ..] if rest.len() == Self::MAX_ENCODED_LEN - 1 => {
+                let (sha256, signature) = rest[8..].split_at(Sha256::DIGEST_LEN);
+                let mut image = ImageInfo {
+                    size: u32_at(rest, 0),
+                    crc32: u32_at(rest, 4),
+                    sha256: [0; Sha256::DIGEST_LEN],
+                    signature: [0; SIGNATURE_LEN],
+                };
+                image.sha256.copy_from_slice(sha256);
+                image.signature.copy_from_slice(signature);
+                Ok(Request::Start(image))
+            }
+            [FINISH] => Ok(Request::Finish),
+            [ABORT] => Ok(Request::Abort),
+            _ => Err(DfuError::Malformed),
+        }
+    }
+
+    /// Write the request to `out` and return its length.
+    pub fn encode(&self, out: &mut [u8; Self::MAX_ENCODED_LEN]) -> usize {
+        match self {
+            Request::Start(image) => {
+                out[0] = START;
+                out[1..5].copy_from_slice(&image.size.to_le_bytes());
+                out[5..9].copy_from_slice(&image.crc32.to_le_bytes());
+                out[9..41].copy_from_slice(&image.sha256);
+                out[41..].copy_from_slice(&image.signature);
+                Self::MAX_ENCODED_LEN
+            }
+            Request::Finish => {
+                out[0] = FINISH;
+                1
+            }
+            Request::Abort => {
+                out[0] = ABORT;
+                1
+            }
+        }
+    }
+}
+
+/// A write to `dfu_data`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    /// Offset of `data` in the image.
+    pub offset: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    pub const HEADER_LEN: usize = 4;
+
+    pub fn decode(buf: &'a [u8]) -> Result<Chunk<'a>, DfuError> {
+        if buf.len() < Self::HEADER_LEN {
+            return Err(DfuError::Malformed);
+        }
+        Ok(Chunk {
+            offset: u32_at(buf, 0),
+            data: &buf[Self::HEADER_LEN..],
+        })
+    }
+
+    /// Write the chunk to `out` and return its length, or `None` if it doesn't fit.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let len = Self::HEADER_LEN + self.data.len();
+        let out = out.get_mut(..len)?;
+        out[..Self::HEADER_LEN].copy_from_slice(&self.offset.to_le_bytes());
+        out[Self::HEADER_LEN..].copy_from_slice(self.data);
+        Some(len)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum State {
+    /// No update in progress.
+    #[default]
+    Idle = 0,
+    /// Waiting for chunks or the finish request.
+    Receiving = 1,
+    /// The image was verified and boots on the next restart.
+    Complete = 2,
+    /// The image was rejected, a new start request is needed.
+    Failed = 3,
+}
+
+impl State {
+    pub fn from_u8(value: u8) -> Option<State> {
+        match value {
+            0 => Some(State::Idle),
+            1 => Some(State::Receiving),
+            2 => Some(State::Complete),
+            3 => Some(State::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Why a request was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum DfuError {
+    /// Unknown opcode or wrong length.
+    Malformed = 1,
+    /// Not possible in the current [`State`].
+    State = 2,
+    /// The image is empty or doesn't fit the partition, or a chunk goes past its end.
+    Size = 3,
+    /// The chunk doesn't start where the previous one ended, see the status for where that is.
+    Offset = 4,
+    /// Finish before the whole image was received.
+    Incomplete = 5,
+    /// The image doesn't start with [`IMAGE_MAGIC`].
+    NotAnImage = 6,
+    /// The CRC-32 of the received image doesn't match.
+    Crc = 7,
+    /// The SHA-256 of the received image doesn't match.
+    Sha256 = 8,
+    /// The image read back from flash differs from the one received.
+    Verify = 9,
+    /// Erasing, writing or reading the flash, or switching the boot partition failed.
+    Flash = 10,
+    /// The signature doesn't verify, or the firmware was built without a public key.
+    Signature = 11,
+}
+
+impl DfuError {
+    pub fn from_u8(value: u8) -> Option<DfuError> {
+        match value {
+            1 => Some(DfuError::Malformed),
+            2 => Some(DfuError::State),
+            3 => Some(DfuError::Size),
+            4 => Some(DfuError::Offset),
+            5 => Some(DfuError::Incomplete),
+            6 => Some(DfuError::NotAnImage),
+            7 => Some(DfuError::Crc),
+            8 => Some(DfuError::Sha256),
+            9 => Some(DfuError::Verify),
+            10 => Some(DfuError::Flash),
+            11 => Some(DfuError::Signature),
+            _ => None,
+        }
+    }
+}
+
+/// The value of `dfu_status`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Status {
+    pub state: State,
+    /// Error of the last request, `None` if it succeeded.
+    pub error: Option<DfuError>,
+    /// Image bytes received, the offset of the next chunk.
+    pub received: u32,
+}
+
+impl Status {
+    pub const ENCODED_LEN: usize = 6;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let [r0, r1, r2, r3] = self.received.to_le_bytes();
+        let error = self.error.map_or(0, |e| e as u8);
+        [self.state as u8, error, r0, r1, r2, r3]
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Status> {
+        if buf.len() != Self::ENCODED_LEN {
+            return None;
+        }
+        let error = match buf[1] {
+            0 => None,
+            e => Some(DfuError::from_u8(e)?),
+        };
+        Some(Status {
+            state: State::from_u8(buf[0])?,
+            error,
+            received: u32_at(buf, 2),
+        })
+    }
+}
+
+/// The partition the image is written to, and the OTA data that selects the boot partition.
+pub trait ImageFlash {
+    type Error;
+
+    /// Size of the partition in bytes.
+    fn capacity(&self) -> u32;
+
+    /// Erase the sector at `offset`, a multiple of [`SECTOR_SIZE`], and write `data` to it.
+    fn write_sector(&mut self, offset: u32, data: &[u8; SECTOR_SIZE]) -> Result<(), Self::Error>;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Boot from the partition on the next restart.
+    fn activate(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Receives an image, see the module docs.
+pub struct Updater {
+    /// The key images must be signed with, `None` rejects all of them.
+    public_key: Option<[u8; PUBLIC_KEY_LEN]>,
+    state: State,
+    error: Option<DfuError>,
+    image: ImageInfo,
+    received: u32,
+    crc: Crc32,
+    /// Started with each transfer, `sha2` cannot build one in a `const fn`.
+    sha: Option<Sha256>,
+    /// The sector being received, written once full.
+    sector: [u8; SECTOR_SIZE],
+}
+
+impl Updater {
+    pub const fn new(public_key: Option<[u8; PUBLIC_KEY_LEN]>) -> Self {
+        Self {
+            public_key,
+            state: State::Idle,
+            error: None,
+            image: ImageInfo {
+                size: 0,
+                crc32: 0,
+                sha256: [0; Sha256::DIGEST_LEN],
+                signature: [0; SIGNATURE_LEN],
+            },
+            received: 0,
+            crc: Crc32::new(),
+            sha: None,
+            sector: [0; SECTOR_SIZE],
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            state: self.state,
+            error: self.error,
+            received: self.received,
+        }
+    }
+
+    /// Handle a write to `dfu_control`.
+    pub fn control<F: ImageFlash>(
+        &mut self,
+        flash: &mut F,
+        request: Request,
+    ) -> Result<(), DfuError> {
+        let result = match request {
+            Request::Start(image) => self.start(flash, image),
+            Request::Finish => self.finish(flash),
+            Request::Abort => {
+                self.state = State::Idle;
+                self.received = 0;
+                Ok(())
+            }
+        };
+        self.error = result.err();
+        result
+    }
+
+    /// Handle a write to `dfu_data`.
+    pub fn write<F: ImageFlash>(
+        &mut self,
+        flash: &mut F,
+        chunk: Chunk<'_>,
+    ) -> Result<(), DfuError> {
+        let result = self.receive(flash, chunk);
+        self.error = result.err();
+        result
+    }
+
+    /// Reject a malformed write, which never reaches [`Updater::control`] or [`Updater::write`].
+    pub fn reject(&mut self, error: DfuError) {
+        self.error = Some(error);
+    }
+
+    fn start<F: ImageFlash>(&mut self, flash: &mut F, image: ImageInfo) -> Result<(), DfuError> {
+        if image.size == 0 || image.size > flash.capacity() {
+            return Err(DfuError::Size);
+        }
+        let signed = self
+            .public_key
+            .is_some_and(|key| ed25519::verify(&key, &image.sha256, &image.signature));
+        if !signed {
+            return Err(DfuError::Signature);
+        }
+        self.state = State::Receiving;
+        self.image = image;
+        self.received = 0;
+        self.crc = Crc32::new();
+        self.sha = Some(Sha256::new());
+        Ok(())
+    }
+
+    fn receive<F: ImageFlash>(&mut self, flash: &mut F, chunk: Chunk<'_>) -> Result<(), DfuError> {
+        if self.state != State::Receiving {
+            return Err(DfuError::State);
+        }
+        if chunk.offset != self.received {
+            return Err(DfuError::Offset);
+        }
+        let end = chunk.offset as u64 + chunk.data.len() as u64;
+        if end > self.image.size as u64 {
+            return Err(DfuError::Size);
+        }
+        if self.received == 0 && chunk.data.first().is_some_and(|b| *b != IMAGE_MAGIC) {
+            return self.fail(DfuError::NotAnImage);
+        }
+        self.crc.update(chunk.data);
+        if let Some(sha) = &mut self.sha {
+            sha.update(chunk.data);
+        }
+        let mut data = chunk.data;
+        while !data.is_empty() {
+            let at = self.received as usize % SECTOR_SIZE;
+            let take = (SECTOR_SIZE - at).min(data.len());
+            self.sector[at..at + take].copy_from_slice(&data[..take]);
+            self.received += take as u32;
+            data = &data[take..];
+            if at + take == SECTOR_SIZE {
+                self.write_sector(flash)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish<F: ImageFlash>(&mut self, flash: &mut F) -> Result<(), DfuError> {
+        if self.state != State::Receiving {
+            return Err(DfuError::State);
+        }
+        if self.received != self.image.size {
+            return Err(DfuError::Incomplete);
+        }
+        let rest = self.received as usize % SECTOR_SIZE;
+        if rest != 0 {
+            self.sector[rest..].fill(0xff);
+            self.write_sector(flash)?;
+        }
+        if self.crc.finish() != self.image.crc32 {
+            return self.fail(DfuError::Crc);
+        }
+        if self.sha.take().map(Sha256::finish) != Some(self.image.sha256) {
+            return self.fail(DfuError::Sha256);
+        }
+        // Catch sectors that didn't take, before the bootloader finds them.
+        let mut sha = Sha256::new();
+        let mut offset = 0;
+        while offset < self.image.size {
+            let len = (self.image.size - offset).min(SECTOR_SIZE as u32) as usize;
+            if flash.read(offset, &mut self.sector[..len]).is_err() {
+                return self.fail(DfuError::Flash);
+            }
+            sha.update(&self.sector[..len]);
+            offset += len as u32;
+        }
+        if sha.finish() != self.image.sha256 {
+            return self.fail(DfuError::Verify);
+        }
+        if flash.activate().is_err() {
+            return self.fail(DfuError::Flash);
+        }
+        self.state = State::Complete;
+        Ok(())
+    }
+
+    /// Write the sector the last received byte is in.
+    fn write_sector<F: ImageFlash>(&mut self, flash: &mut F) -> Result<(), DfuError> {
+        let offset = (self.received - 1) / SECTOR_SIZE as u32 * SECTOR_SIZE as u32;
+        match flash.write_sector(offset, &self.sector) {
+            Ok(()) => Ok(()),
+            Err(_) => self.fail(DfuError::Flash),
+        }
+    }
+
+    fn fail(&mut self, error: DfuError) -> Result<(), DfuError> {
+        self.state = State::Failed;
+        Err(error)
+    }
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::XorShift;
+
+    const SECTORS: usize = 4;
+    const IMAGE_LEN: usize = 10_000;
+    const SECRET_KEY: [u8; SECRET_KEY_LEN] = [0x5e; SECRET_KEY_LEN];
+
+    /// A partition in RAM.
+    struct MemFlash {
+        data: [u8; SECTORS * SECTOR_SIZE],
+        erases: usize,
+        active: bool,
+        /// Flip a bit of every written sector, as a worn out flash might.
+        corrupt: bool,
+    }
+
+    impl MemFlash {
+        fn new() -> Self {
+            Self {
+                data: [0xff; SECTORS * SECTOR_SIZE],
+                erases: 0,
+                active: false,
+                corrupt: false,
+            }
+        }
+    }
+
+    impl ImageFlash for MemFlash {
+        type Error = ();
+
+        fn capacity(&self) -> u32 {
+            self.data.len() as u32
+        }
+
+        fn write_sector(&mut self, offset: u32, data: &[u8; SECTOR_SIZE]) -> Result<(), ()> {
+            assert_eq!(offset as usize % SECTOR_SIZE, 0);
+            let sector = &mut self.data[offset as usize..offset as usize + SECTOR_SIZE];
+            sector.copy_from_slice(data);
+            if self.corrupt {
+                sector[7] ^= 0x10;
+            }
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn activate(&mut self) -> Result<(), ()> {
+            self.active = true;
+            Ok(())
+        }
+    }
+
+    fn image(rng: &mut XorShift) -> [u8; IMAGE_LEN] {
+        let mut image = [0; IMAGE_LEN];
+        rng.fill(&mut image);
+        image[0] = IMAGE_MAGIC;
+        image
+    }
+
+    fn info(image: &[u8]) -> ImageInfo {
+        ImageInfo::sign(&SECRET_KEY, image)
+    }
+
+    fn updater() -> Updater {
+        Updater::new(Some(ed25519::public_key(&SECRET_KEY)))
+    }
+
+    /// Send `image[from..]` in chunks of random length, through the wire encoding.
+    fn send(updater: &mut Updater, flash: &mut MemFlash, image: &[u8], from: usize) {
+        let mut rng = XorShift::new(from as u64 + 1);
+        let mut offset = from;
+        let mut buf = [0; 512];
+        while offset < image.len() {
+            let len = (rng.below(508) + 1).min(image.len() - offset);
+            let chunk = Chunk {
+                offset: offset as u32,
+                data: &image[offset..offset + len],
+            };
+            let written = chunk.encode(&mut buf).unwrap();
+            let chunk = Chunk::decode(&buf[..written]).unwrap();
+            assert_eq!(updater.write(flash, chunk), Ok(()));
+            offset += len;
+        }
+    }
+
+    #[test]
+    fn writes_verifies_and_activates_an_image() {
+        let image = image(&mut XorShift::new(1));
+        let mut flash = MemFlash::new();
+        let mut updater = updater();
+        let mut buf = [0; Request::MAX_ENCODED_LEN];
+        let len = Request::Start(info(&image)).encode(&mut buf);
+        let start = Request::decode(&buf[..len]).unwrap();
+        assert_eq!(updater.control(&mut flash, start), Ok(()));
+        send(&mut updater, &mut flash, &image, 0);
+        assert!(!flash.active);
+        assert_eq!(updater.control(&mut flash, Request::Finish), Ok(()));
+        assert!(flash.active);
+        assert_eq!(flash.erases, IMAGE_LEN.div_ceil(SECTOR_SIZE));
+        assert_eq!(flash.data[..IMAGE_LEN], image);
+        assert!(flash.data[IMAGE_LEN..3 * SECTOR_SIZE]
+            .iter()
+            .all(|b| *b == 0xff));
+        let status = updater.status();
+        assert_eq!(status.state, State::Complete);
+        assert_eq!(status.received, IMAGE_LEN as u32);
+        assert_eq!(Status::decode(&status.encode()), Some(status));
+    }
+
+    #[test]
+    fn resumes_at_the_reported_offset() {
+        let image = image(&mut XorShift::new(2));
+        let mut flash = MemFlash::new();
+        let mut updater = updater();
+        updater
+            .control(&mut flash, Request::Start(info(&image)))
+            .unwrap();
+        send(&mut updater, &mut flash, &image[..5000], 0);
+        // A chunk lost with the connection, then the client retries from where it thinks it was.
+        let retry = Chunk {
+            offset: 6000,
+            data: &image[6000..6100],
+        };
+        assert_eq!(updater.write(&mut flash, retry), Err(DfuError::Offset));
+        let status = updater.status();
+        assert_eq!(status.state, State::Receiving);
+        assert_eq!(status.error, Some(DfuError::Offset));
+        assert_eq!(status.received, 5000);
+        send(&mut updater, &mut flash, &image, status.received as usize);
+        assert_eq!(updater.control(&mut flash, Request::Finish), Ok(()));
+        assert_eq!(updater.status().error, None);
+        assert_eq!(flash.data[..IMAGE_LEN], image);
+    }
+
+    #[test]
+    fn rejects_a_corrupted_transfer() {
+        let image = image(&mut XorShift::new(3));
+        for (info, error) in [
+            (
+                ImageInfo {
+                    crc32: crc32(&image) ^ 1,
+                    ..info(&image)
+                },
+                DfuError::Crc,
+            ),
+            (
+                // Signed, but not what was sent.
+                ImageInfo {
+                    sha256: [0; 32],
+                    signature: ed25519::sign(&SECRET_KEY, &[0; 32]),
+                    ..info(&image)
+                },
+                DfuError::Sha256,
+            ),
+        ] {
+            let mut flash = MemFlash::new();
+            let mut updater = updater();
+            updater.control(&mut flash, Request::Start(info)).unwrap();
+            send(&mut updater, &mut flash, &image, 0);
+            assert_eq!(updater.control(&mut flash, Request::Finish), Err(error));
+            assert_eq!(updater.status().state, State::Failed);
+            assert!(!flash.active);
+        }
+    }
+
+    #[test]
+    fn rejects_an_image_that_did_not_reach_the_flash() {
+        let image = image(&mut XorShift::new(4));
+        let mut flash = MemFlash::new();
+        flash.corrupt = true;
+        let mut updater = updater();
+        updater
+            .control(&mut flash, Request::Start(info(&image)))
+            .unwrap();
+        send(&mut updater, &mut flash, &image, 0);
+        assert_eq!(
+            updater.control(&mut flash, Request::Finish),
+            Err(DfuError::Verify)
+        );
+        assert!(!flash.active);
+    }
+
+    #[test]
+    fn enforces_the_transfer_order() {
+        let image = image(&mut XorShift::new(5));
+        let mut flash = MemFlash::new();
+        let mut updater = updater();
+        let chunk = Chunk {
+            offset: 0,
+            data: &image[..100],
+        };
+        assert_eq!(updater.write(&mut flash, chunk), Err(DfuError::State));
+        assert_eq!(
+            updater.control(&mut flash, Request::Finish),
+            Err(DfuError::State)
+        );
+        let too_large = ImageInfo {
+            size: (SECTORS * SECTOR_SIZE + 1) as u32,
+            ..info(&image)
+        };
+        assert_eq!(
+            updater.control(&mut flash, Request::Start(too_large)),
+            Err(DfuError::Size)
+        );
+        updater
+            .control(&mut flash, Request::Start(info(&image)))
+            .unwrap();
+        updater.write(&mut flash, chunk).unwrap();
+        assert_eq!(
+            updater.control(&mut flash, Request::Finish),
+            Err(DfuError::Incomplete)
+        );
+        let past_end = Chunk {
+            offset: 100,
+            data: &[0; IMAGE_LEN],
+        };
+        assert_eq!(updater.write(&mut flash, past_end), Err(DfuError::Size));
+        assert_eq!(updater.status().state, State::Receiving);
+        updater.control(&mut flash, Request::Abort).unwrap();
+        assert_eq!(updater.status().state, State::Idle);
+        assert_eq!(updater.write(&mut flash, chunk), Err(DfuError::State));
+    }
+
+    #[test]
+    fn rejects_what_is_not_an_app_image() {
+        let mut image = image(&mut XorShift::new(6));
+        image[0] = 0x7f;
+        let mut flash = MemFlash::new();
+        let mut updater = updater();
+        updater
+            .control(&mut flash, Request::Start(info(&image)))
+            .unwrap();
+        let chunk = Chunk {
+            offset: 0,
+            data: &image[..100],
+        };
+        assert_eq!(updater.write(&mut flash, chunk), Err(DfuError::NotAnImage));
+        assert_eq!(updater.status().state, State::Failed);
+        assert_eq!(flash.erases, 0);
+    }
+
+    #[test]
+    fn rejects_images_without_a_valid_signature() {
+        let image = image(&mut XorShift::new(7));
+        let mut tampered = image;
+        tampered[100] ^= 1;
+        let tampered = ImageInfo {
+            signature: info(&image).signature,
+            ..info(&tampered)
+        };
+        let other_key = ImageInfo::sign(&[0x11; SECRET_KEY_LEN], &image);
+        for (mut updater, info) in [
+            (updater(), tampered),
+            (updater(), other_key),
+            (Updater::new(None), info(&image)),
+        ] {
+            let mut flash = MemFlash::new();
+            assert_eq!(
+                updater.control(&mut flash, Request::Start(info)),
+                Err(DfuError::Signature)
+            );
+            let status = updater.status();
+            assert_eq!(status.state, State::Idle);
+            assert_eq!(status.error, Some(DfuError::Signature));
+            let chunk = Chunk {
+                offset: 0,
+                data: &image[..100],
+            };
+            assert_eq!(updater.write(&mut flash, chunk), Err(DfuError::State));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(Request::decode(&[]), Err(DfuError::Malformed));
+        assert_eq!(Request::decode(&[START, 1, 2]), Err(DfuError::Malformed));
+        assert_eq!(Request::decode(&[FINISH, 0]), Err(DfuError::Malformed));
+        assert_eq!(Request::decode(&[0x7f]), Err(DfuError::Malformed));
+        assert_eq!(Request::decode(&[ABORT]), Ok(Request::Abort));
+        assert_eq!(Chunk::decode(&[1, 2, 3]), Err(DfuError::Malformed));
+        assert_eq!(Status::decode(&[4, 0, 0, 0, 0, 0]), None);
+        assert_eq!(Status::decode(&[1, 12, 0, 0, 0, 0]), None);
+    }
+}
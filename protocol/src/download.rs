@@ -0,0 +1,884 @@
+//! Download of recorded sessions over BLE: the messages of the log service and the engine
+//! answering them from the log described in [`crate::recording`].
+//!
+//! A session downloads as one file: its [`SessionHeader`] followed by the records of all its
+//! blocks, in order. Records never span blocks, so [`records`](crate::recording::records) splits
+//! the file after the session header. The listing gives each file's size and CRC-32 for the client
+//! to check the download against. Reads name a session and a file offset, so after a dropped
+//! connection a client carries on from the last offset it received. Damaged blocks are left out of
+//! the file, and a session whose first block is damaged is not listed.
+//!
+//! The log is append-only, so deleting a session only hides it: its blocks are rewritten with the
+//! block number [`DELETED`] and their space comes back when the log is formatted. Formatting erases
+//! the log from its end, one block per [`Downloader::erase_last`].
+//!
+//! Request:
+//!
+//! | opcode | request | payload                                   |
+//! |--------|---------|-------------------------------------------|
+//! | `0x01` | list    |                                           |
+//! | `0x02` | read    | `u16` session, `u32` offset, `u32` length |
+//! | `0x03` | delete  | `u16` session                             |
+//! | `0x04` | format  |                                           |
+//!
+//! Response:
+//!
+//! | opcode | response | payload                                                  |
+//! |--------|----------|----------------------------------------------------------|
+//! | `0x81` | session  | [`SessionInfo`], one per session listed                  |
+//! | `0x82` | data     | `u16` session, `u32` offset in the file, the file bytes  |
+//! | `0x83` | done     | `u8` opcode of the request                               |
+//! | `0xff` | error    | `u8` opcode of the request, `u8` [`TransferError`]       |
+//!
+//! A read is answered with data responses covering at most `length` bytes from `offset`, then
+//! done; it stops early at the end of the file. All integers are little endian.
+use crate::config::ConfigPacket;
+use crate::hash::Crc32;
+use crate::recording::{
+    block_crc, BlockHeader, LogError, LogFlash, Recorder, SessionHeader, BLOCK_LEN, DELETED,
+    HEADER_LEN,
+};
+
+const LIST: u8 = 0x01;
+const READ: u8 = 0x02;
+const DELETE: u8 = 0x03;
+const FORMAT: u8 = 0x04;
+
+const SESSION: u8 = 0x81;
+const DATA: u8 = 0x82;
+const DONE: u8 = 0x83;
+const ERROR: u8 = 0xff;
+
+/// A write to `log_control`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Request {
+    /// List the sessions in the log.
+    List,
+    /// Send up to `len` bytes of the file of `session`, starting at `offset`.
+    Read { session: u16, offset: u32, len: u32 },
+    /// Hide a session from the listing.
+    Delete(u16),
+    /// Erase the whole log.
+    Format,
+}
+
+impl Request {
+    pub const MAX_ENCODED_LEN: usize = 1 + 2 + 4 + 4;
+
+    pub fn decode(buf: &[u8]) -> Result<Request, TransferError> {
+        match buf {
+            [LIST] => Ok(Request::List),
+            [READ, rest @ ..] if rest.len() == 10 => Ok(Request::Read {
+                session: u16_at(rest, 0),
+                offset: u32_at(rest, 2),
+                len: u32_at(rest, 6),
+            }),
+            [DELETE, a, b] => Ok(Request::Delete(u16::from_le_bytes([*a, *b]))),
+            [FORMAT] => Ok(Request::Format),
+            _ => Err(TransferError::Malformed),
+        }
+    }
+
+    /// Write the request to `out` and return its length.
+    pub fn encode(&self, out: &mut [u8; Self::MAX_ENCODED_LEN]) -> usize {
+        out[0] = self.opcode();
+        match *self {
+            Request::List | Request::Format => 1,
+            Request::Read {
+                session,
+                offset,
+                len,
+            } => {
+                out[1..3].copy_from_slice(&session.to_le_bytes());
+                out[3..7].copy_from_slice(&offset.to_le_bytes());
+                out[7..11].copy_from_slice(&len.to_le_bytes());
+                11
+            }
+            Request::Delete(session) => {
+                out[1..3].copy_from_slice(&session.to_le_bytes());
+                3
+            }
+        }
+    }
+
+    pub const fn opcode(&self) -> u8 {
+        match self {
+            Request::List => LIST,
+            Request::Read { .. } => READ,
+            Request::Delete(_) => DELETE,
+            Request::Format => FORMAT,
+        }
+    }
+}
+
+/// A session, as listed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SessionInfo {
+    pub id: u16,
+    /// Milliseconds since boot when the session started.
+    pub start_ms: u32,
+    /// Timestamp of the last sample, the length of the read window unless its epoch was marked.
+    pub duration_ms: u32,
+    pub samples: u32,
+    pub sample_interval_ms: u16,
+    pub config: ConfigPacket,
+    /// Length of the session's file.
+    pub size: u32,
+    /// CRC-32 of the session's file.
+    pub crc32: u32,
+}
+
+impl SessionInfo {
+    pub const ENCODED_LEN: usize = 2 + 4 + 4 + 4 + 2 + ConfigPacket::ENCODED_LEN + 4 + 4;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0; Self::ENCODED_LEN];
+        out[..2].copy_from_slice(&self.id.to_le_bytes());
+        out[2..6].copy_from_slice(&self.start_ms.to_le_bytes());
+        out[6..10].copy_from_slice(&self.duration_ms.to_le_bytes());
+        out[10..14].copy_from_slice(&self.samples.to_le_bytes());
+        out[14..16].copy_from_slice(&self.sample_interval_ms.to_le_bytes());
+        out[16..22].copy_from_slice(&self.config.encode());
+        out[22..26].copy_from_slice(&self.size.to_le_bytes());
+        out[26..30].copy_from_slice(&self.crc32.to_le_bytes());
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<SessionInfo> {
+        if buf.len() != Self::ENCODED_LEN {
+            return None;
+        }
+        Some(SessionInfo {
+            id: u16_at(buf, 0),
+            start_ms: u32_at(buf, 2),
+            duration_ms: u32_at(buf, 6),
+            samples: u32_at(buf, 10),
+            sample_interval_ms: u16_at(buf, 14),
+            config: ConfigPacket::decode(&buf[16..22]).ok()?,
+            size: u32_at(buf, 22),
+            crc32: u32_at(buf, 26),
+        })
+    }
+}
+
+/// A notification on `log_data`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response<'a> {
+    Session(SessionInfo),
+    Data {
+        session: u16,
+        /// Offset of `data` in the session's file.
+        offset: u32,
+        data: &'a [u8],
+    },
+    /// The request with this opcode is done.
+    Done(u8),
+    Error {
+        /// Opcode of the request, 0 if it had none.
+        request: u8,
+        error: TransferError,
+    },
+}
+
+impl<'a> Response<'a> {
+    /// Length of a data response without its data.
+    pub const DATA_HEADER_LEN: usize = 1 + 2 + 4;
+
+    /// Write the response to `out` and return its length, or `None` if it doesn't fit.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        match *self {
+            Response::Session(info) => {
+                let out = out.get_mut(..1 + SessionInfo::ENCODED_LEN)?;
+                out[0] = SESSION;
+                out[1..].copy_from_slice(&info.encode());
+                Some(out.len())
+            }
+            Response::Data {
+                session,
+                offset,
+                data,
+            } => {
+                let out = out.get_mut(..Self::DATA_HEADER_LEN + data.len())?;
+                out[0] = DATA;
+                out[1..3].copy_from_slice(&session.to_le_bytes());
+                out[3..7].copy_from_slice(&offset.to_le_bytes());
+                out[Self::DATA_HEADER_LEN..].copy_from_slice(data);
+                Some(out.len())
+            }
+            Response::Done(request) => {
+                let out = out.get_mut(..2)?;
+                out.copy_from_slice(&[DONE, request]);
+                Some(2)
+            }
+            Response::Error { request, error } => {
+                let out = out.get_mut(..3)?;
+                out.copy_from_slice(&[ERROR, request, error as u8]);
+                Some(3)
+            }
+        }
+    }
+
+    pub fn decode(buf: &'a [u8]) -> Option<Response<'a>> {
+        match buf {
+            [SESSION, rest @ ..] => SessionInfo::decode(rest).map(Response::Session),
+            [DATA, rest @ ..] if rest.len() >= Self::DATA_HEADER_LEN - 1 => Some(Response::Data {
+                session: u16_at(rest, 0),
+                offset: u32_at(rest, 2),
+                data: &rest[Self::DATA_HEADER_LEN - 1..],
+            }),
+            [DONE, request] => Some(Response::Done(*request)),
+            [ERROR, request, error] => Some(Response::Error {
+                request: *request,
+                error: TransferError::from_u8(*error)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Why a request failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum TransferError {
+    /// Unknown opcode or wrong length.
+    Malformed = 1,
+    /// No session with this id is listed.
+    NoSession = 2,
+    /// The offset is past the end of the session's file.
+    Offset = 3,
+    /// The session is being recorded, or any session is while formatting.
+    Busy = 4,
+    /// Reading or writing the flash failed.
+    Flash = 5,
+}
+
+impl TransferError {
+    pub fn from_u8(value: u8) -> Option<TransferError> {
+        match value {
+            1 => Some(TransferError::Malformed),
+            2 => Some(TransferError::NoSession),
+            3 => Some(TransferError::Offset),
+            4 => Some(TransferError::Busy),
+            5 => Some(TransferError::Flash),
+            _ => None,
+        }
+    }
+}
+
+impl From<LogError> for TransferError {
+    fn from(e: LogError) -> Self {
+        match e {
+            LogError::Busy => TransferError::Busy,
+            _ => TransferError::Flash,
+        }
+    }
+}
+
+/// Where the last read ended.
+#[derive(Clone, Copy, Debug)]
+struct Position {
+    session: u16,
+    /// The session's block 0.
+    first: u32,
+    block: u32,
+    /// Offset in the file of the payload of `block`.
+    start: u32,
+}
+
+/// Answers the requests from the log, see the module docs. The log's blocks are passed in with the
+/// [`Recorder`] appending to it.
+pub struct Downloader {
+    /// A checked block, read once for all the reads it serves.
+    block: [u8; BLOCK_LEN],
+    loaded: Option<(u32, BlockHeader)>,
+    /// The block the listing carries on from.
+    list_from: u32,
+    position: Option<Position>,
+}
+
+impl Downloader {
+    pub const fn new() -> Self {
+        Self {
+            block: [0xff; BLOCK_LEN],
+            loaded: None,
+            list_from: 0,
+            position: None,
+        }
+    }
+
+    /// List from the first session again.
+    pub fn rewind(&mut self) {
+        self.list_from = 0;
+    }
+
+    /// The next session of the listing, `None` once all were listed. The one being recorded is
+    /// left out.
+    pub fn next_session<F: LogFlash>(
+        &mut self,
+        log: &Recorder,
+        flash: &mut F,
+    ) -> Result<Option<SessionInfo>, TransferError> {
+        while self.list_from < log.used() {
+            let first = self.list_from;
+            self.list_from += 1;
+            let Some(header) = read_header(flash, first)? else {
+                continue;
+            };
+            if header.block != 0 || log.session() == Some(header.session) {
+                continue;
+            }
+            if let Some(info) = self.info(log, flash, header.session, first)? {
+                return Ok(Some(info));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copy the file of `session` from `offset` to `buf`, as much as one block holds, and return
+    /// the length copied. 0 means the file ends at `offset`.
+    pub fn read<F: LogFlash>(
+        &mut self,
+        log: &Recorder,
+        flash: &mut F,
+        session: u16,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, TransferError> {
+        if log.session() == Some(session) {
+            return Err(TransferError::Busy);
+        }
+        let mut position = match self.position {
+            Some(p) if p.session == session && p.start <= offset => p,
+            _ => {
+                let first = find(log, flash, session)?;
+                Position {
+                    session,
+                    first,
+                    block: first,
+                    start: 0,
+                }
+            }
+        };
+        while continues(log, flash, session, position.first, position.block)? {
+            match self.load(flash, position.block) {
+                Ok(header) => {
+                    let len = header.payload_len as u32;
+                    if offset < position.start + len {
+                        let payload = &self.block[HEADER_LEN..HEADER_LEN + len as usize];
+                        let payload = &payload[(offset - position.start) as usize..];
+                        let n = buf.len().min(payload.len());
+                        buf[..n].copy_from_slice(&payload[..n]);
+                        self.position = Some(position);
+                        return Ok(n);
+                    }
+                    position.start += len;
+                }
+                Err(LogError::Flash) => return Err(TransferError::Flash),
+                // Damaged blocks are not part of the file.
+                Err(_) => {}
+            }
+            position.block += 1;
+        }
+        if offset == position.start {
+            Ok(0)
+        } else {
+            Err(TransferError::Offset)
+        }
+    }
+
+    /// Hide `session` from the listing.
+    pub fn delete<F: LogFlash>(
+        &mut self,
+        log: &Recorder,
+        flash: &mut F,
+        session: u16,
+    ) -> Result<(), TransferError> {
+        if log.session() == Some(session) {
+            return Err(TransferError::Busy);
+        }
+        let first = find(log, flash, session)?;
+        let mut end = first + 1;
+        while continues(log, flash, session, first, end)? {
+            end += 1;
+        }
+        self.forget();
+        let mut block = [0xff; BLOCK_LEN];
+        let mut header = BlockHeader {
+            session,
+            block: DELETED,
+            payload_len: 0,
+            samples: 0,
+            end_ms: 0,
+            crc32: 0,
+        }
+        .encode();
+        let crc = block_crc(&header, &[]);
+        header[16..].copy_from_slice(&crc.to_le_bytes());
+        block[..HEADER_LEN].copy_from_slice(&header);
+        // Block 0 first, so a reset part way leaves no session with missing blocks.
+        for index in first..end {
+            flash
+                .write_sector(index * BLOCK_LEN as u32, &block)
+                .map_err(|_| TransferError::Flash)?;
+        }
+        Ok(())
+    }
+
+    /// Erase the last block of the log and return whether any are left. Formatting calls this
+    /// until it returns false, so the caller can do other work in between.
+    pub fn erase_last<F: LogFlash>(
+        &mut self,
+        log: &mut Recorder,
+        flash: &mut F,
+    ) -> Result<bool, TransferError> {
+        self.forget();
+        Ok(log.erase_last(flash)?)
+    }
+
+    /// Drop what was read from blocks about to change.
+    fn forget(&mut self) {
+        self.loaded = None;
+        self.position = None;
+        self.list_from = 0;
+    }
+
+    /// Read and check block `index` into `self.block`, unless it is there already.
+    fn load<F: LogFlash>(&mut self, flash: &mut F, index: u32) -> Result<BlockHeader, LogError> {
+        if let Some((loaded, header)) = self.loaded {
+            if loaded == index {
+                return Ok(header);
+            }
+        }
+        self.loaded = None;
+        flash
+            .read(index * BLOCK_LEN as u32, &mut self.block)
+            .map_err(|_| LogError::Flash)?;
+        let (header, _) = crate::recording::decode_block(&self.block)?;
+        self.loaded = Some((index, header));
+        Ok(header)
+    }
+
+    /// Describe the session starting at block `first`, `None` if that block is damaged.
+    fn info<F: LogFlash>(
+        &mut self,
+        log: &Recorder,
+        flash: &mut F,
+        session: u16,
+        first: u32,
+    ) -> Result<Option<SessionInfo>, TransferError> {
+        let header = match self.load(flash, first) {
+            Ok(header) => header,
+            Err(LogError::Flash) => return Err(TransferError::Flash),
+            Err(_) => return Ok(None),
+        };
+        let payload = &self.block[HEADER_LEN..HEADER_LEN + header.payload_len as usize];
+        let Some(session_header) = SessionHeader::decode(payload) else {
+            return Ok(None);
+        };
+        let mut info = SessionInfo {
+            id: session,
+            start_ms: session_header.start_ms,
+            duration_ms: 0,
+            samples: 0,
+            sample_interval_ms: session_header.sample_interval_ms,
+            config: session_header.config,
+            size: 0,
+            crc32: 0,
+        };
+        let mut crc = Crc32::new();
+        let mut index = first;
+        while continues(log, flash, session, first, index)? {
+            match self.load(flash, index) {
+                Ok(header) => {
+                    crc.update(&self.block[HEADER_LEN..HEADER_LEN + header.payload_len as usize]);
+                    info.size += header.payload_len as u32;
+                    info.samples += header.samples as u32;
+                    if header.samples > 0 {
+                        info.duration_ms = header.end_ms;
+                    }
+                }
+                Err(LogError::Flash) => return Err(TransferError::Flash),
+                Err(_) => {}
+            }
+            index += 1;
+        }
+        info.crc32 = crc.finish();
+        // The listing carries on after the session.
+        self.list_from = self.list_from.max(index);
+        Ok(Some(info))
+    }
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The header of block `index`, `None` if it is not a block.
+fn read_header<F: LogFlash>(flash: &mut F, index: u32) -> Result<Option<BlockHeader>, LogError> {
+    let mut header = [0; HEADER_LEN];
+    flash
+        .read(index * BLOCK_LEN as u32, &mut header)
+        .map_err(|_| LogError::Flash)?;
+    Ok(BlockHeader::decode(&header).ok())
+}
+
+/// The block 0 of `session`.
+fn find<F: LogFlash>(log: &Recorder, flash: &mut F, session: u16) -> Result<u32, TransferError> {
+    for index in 0..log.used() {
+        if let Some(header) = read_header(flash, index)? {
+            if header.session == session && header.block == 0 {
+                return Ok(index);
+            }
+        }
+    }
+    Err(TransferError::NoSession)
+}
+
+/// Whether block `index` belongs to the session whose block 0 is `first`. The blocks of a session
+/// follow each other, one that doesn't belong ends it.
+fn continues<F: LogFlash>(
+    log: &Recorder,
+    flash: &mut F,
+    session: u16,
+    first: u32,
+    index: u32,
+) -> Result<bool, LogError> {
+    if index >= log.used() {
+        return Ok(false);
+    }
+    Ok(match read_header(flash, index)? {
+        Some(header) if header.session == session => {
+            if index == first {
+                header.block == 0
+            } else {
+                header.block != 0 && header.block != DELETED
+            }
+        }
+        _ => false,
+    })
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::crc32;
+    use crate::recording::{decode_block, records};
+    use crate::test_util::XorShift;
+
+    const BLOCKS: usize = 8;
+
+    /// A partition in RAM.
+    struct MemFlash {
+        data: [u8; BLOCKS * BLOCK_LEN],
+    }
+
+    impl LogFlash for MemFlash {
+        type Error = ();
+
+        fn capacity(&self) -> u32 {
+            self.data.len() as u32
+        }
+
+        fn write_sector(&mut self, offset: u32, data: &[u8; BLOCK_LEN]) -> Result<(), ()> {
+            self.data[offset as usize..offset as usize + BLOCK_LEN].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn erase_sector(&mut self, offset: u32) -> Result<(), ()> {
+            self.data[offset as usize..offset as usize + BLOCK_LEN].fill(0xff);
+            Ok(())
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+    }
+
+    fn session_header(start_ms: u32) -> SessionHeader {
+        SessionHeader {
+            start_ms,
+            sample_interval_ms: 5,
+            config: ConfigPacket {
+                accel_scale: 2,
+                gyro_scale: 1,
+                buzz_frequency_mode: 3,
+                filter: 0,
+                motion_detection: false,
+            },
+        }
+    }
+
+    /// A mounted log with three sessions of `frames` random frames of 4 samples each, 20 ms apart.
+    fn log(frames: [usize; 3]) -> (Recorder, MemFlash) {
+        let mut flash = MemFlash {
+            data: [0xff; BLOCKS * BLOCK_LEN],
+        };
+        let mut recorder = Recorder::new();
+        recorder.mount(&mut flash).unwrap();
+        let mut rng = XorShift::new(0xd0);
+        let mut frame = [0; 512];
+        for (session, frames) in frames.into_iter().enumerate() {
+            let start_ms = 1000 * session as u32;
+            recorder
+                .start(&mut flash, session_header(start_ms))
+                .unwrap();
+            for i in 0..frames {
+                let len = rng.below(frame.len()) + 1;
+                rng.fill(&mut frame[..len]);
+                let end_ms = 20 * (i as u32 + 1);
+                recorder
+                    .append(&mut flash, &frame[..len], 4, end_ms)
+                    .unwrap();
+            }
+        }
+        recorder.finish(&mut flash).unwrap();
+        (recorder, flash)
+    }
+
+    /// The file of the session whose block 0 is `first`, straight from the blocks.
+    fn expected_file(flash: &MemFlash, first: usize, out: &mut [u8]) -> usize {
+        let mut len = 0;
+        for block in flash.data.as_chunks::<BLOCK_LEN>().0[first..].iter() {
+            let Ok((header, payload)) = decode_block(block) else {
+                break;
+            };
+            if header.block == 0 && len > 0 {
+                break;
+            }
+            out[len..len + payload.len()].copy_from_slice(payload);
+            len += payload.len();
+        }
+        len
+    }
+
+    fn list(
+        downloader: &mut Downloader,
+        log: &Recorder,
+        flash: &mut MemFlash,
+    ) -> [Option<SessionInfo>; 4] {
+        let mut sessions = [None; 4];
+        downloader.rewind();
+        for session in sessions.iter_mut() {
+            *session = downloader.next_session(log, flash).unwrap();
+        }
+        sessions
+    }
+
+    #[test]
+    fn lists_and_downloads_sessions() {
+        let (log, mut flash) = log([3, 30, 2]);
+        let mut downloader = Downloader::new();
+        let sessions = list(&mut downloader, &log, &mut flash);
+        let [Some(a), Some(b), Some(c), None] = sessions else {
+            panic!("{sessions:?}");
+        };
+        assert_eq!((a.id, b.id, c.id), (0, 1, 2));
+        assert_eq!((b.start_ms, b.duration_ms, b.samples), (1000, 600, 120));
+        assert_eq!(b.sample_interval_ms, 5);
+        assert_eq!(b.config, session_header(0).config);
+        assert_eq!(c.samples, 8);
+
+        // Session 1 spans several blocks. Download it in uneven chunks, with a restart part way.
+        let mut expected = [0; BLOCKS * BLOCK_LEN];
+        let len = expected_file(&flash, 1, &mut expected);
+        assert_eq!(b.size as usize, len);
+        assert_eq!(b.crc32, crc32(&expected[..len]));
+        let mut file = [0; BLOCKS * BLOCK_LEN];
+        let mut offset = 0;
+        let mut rng = XorShift::new(7);
+        let mut chunk = [0; 300];
+        let mut resumed = false;
+        loop {
+            let want = rng.below(chunk.len()) + 1;
+            let n = downloader
+                .read(&log, &mut flash, 1, offset as u32, &mut chunk[..want])
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            file[offset..offset + n].copy_from_slice(&chunk[..n]);
+            offset += n;
+            if offset > len / 2 && !resumed {
+                // After reconnecting the reads carry on from the offset, with nothing cached.
+                downloader = Downloader::new();
+                resumed = true;
+            }
+        }
+        assert_eq!(&file[..offset], &expected[..len]);
+        assert_eq!(
+            SessionHeader::decode(&file[..offset]),
+            Some(session_header(1000))
+        );
+        let frames = records(&file[SessionHeader::ENCODED_LEN..offset]).count();
+        assert_eq!(frames, 30);
+
+        // Going back is fine, going past the end is not.
+        let n = downloader
+            .read(&log, &mut flash, 1, 10, &mut chunk)
+            .unwrap();
+        assert_eq!(&chunk[..n], &expected[10..10 + n]);
+        assert_eq!(
+            downloader.read(&log, &mut flash, 1, len as u32 + 1, &mut chunk),
+            Err(TransferError::Offset)
+        );
+        assert_eq!(
+            downloader.read(&log, &mut flash, 9, 0, &mut chunk),
+            Err(TransferError::NoSession)
+        );
+    }
+
+    #[test]
+    fn leaves_damaged_blocks_out() {
+        let (log, mut flash) = log([1, 30, 1]);
+        let mut expected = [0; BLOCKS * BLOCK_LEN];
+        let len = expected_file(&flash, 1, &mut expected);
+        // Tear the second block of session 1.
+        let damaged = 2 * BLOCK_LEN;
+        let (header, payload) =
+            decode_block(flash.data[damaged..][..BLOCK_LEN].try_into().unwrap())
+                .map(|(h, p)| (h, p.len()))
+                .unwrap();
+        assert_eq!((header.session, header.block), (1, 1));
+        flash.data[damaged + HEADER_LEN] ^= 1;
+
+        let mut downloader = Downloader::new();
+        let b = list(&mut downloader, &log, &mut flash)[1].unwrap();
+        assert_eq!(b.size as usize, len - payload);
+        assert_eq!(b.samples, 120 - header.samples as u32);
+        let mut file = [0; BLOCKS * BLOCK_LEN];
+        let mut offset = 0;
+        loop {
+            let n = downloader
+                .read(&log, &mut flash, 1, offset as u32, &mut file[offset..])
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            offset += n;
+        }
+        assert_eq!(offset, b.size as usize);
+        assert_eq!(crc32(&file[..offset]), b.crc32);
+
+        // Without its first block a session can't be described.
+        flash.data[BLOCK_LEN + HEADER_LEN] ^= 1;
+        let sessions = list(&mut downloader, &log, &mut flash);
+        assert_eq!(
+            sessions.map(|s| s.map(|s| s.id)),
+            [Some(0), Some(2), None, None]
+        );
+    }
+
+    #[test]
+    fn deletes_and_formats() {
+        let (mut log, mut flash) = log([2, 30, 2]);
+        let mut downloader = Downloader::new();
+        let mut chunk = [0; 64];
+        downloader.read(&log, &mut flash, 1, 0, &mut chunk).unwrap();
+        downloader.delete(&log, &mut flash, 1).unwrap();
+        let sessions = list(&mut downloader, &log, &mut flash);
+        assert_eq!(
+            sessions.map(|s| s.map(|s| s.id)),
+            [Some(0), Some(2), None, None]
+        );
+        assert_eq!(
+            downloader.read(&log, &mut flash, 1, 0, &mut chunk),
+            Err(TransferError::NoSession)
+        );
+        assert_eq!(
+            downloader.delete(&log, &mut flash, 1),
+            Err(TransferError::NoSession)
+        );
+
+        // The session being recorded is off limits until it ends.
+        let used = log.used();
+        assert_eq!(log.start(&mut flash, session_header(0)), Ok(3));
+        assert_eq!(
+            downloader.read(&log, &mut flash, 3, 0, &mut chunk),
+            Err(TransferError::Busy)
+        );
+        assert_eq!(
+            downloader.erase_last(&mut log, &mut flash),
+            Err(TransferError::Busy)
+        );
+        log.finish(&mut flash).unwrap();
+        assert_eq!(list(&mut downloader, &log, &mut flash)[2].unwrap().id, 3);
+
+        // One call per block, session 3 took one more.
+        let mut calls = 1;
+        while downloader.erase_last(&mut log, &mut flash).unwrap() {
+            calls += 1;
+        }
+        assert_eq!(calls, used + 1);
+        assert_eq!(log.used(), 0);
+        assert!(flash.data.iter().all(|b| *b == 0xff));
+        assert_eq!(list(&mut downloader, &log, &mut flash)[0], None);
+        log.mount(&mut flash).unwrap();
+        assert_eq!(log.start(&mut flash, session_header(0)), Ok(0));
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let requests = [
+            Request::List,
+            Request::Read {
+                session: 0x1234,
+                offset: 0x0102_0304,
+                len: u32::MAX,
+            },
+            Request::Delete(7),
+            Request::Format,
+        ];
+        let mut buf = [0; Request::MAX_ENCODED_LEN];
+        for request in requests {
+            let len = request.encode(&mut buf);
+            assert_eq!(Request::decode(&buf[..len]), Ok(request));
+        }
+        assert_eq!(Request::decode(&[READ, 0]), Err(TransferError::Malformed));
+        assert_eq!(Request::decode(&[]), Err(TransferError::Malformed));
+
+        let info = SessionInfo {
+            id: 3,
+            start_ms: 123_456,
+            duration_ms: 9000,
+            samples: 1800,
+            sample_interval_ms: 5,
+            config: session_header(0).config,
+            size: 40_000,
+            crc32: 0xdead_beef,
+        };
+        let responses = [
+            Response::Session(info),
+            Response::Data {
+                session: 3,
+                offset: 4096,
+                data: &[1, 2, 3],
+            },
+            Response::Done(FORMAT),
+            Response::Error {
+                request: READ,
+                error: TransferError::Offset,
+            },
+        ];
+        let mut buf = [0; 64];
+        for response in responses {
+            let len = response.encode(&mut buf).unwrap();
+            assert_eq!(Response::decode(&buf[..len]), Some(response));
+        }
+        assert_eq!(Response::Session(info).encode(&mut [0; 20]), None);
+    }
+}
@@ -0,0 +1,128 @@
+//! Ed25519 signatures (RFC 8032), used to check that a firmware image comes from whoever holds the
+//! signing key.
+//!
+//! Thin wrappers over the `ed25519-compact` crate with fixed size arrays. Verifying takes a
+//! fraction of a second on the device, once per update. Signing runs on the build machine only.
+use ed25519_compact::{KeyPair, PublicKey, Seed, Signature};
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SECRET_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// The public key for a secret key (the 32 byte seed of RFC 8032).
+pub fn public_key(secret: &[u8; SECRET_KEY_LEN]) -> [u8; PUBLIC_KEY_LEN] {
+    *KeyPair::from_seed(Seed::new(*secret)).pk
+}
+
+pub fn sign(secret: &[u8; SECRET_KEY_LEN], message: &[u8]) -> [u8; SIGNATURE_LEN] {
+    // Deterministic, as RFC 8032 specifies.
+    *KeyPair::from_seed(Seed::new(*secret))
+        .sk
+        .sign(message, None)
+}
+
+/// Whether `signature` is a valid signature of `message` by `public_key`. Non-canonical
+/// signatures and small order keys are rejected.
+pub fn verify(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    message: &[u8],
+    signature: &[u8; SIGNATURE_LEN],
+) -> bool {
+    PublicKey::new(*public_key)
+        .verify(message, &Signature::new(*signature))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::hex;
+
+    /// Test vectors 1 to 3 of RFC 8032 section 7.1: secret key, public key, message, signature.
+    const VECTORS: [(&str, &str, &[u8], &str); 3] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            b"",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            &[0x72],
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            &[0xaf, 0x82],
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+             18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn rfc8032_test_vectors() {
+        for (secret, public, message, signature) in VECTORS {
+            let secret = hex(secret);
+            let public = hex(public);
+            let signature = hex(signature);
+            assert_eq!(public_key(&secret), public);
+            assert_eq!(sign(&secret, message), signature);
+            assert!(verify(&public, message, &signature));
+        }
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let (secret, _, _, _) = VECTORS[2];
+        let secret = hex(secret);
+        let public = public_key(&secret);
+        let message = [0x5a; 32];
+        let signature = sign(&secret, &message);
+        assert!(verify(&public, &message, &signature));
+
+        let mut other = message;
+        other[7] ^= 1;
+        assert!(!verify(&public, &other, &signature));
+        for i in [0, 31, 32, 63] {
+            let mut bad = signature;
+            bad[i] ^= 0x10;
+            assert!(!verify(&public, &message, &bad));
+        }
+        let other_key = public_key(&hex(VECTORS[0].0));
+        assert!(!verify(&other_key, &message, &signature));
+    }
+
+    #[test]
+    fn rejects_small_order_keys() {
+        let secret = hex(VECTORS[0].0);
+        let signature = sign(&secret, b"");
+        // The identity, which every signature with R = s * B would match, and the all zero key.
+        let mut identity = [0; PUBLIC_KEY_LEN];
+        identity[0] = 1;
+        assert!(!verify(&identity, b"", &signature));
+        assert!(!verify(&[0; PUBLIC_KEY_LEN], b"", &signature));
+    }
+
+    #[test]
+    fn rejects_non_canonical_s() {
+        let secret = hex(VECTORS[0].0);
+        let public = public_key(&secret);
+        let mut signature = sign(&secret, b"");
+        // s + L is the same scalar but not the encoding signers produce.
+        const L: [i64; 32] = [
+            0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9,
+            0xde, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+        ];
+        let mut carry = 0;
+        for i in 0..32 {
+            let sum = signature[32 + i] as i64 + L[i] + carry;
+            signature[32 + i] = sum as u8;
+            carry = sum >> 8;
+        }
+        assert!(!verify(&public, b"", &signature));
+    }
+}
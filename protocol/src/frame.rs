@@ -0,0 +1,1361 @@
+//! Versioned binary frame used for every sensor stream notification.
+//!
+//! All multi-byte fields are little endian.
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 1    | frame version ([`FRAME_VERSION`])             |
+//! | 1      | 1    | stream id ([`StreamId`])                      |
+//! | 2      | 1    | flags ([`FLAG_TEMPERATURE`], ...)             |
+//! | 3      | 1    | full-scale index of the sensor(s), see below  |
+//! | 4      | 1    | sample count                                  |
+//! | 5      | 2    | sample rate in Hz (0 = not periodic)          |
+//! | 7      | 2    | sequence number (wraps)                       |
+//! | 9      | ..   | `sample count` samples                        |
+//!
+//! A sample is a `u32` timestamp in milliseconds since the read epoch followed by `i16` fields.
+//! The accel and gyro streams carry the x, y and z axis of one sensor. The combined stream carries
+//! accel x/y/z and gyro x/y/z, then the temperature and the w/x/y/z quaternion (Q14) if the
+//! matching flag is set; its scale byte holds the accel scale in the low and the gyro scale in the
+//! high nibble.
+//!
+//! Frames of different streams produced from the same batch of samples share a sequence number,
+//! so a client can re-join them without comparing timestamps.
+//!
+//! With [`FLAG_COMPRESSED`] set only the first sample of a frame is stored like this (the
+//! keyframe). The following samples are stored as differences to the sample before them, in
+//! blocks of up to 8 samples:
+//!
+//! | size | field                                                     |
+//! |------|-----------------------------------------------------------|
+//! | 1    | timestamp width `t` in bits (0..=32)                      |
+//! | 1    | field width `f` in bits (0..=16)                          |
+//! | ..   | per sample `t` bits of timestamp, then `f` bits per field |
+//!
+//! The timestamp entry is the change of the interval between samples, starting from the nominal
+//! interval given by the sample rate, so a steady rate costs no bits at all. Field entries are the
+//! wrapping `i16` change of the field. Both are zigzag encoded (0, -1, 1, -2, ... map to 0, 1, 2,
+//! 3, ...) and packed least significant bit first; each block is padded to a whole byte. Only the
+//! last block of a frame holds fewer than 8 samples. Each frame starts with a keyframe, so a lost
+//! notification never affects the decoding of the next one.
+//!
+//! The accel and gyro streams still default to the unversioned layout they had before frames, see
+//! [`LegacyWriter`].
+
+/// Version written in the first byte of every frame.
+pub const FRAME_VERSION: u8 = 1;
+/// Length of the frame header in bytes.
+pub const HEADER_LEN: usize = 9;
+/// Length of one encoded [`Sample`] in bytes.
+pub const SAMPLE_LEN: usize = 10;
+/// Most `i16` fields any sample has.
+pub const MAX_FIELDS: usize = 11;
+
+/// Combined stream samples carry the raw temperature.
+pub const FLAG_TEMPERATURE: u8 = 0x01;
+/// Combined stream samples carry the DMP quaternion.
+pub const FLAG_QUATERNION: u8 = 0x02;
+/// Samples after the first are delta encoded.
+pub const FLAG_COMPRESSED: u8 = 0x04;
+
+/// Length of one sample in the unversioned layout, see [`LegacyWriter`].
+pub const LEGACY_SAMPLE_LEN: usize = 11;
+/// Most samples the unversioned layout carries in one notification.
+pub const LEGACY_MAX_SAMPLES: usize = 10;
+
+const TIMESTAMP_LEN: usize = 4;
+/// Samples per bit packed block of a compressed frame.
+const BLOCK_SAMPLES: usize = 8;
+const BLOCK_HEADER_LEN: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError {
+    /// The buffer is shorter than the frame header.
+    TooShort,
+    /// The frame was written by an incompatible protocol version.
+    UnsupportedVersion(u8),
+    /// The stream id is not known to this protocol version.
+    UnknownStream(u8),
+    /// The flags contain bits this protocol version does not know for the stream.
+    UnsupportedFlags(u8),
+    /// The payload length does not match the sample count in the header.
+    LengthMismatch,
+    /// A compressed block declares deltas wider than the fields they belong to.
+    InvalidDelta,
+    /// No more samples fit into the output buffer.
+    BufferFull,
+    /// The sample type does not belong to the frame's stream.
+    WrongStream,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum StreamId {
+    Accel = 1,
+    Gyro = 2,
+    Combined = 3,
+}
+
+impl StreamId {
+    pub const ALL: [StreamId; 3] = [StreamId::Accel, StreamId::Gyro, StreamId::Combined];
+
+    pub fn from_u8(value: u8) -> Option<StreamId> {
+        match value {
+            1 => Some(StreamId::Accel),
+            2 => Some(StreamId::Gyro),
+            3 => Some(StreamId::Combined),
+            _ => None,
+        }
+    }
+
+    /// Flags that may be set in frames of this stream.
+    pub const fn supported_flags(self) -> u8 {
+        match self {
+            StreamId::Accel | StreamId::Gyro => FLAG_COMPRESSED,
+            StreamId::Combined => FLAG_TEMPERATURE | FLAG_QUATERNION | FLAG_COMPRESSED,
+        }
+    }
+
+    /// Number of `i16` fields per sample.
+    pub const fn field_count(self, flags: u8) -> usize {
+        match self {
+            StreamId::Accel | StreamId::Gyro => 3,
+            StreamId::Combined => {
+                6 + if flags & FLAG_TEMPERATURE != 0 { 1 } else { 0 }
+                    + if flags & FLAG_QUATERNION != 0 { 4 } else { 0 }
+            }
+        }
+    }
+
+    /// Length of an uncompressed sample, or a keyframe.
+    pub const fn sample_len(self, flags: u8) -> usize {
+        TIMESTAMP_LEN + 2 * self.field_count(flags)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrameHeader {
+    pub stream: StreamId,
+    pub flags: u8,
+    pub scale: u8,
+    pub sample_count: u8,
+    pub sample_rate_hz: u16,
+    pub sequence: u16,
+}
+
+impl FrameHeader {
+    /// Scale byte of a combined frame.
+    pub const fn combined_scale(accel_scale: u8, gyro_scale: u8) -> u8 {
+        (accel_scale & 0x0F) | (gyro_scale << 4)
+    }
+
+    pub const fn sample_len(&self) -> usize {
+        self.stream.sample_len(self.flags)
+    }
+
+    pub const fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    /// Number of samples that fit into a frame of at most `frame_len` bytes. For compressed frames
+    /// this is the number of uncompressed samples, usually a lot more fit.
+    pub fn capacity(&self, frame_len: usize) -> usize {
+        (frame_len.saturating_sub(HEADER_LEN) / self.sample_len()).min(u8::MAX as usize)
+    }
+
+    pub fn encode(&self, out: &mut [u8]) -> Result<(), FrameError> {
+        let out = out.get_mut(..HEADER_LEN).ok_or(FrameError::TooShort)?;
+        out[0] = FRAME_VERSION;
+        out[1] = self.stream as u8;
+        out[2] = self.flags;
+        out[3] = self.scale;
+        out[4] = self.sample_count;
+        out[5..7].copy_from_slice(&self.sample_rate_hz.to_le_bytes());
+        out[7..9].copy_from_slice(&self.sequence.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<FrameHeader, FrameError> {
+        let buf = buf.get(..HEADER_LEN).ok_or(FrameError::TooShort)?;
+        if buf[0] != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(buf[0]));
+        }
+        let stream = StreamId::from_u8(buf[1]).ok_or(FrameError::UnknownStream(buf[1]))?;
+        if buf[2] & !stream.supported_flags() != 0 {
+            return Err(FrameError::UnsupportedFlags(buf[2]));
+        }
+        Ok(FrameHeader {
+            stream,
+            flags: buf[2],
+            scale: buf[3],
+            sample_count: buf[4],
+            sample_rate_hz: u16::from_le_bytes([buf[5], buf[6]]),
+            sequence: u16::from_le_bytes([buf[7], buf[8]]),
+        })
+    }
+}
+
+/// A sample type that can be carried in a frame.
+pub trait FrameSample: Sized {
+    /// Whether frames of `stream` carry this sample type.
+    fn accepts(stream: StreamId) -> bool;
+
+    fn timestamp_ms(&self) -> u32;
+
+    /// Fill `fields` with the `stream.field_count(flags)` values of this sample.
+    fn write_fields(&self, flags: u8, fields: &mut [i16]);
+
+    fn from_fields(timestamp_ms: u32, flags: u8, fields: &[i16]) -> Self;
+}
+
+/// One three-axis reading, the sample of the accel and gyro streams.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sample {
+    pub timestamp_ms: u32,
+    pub xyz: [i16; 3],
+}
+
+impl FrameSample for Sample {
+    fn accepts(stream: StreamId) -> bool {
+        matches!(stream, StreamId::Accel | StreamId::Gyro)
+    }
+
+    fn timestamp_ms(&self) -> u32 {
+        self.timestamp_ms
+    }
+
+    fn write_fields(&self, _flags: u8, fields: &mut [i16]) {
+        fields.copy_from_slice(&self.xyz);
+    }
+
+    fn from_fields(timestamp_ms: u32, _flags: u8, fields: &[i16]) -> Self {
+        Sample {
+            timestamp_ms,
+            xyz: [fields[0], fields[1], fields[2]],
+        }
+    }
+}
+
+/// Everything measured at one instant, the sample of the combined stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CombinedSample {
+    pub timestamp_ms: u32,
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+    /// Raw temperature register value.
+    pub temperature: Option<i16>,
+    /// w, x, y, z in Q14.
+    pub quaternion: Option<[i16; 4]>,
+}
+
+impl CombinedSample {
+    /// Frame flags needed to carry this sample's optional fields.
+    pub fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.temperature.is_some() {
+            flags |= FLAG_TEMPERATURE;
+        }
+        if self.quaternion.is_some() {
+            flags |= FLAG_QUATERNION;
+        }
+        flags
+    }
+}
+
+impl FrameSample for CombinedSample {
+    fn accepts(stream: StreamId) -> bool {
+        stream == StreamId::Combined
+    }
+
+    fn timestamp_ms(&self) -> u32 {
+        self.timestamp_ms
+    }
+
+    fn write_fields(&self, flags: u8, fields: &mut [i16]) {
+        fields[0..3].copy_from_slice(&self.accel);
+        fields[3..6].copy_from_slice(&self.gyro);
+        let mut i = 6;
+        if flags & FLAG_TEMPERATURE != 0 {
+            fields[i] = self.temperature.unwrap_or_default();
+            i += 1;
+        }
+        if flags & FLAG_QUATERNION != 0 {
+            fields[i..i + 4].copy_from_slice(&self.quaternion.unwrap_or_default());
+        }
+    }
+
+    fn from_fields(timestamp_ms: u32, flags: u8, fields: &[i16]) -> Self {
+        let mut i = 6;
+        let temperature = (flags & FLAG_TEMPERATURE != 0).then(|| {
+            i += 1;
+            fields[i - 1]
+        });
+        let quaternion = (flags & FLAG_QUATERNION != 0)
+            .then(|| [fields[i], fields[i + 1], fields[i + 2], fields[i + 3]]);
+        CombinedSample {
+            timestamp_ms,
+            accel: [fields[0], fields[1], fields[2]],
+            gyro: [fields[3], fields[4], fields[5]],
+            temperature,
+            quaternion,
+        }
+    }
+}
+
+/// Timestamp and fields of one sample, as stored in a frame.
+#[derive(Clone, Copy)]
+struct Fields {
+    timestamp_ms: u32,
+    values: [i16; MAX_FIELDS],
+}
+
+impl Fields {
+    fn of<S: FrameSample>(sample: &S, header: &FrameHeader) -> Fields {
+        let mut values = [0i16; MAX_FIELDS];
+        sample.write_fields(
+            header.flags,
+            &mut values[..header.stream.field_count(header.flags)],
+        );
+        Fields {
+            timestamp_ms: sample.timestamp_ms(),
+            values,
+        }
+    }
+}
+
+/// One sample of a compressed frame relative to the one before it, zigzag encoded.
+#[derive(Clone, Copy, Default)]
+struct Delta {
+    /// Change of the timestamp delta.
+    timestamp: u32,
+    values: [u16; MAX_FIELDS],
+}
+
+fn zigzag16(value: i16) -> u16 {
+    ((value << 1) ^ (value >> 15)) as u16
+}
+
+fn unzigzag16(value: u16) -> i16 {
+    ((value >> 1) as i16) ^ -((value & 1) as i16)
+}
+
+fn zigzag32(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag32(value: u32) -> i32 {
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+fn bit_width(value: u32) -> u8 {
+    (u32::BITS - value.leading_zeros()) as u8
+}
+
+/// Timestamp delta expected between samples, the starting point of the delta encoding.
+fn nominal_interval_ms(header: &FrameHeader) -> u32 {
+    match header.sample_rate_hz {
+        0 => 0,
+        rate => 1000 / rate as u32,
+    }
+}
+
+/// Encode `fields` relative to `prev`, whose timestamp followed the one before it by
+/// `prev_interval`.
+fn delta(fields: &Fields, prev: &Fields, prev_interval: u32, field_count: usize) -> Delta {
+    let interval = fields.timestamp_ms.wrapping_sub(prev.timestamp_ms);
+    let mut delta = Delta {
+        timestamp: zigzag32(interval.wrapping_sub(prev_interval) as i32),
+        values: [0; MAX_FIELDS],
+    };
+    for ((out, field), prev) in delta.values[..field_count]
+        .iter_mut()
+        .zip(&fields.values)
+        .zip(&prev.values)
+    {
+        *out = zigzag16(field.wrapping_sub(*prev));
+    }
+    delta
+}
+
+/// Bytes taken by a block of `count` samples with the given bit widths.
+fn block_len(count: usize, field_count: usize, timestamp_width: u8, field_width: u8) -> usize {
+    let bits = timestamp_width as usize + field_count * field_width as usize;
+    BLOCK_HEADER_LEN + (count * bits).div_ceil(8)
+}
+
+/// Write the low `width` bits of `value` at bit offset `pos`, least significant bit first.
+fn put_bits(out: &mut [u8], pos: &mut usize, value: u32, width: u8) {
+    for i in 0..width {
+        let bit = (value >> i) as u8 & 1;
+        out[*pos / 8] |= bit << (*pos % 8);
+        *pos += 1;
+    }
+}
+
+fn get_bits(buf: &[u8], pos: &mut usize, width: u8) -> u32 {
+    let mut value = 0;
+    for i in 0..width {
+        value |= ((buf[*pos / 8] >> (*pos % 8)) as u32 & 1) << i;
+        *pos += 1;
+    }
+    value
+}
+
+fn encode_keyframe(fields: &Fields, field_count: usize, out: &mut [u8]) {
+    out[..TIMESTAMP_LEN].copy_from_slice(&fields.timestamp_ms.to_le_bytes());
+    for (chunk, field) in out[TIMESTAMP_LEN..]
+        .as_chunks_mut::<2>()
+        .0
+        .iter_mut()
+        .zip(&fields.values[..field_count])
+    {
+        *chunk = field.to_le_bytes();
+    }
+}
+
+fn decode_keyframe(buf: &[u8], field_count: usize) -> Fields {
+    let mut values = [0i16; MAX_FIELDS];
+    for (field, chunk) in values[..field_count]
+        .iter_mut()
+        .zip(buf[TIMESTAMP_LEN..].as_chunks::<2>().0)
+    {
+        *field = i16::from_le_bytes(*chunk);
+    }
+    Fields {
+        timestamp_ms: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+        values,
+    }
+}
+
+/// Deltas collected by a [`FrameWriter`] until they are packed into the frame.
+struct Block {
+    deltas: [Delta; BLOCK_SAMPLES],
+    len: usize,
+    timestamp_width: u8,
+    field_width: u8,
+}
+
+impl Block {
+    const EMPTY: Block = Block {
+        deltas: [Delta {
+            timestamp: 0,
+            values: [0; MAX_FIELDS],
+        }; BLOCK_SAMPLES],
+        len: 0,
+        timestamp_width: 0,
+        field_width: 0,
+    };
+
+    /// Bit widths of the block once `delta` is added.
+    fn widths_with(&self, delta: &Delta, field_count: usize) -> (u8, u8) {
+        let field_width = delta.values[..field_count]
+            .iter()
+            .map(|v| bit_width(*v as u32))
+            .fold(self.field_width, u8::max);
+        (
+            self.timestamp_width.max(bit_width(delta.timestamp)),
+            field_width,
+        )
+    }
+
+    fn encoded_len(&self, field_count: usize) -> usize {
+        match self.len {
+            0 => 0,
+            len => block_len(len, field_count, self.timestamp_width, self.field_width),
+        }
+    }
+
+    /// Pack the block into `out`, which must hold [`encoded_len`](Self::encoded_len) bytes.
+    fn write(&self, field_count: usize, out: &mut [u8]) {
+        let out = &mut out[..self.encoded_len(field_count)];
+        out.fill(0);
+        out[0] = self.timestamp_width;
+        out[1] = self.field_width;
+        let mut pos = BLOCK_HEADER_LEN * 8;
+        for delta in &self.deltas[..self.len] {
+            put_bits(out, &mut pos, delta.timestamp, self.timestamp_width);
+            for value in &delta.values[..field_count] {
+                put_bits(out, &mut pos, *value as u32, self.field_width);
+            }
+        }
+    }
+}
+
+/// Reads the samples of a frame payload, checking it against the header as it goes.
+struct FieldReader<'a> {
+    header: FrameHeader,
+    payload: &'a [u8],
+    pos: usize,
+    remaining: u8,
+    prev: Option<Fields>,
+    prev_interval: u32,
+    block_left: usize,
+    block_bit: usize,
+    timestamp_width: u8,
+    field_width: u8,
+}
+
+impl<'a> FieldReader<'a> {
+    fn new(header: FrameHeader, payload: &'a [u8]) -> FieldReader<'a> {
+        FieldReader {
+            header,
+            payload,
+            pos: 0,
+            remaining: header.sample_count,
+            prev: None,
+            prev_interval: nominal_interval_ms(&header),
+            block_left: 0,
+            block_bit: 0,
+            timestamp_width: 0,
+            field_width: 0,
+        }
+    }
+
+    fn next_fields(&mut self) -> Result<Option<Fields>, FrameError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let field_count = self.header.stream.field_count(self.header.flags);
+        let fields = match self.prev.filter(|_| self.header.is_compressed()) {
+            None => {
+                let end = self.pos + self.header.sample_len();
+                let buf = self
+                    .payload
+                    .get(self.pos..end)
+                    .ok_or(FrameError::LengthMismatch)?;
+                self.pos = end;
+                decode_keyframe(buf, field_count)
+            }
+            Some(prev) => {
+                if self.block_left == 0 {
+                    self.start_block(field_count)?;
+                }
+                let pos = &mut self.block_bit;
+                let timestamp = get_bits(self.payload, pos, self.timestamp_width);
+                let interval = self
+                    .prev_interval
+                    .wrapping_add(unzigzag32(timestamp) as u32);
+                let mut values = prev.values;
+                for field in &mut values[..field_count] {
+                    let delta = get_bits(self.payload, pos, self.field_width);
+                    *field = field.wrapping_add(unzigzag16(delta as u16));
+                }
+                self.prev_interval = interval;
+                self.block_left -= 1;
+                Fields {
+                    timestamp_ms: prev.timestamp_ms.wrapping_add(interval),
+                    values,
+                }
+            }
+        };
+        self.remaining -= 1;
+        self.prev = Some(fields);
+        Ok(Some(fields))
+    }
+
+    /// Read the header of the next block and check it fits the payload.
+    fn start_block(&mut self, field_count: usize) -> Result<(), FrameError> {
+        let header = self
+            .payload
+            .get(self.pos..self.pos + BLOCK_HEADER_LEN)
+            .ok_or(FrameError::LengthMismatch)?;
+        let (timestamp_width, field_width) = (header[0], header[1]);
+        if timestamp_width > 32 || field_width > 16 {
+            return Err(FrameError::InvalidDelta);
+        }
+        let count = (self.remaining as usize).min(BLOCK_SAMPLES);
+        let end = self.pos + block_len(count, field_count, timestamp_width, field_width);
+        if end > self.payload.len() {
+            return Err(FrameError::LengthMismatch);
+        }
+        self.block_bit = (self.pos + BLOCK_HEADER_LEN) * 8;
+        self.block_left = count;
+        self.timestamp_width = timestamp_width;
+        self.field_width = field_width;
+        self.pos = end;
+        Ok(())
+    }
+}
+
+/// Builds a frame in place in a caller provided buffer.
+pub struct FrameWriter<'a, S: FrameSample> {
+    buf: &'a mut [u8],
+    header: FrameHeader,
+    len: usize,
+    prev: Option<Fields>,
+    prev_interval: u32,
+    block: Block,
+    _sample: core::marker::PhantomData<S>,
+}
+
+impl<'a, S: FrameSample> FrameWriter<'a, S> {
+    /// Start a new frame. The `sample_count` of `header` is ignored and filled in by [`finish`](Self::finish).
+    pub fn new(buf: &'a mut [u8], header: FrameHeader) -> Result<FrameWriter<'a, S>, FrameError> {
+        if buf.len() < HEADER_LEN {
+            return Err(FrameError::TooShort);
+        }
+        if !S::accepts(header.stream) {
+            return Err(FrameError::WrongStream);
+        }
+        if header.flags & !header.stream.supported_flags() != 0 {
+            return Err(FrameError::UnsupportedFlags(header.flags));
+        }
+        Ok(FrameWriter {
+            buf,
+            header: FrameHeader {
+                sample_count: 0,
+                ..header
+            },
+            len: HEADER_LEN,
+            prev: None,
+            prev_interval: nominal_interval_ms(&header),
+            block: Block::EMPTY,
+            _sample: core::marker::PhantomData,
+        })
+    }
+
+    pub fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.header.sample_count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.sample_count == 0
+    }
+
+    /// True when the next sample might not fit. A compressed frame may still take samples whose
+    /// deltas are small, [`push`](Self::push) tells for sure.
+    pub fn is_full(&self) -> bool {
+        let field_count = self.header.stream.field_count(self.header.flags);
+        let needed = if self.header.is_compressed() && !self.is_empty() {
+            block_len(self.block.len + 1, field_count, 32, 16)
+        } else {
+            self.header.sample_len()
+        };
+        self.header.sample_count == u8::MAX || self.len + needed > self.buf.len()
+    }
+
+    /// Whether [`push`](Self::push) would accept `sample`.
+    pub fn fits(&self, sample: &S) -> bool {
+        self.plan(&Fields::of(sample, &self.header)).is_ok()
+    }
+
+    /// Append a sample. The frame is left unchanged if it doesn't fit.
+    pub fn push(&mut self, sample: &S) -> Result<(), FrameError> {
+        let field_count = self.header.stream.field_count(self.header.flags);
+        let fields = Fields::of(sample, &self.header);
+        match (self.plan(&fields)?, self.prev) {
+            (Some((delta, timestamp_width, field_width)), Some(prev)) => {
+                self.block.deltas[self.block.len] = delta;
+                self.block.len += 1;
+                self.block.timestamp_width = timestamp_width;
+                self.block.field_width = field_width;
+                if self.block.len == BLOCK_SAMPLES {
+                    self.flush_block(field_count);
+                }
+                self.prev_interval = fields.timestamp_ms.wrapping_sub(prev.timestamp_ms);
+            }
+            _ => {
+                let end = self.len + self.header.sample_len();
+                encode_keyframe(&fields, field_count, &mut self.buf[self.len..end]);
+                self.len = end;
+            }
+        }
+        self.header.sample_count += 1;
+        self.prev = Some(fields);
+        Ok(())
+    }
+
+    /// Check that `fields` fit, returning their delta and the widths of the block holding it
+    /// unless they are stored as a keyframe.
+    fn plan(&self, fields: &Fields) -> Result<Option<(Delta, u8, u8)>, FrameError> {
+        if self.header.sample_count == u8::MAX {
+            return Err(FrameError::BufferFull);
+        }
+        let field_count = self.header.stream.field_count(self.header.flags);
+        let (end, delta) = match self.prev.filter(|_| self.header.is_compressed()) {
+            None => (self.len + self.header.sample_len(), None),
+            Some(prev) => {
+                let delta = delta(fields, &prev, self.prev_interval, field_count);
+                let (timestamp_width, field_width) = self.block.widths_with(&delta, field_count);
+                let block_len = block_len(
+                    self.block.len + 1,
+                    field_count,
+                    timestamp_width,
+                    field_width,
+                );
+                (
+                    self.len + block_len,
+                    Some((delta, timestamp_width, field_width)),
+                )
+            }
+        };
+        if end > self.buf.len() {
+            return Err(FrameError::BufferFull);
+        }
+        Ok(delta)
+    }
+
+    fn flush_block(&mut self, field_count: usize) {
+        if self.block.len == 0 {
+            return;
+        }
+        // `push` only accepts samples whose block fits the buffer.
+        self.block.write(field_count, &mut self.buf[self.len..]);
+        self.len += self.block.encoded_len(field_count);
+        self.block = Block::EMPTY;
+    }
+
+    /// Write the header and return the total frame length.
+    pub fn finish(mut self) -> usize {
+        self.flush_block(self.header.stream.field_count(self.header.flags));
+        // The constructor guarantees room for the header.
+        let _ = self.header.encode(self.buf);
+        self.len
+    }
+}
+
+/// Builds a notification of the accel or gyro stream in the unversioned layout they had before
+/// frames, which the motion_ble webpage reads: no header, per sample the `u32` timestamp, the
+/// full-scale index and `i16` x, y, z ([`LEGACY_SAMPLE_LEN`] bytes), at most
+/// [`LEGACY_MAX_SAMPLES`] of them.
+pub struct LegacyWriter<'a> {
+    buf: &'a mut [u8],
+    scale: u8,
+    len: usize,
+}
+
+impl<'a> LegacyWriter<'a> {
+    pub fn new(buf: &'a mut [u8], scale: u8) -> LegacyWriter<'a> {
+        LegacyWriter { buf, scale, len: 0 }
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.len / LEGACY_SAMPLE_LEN
+    }
+
+    /// Append a sample. Nothing is written if it doesn't fit.
+    pub fn push(&mut self, sample: &Sample) -> Result<(), FrameError> {
+        let end = self.len + LEGACY_SAMPLE_LEN;
+        if self.sample_count() == LEGACY_MAX_SAMPLES || end > self.buf.len() {
+            return Err(FrameError::BufferFull);
+        }
+        let out = &mut self.buf[self.len..end];
+        out[..4].copy_from_slice(&sample.timestamp_ms.to_le_bytes());
+        out[4] = self.scale;
+        for (i, value) in sample.xyz.iter().enumerate() {
+            out[5 + 2 * i..7 + 2 * i].copy_from_slice(&value.to_le_bytes());
+        }
+        self.len = end;
+        Ok(())
+    }
+
+    /// Return the length of the notification.
+    pub fn finish(self) -> usize {
+        self.len
+    }
+}
+
+/// A decoded view over an encoded frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame<'a> {
+    pub header: FrameHeader,
+    payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn decode(buf: &'a [u8]) -> Result<Frame<'a>, FrameError> {
+        let header = FrameHeader::decode(buf)?;
+        let payload = &buf[HEADER_LEN..];
+        // Compressed samples vary in length, so walk them all to validate the payload.
+        let mut reader = FieldReader::new(header, payload);
+        while reader.next_fields()?.is_some() {}
+        if reader.pos != payload.len() {
+            return Err(FrameError::LengthMismatch);
+        }
+        Ok(Frame { header, payload })
+    }
+
+    /// Iterate over the samples, which must be of the type the frame's stream carries.
+    pub fn samples<S: FrameSample>(&self) -> Result<impl Iterator<Item = S> + 'a, FrameError> {
+        if !S::accepts(self.header.stream) {
+            return Err(FrameError::WrongStream);
+        }
+        let flags = self.header.flags;
+        let mut reader = FieldReader::new(self.header, self.payload);
+        // `decode` checked the whole payload, so reading can't fail here.
+        Ok(
+            core::iter::from_fn(move || reader.next_fields().ok().flatten())
+                .map(move |f| S::from_fields(f.timestamp_ms, flags, &f.values)),
+        )
+    }
+}
+
+/// Value of the `stream_mode` characteristic, selecting what the firmware streams.
+///
+/// Bit 0 selects the combined stream instead of the separate accel and gyro streams, bits 1 and 2
+/// add the temperature and quaternion to combined samples, bit 3 delta encodes the frames of
+/// either. Bit 4 sends frames on the separate streams rather than the unversioned layout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamMode {
+    pub combined: bool,
+    pub temperature: bool,
+    pub quaternion: bool,
+    pub compressed: bool,
+    pub framed: bool,
+}
+
+impl StreamMode {
+    const COMBINED: u8 = 0x01;
+    const TEMPERATURE: u8 = 0x02;
+    const QUATERNION: u8 = 0x04;
+    const COMPRESSED: u8 = 0x08;
+    const FRAMED: u8 = 0x10;
+    const ALL: u8 =
+        Self::COMBINED | Self::TEMPERATURE | Self::QUATERNION | Self::COMPRESSED | Self::FRAMED;
+
+    /// Parse a mode byte. Unknown bits, optional fields without the combined stream and
+    /// compression without frames are rejected.
+    pub fn from_u8(value: u8) -> Option<StreamMode> {
+        if value & !Self::ALL != 0 {
+            return None;
+        }
+        let mode = StreamMode {
+            combined: value & Self::COMBINED != 0,
+            temperature: value & Self::TEMPERATURE != 0,
+            quaternion: value & Self::QUATERNION != 0,
+            compressed: value & Self::COMPRESSED != 0,
+            framed: value & Self::FRAMED != 0,
+        };
+        if !mode.combined && (mode.temperature || mode.quaternion) {
+            return None;
+        }
+        if mode.compressed && !mode.uses_frames() {
+            return None;
+        }
+        Some(mode)
+    }
+
+    /// Whether the mode streams frames rather than the unversioned layout of [`LegacyWriter`].
+    pub fn uses_frames(self) -> bool {
+        self.combined || self.framed
+    }
+
+    pub fn to_u8(self) -> u8 {
+        let mut value = 0;
+        if self.combined {
+            value |= Self::COMBINED;
+        }
+        if self.temperature {
+            value |= Self::TEMPERATURE;
+        }
+        if self.quaternion {
+            value |= Self::QUATERNION;
+        }
+        if self.compressed {
+            value |= Self::COMPRESSED;
+        }
+        if self.framed {
+            value |= Self::FRAMED;
+        }
+        value
+    }
+}
+
+/// Contents of the stream format characteristic, so clients can check what the firmware sends
+/// before subscribing.
+///
+/// Encoded as `[frame version, header length, stream count, (stream id, sample length)...]`,
+/// where the sample length is the one without optional fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FormatDescriptor {
+    pub frame_version: u8,
+    pub header_len: u8,
+    pub streams: &'static [StreamId],
+}
+
+impl FormatDescriptor {
+    pub const CURRENT: FormatDescriptor = FormatDescriptor {
+        frame_version: FRAME_VERSION,
+        header_len: HEADER_LEN as u8,
+        streams: &StreamId::ALL,
+    };
+
+    pub const fn encoded_len(&self) -> usize {
+        3 + 2 * self.streams.len()
+    }
+
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, FrameError> {
+        let len = self.encoded_len();
+        let out = out.get_mut(..len).ok_or(FrameError::BufferFull)?;
+        out[0] = self.frame_version;
+        out[1] = self.header_len;
+        out[2] = self.streams.len() as u8;
+        for (chunk, stream) in out[3..].as_chunks_mut::<2>().0.iter_mut().zip(self.streams) {
+            chunk[0] = *stream as u8;
+            chunk[1] = stream.sample_len(0) as u8;
+        }
+        Ok(len)
+    }
+
+    /// Iterate over the `(stream id, sample length)` pairs of an encoded descriptor.
+    pub fn decode_streams(buf: &[u8]) -> Result<impl Iterator<Item = (u8, u8)> + '_, FrameError> {
+        let count = *buf.get(2).ok_or(FrameError::TooShort)? as usize;
+        let entries = buf
+            .get(3..3 + 2 * count)
+            .ok_or(FrameError::LengthMismatch)?;
+        Ok(entries
+            .as_chunks::<2>()
+            .0
+            .iter()
+            .map(|[id, len]| (*id, *len)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::XorShift;
+
+    fn header(stream: StreamId) -> FrameHeader {
+        FrameHeader {
+            stream,
+            flags: 0,
+            scale: 3,
+            sample_count: 0,
+            sample_rate_hz: 100,
+            sequence: 0xBEEF,
+        }
+    }
+
+    fn random_sample(rng: &mut XorShift) -> Sample {
+        Sample {
+            timestamp_ms: rng.next_u32(),
+            xyz: [rng.next_i16(), rng.next_i16(), rng.next_i16()],
+        }
+    }
+
+    fn random_combined(rng: &mut XorShift, flags: u8) -> CombinedSample {
+        CombinedSample {
+            timestamp_ms: rng.next_u32(),
+            accel: [rng.next_i16(), rng.next_i16(), rng.next_i16()],
+            gyro: [rng.next_i16(), rng.next_i16(), rng.next_i16()],
+            temperature: (flags & FLAG_TEMPERATURE != 0).then(|| rng.next_i16()),
+            quaternion: (flags & FLAG_QUATERNION != 0).then(|| {
+                [
+                    rng.next_i16(),
+                    rng.next_i16(),
+                    rng.next_i16(),
+                    rng.next_i16(),
+                ]
+            }),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = [
+            Sample {
+                timestamp_ms: 0,
+                xyz: [1, -2, 3],
+            },
+            Sample {
+                timestamp_ms: u32::MAX,
+                xyz: [i16::MIN, i16::MAX, 0],
+            },
+        ];
+        let mut buf = [0u8; 64];
+        let mut writer = FrameWriter::new(&mut buf, header(StreamId::Gyro)).unwrap();
+        for s in &samples {
+            writer.push(s).unwrap();
+        }
+        let len = writer.finish();
+        assert_eq!(len, HEADER_LEN + 2 * SAMPLE_LEN);
+
+        let frame = Frame::decode(&buf[..len]).unwrap();
+        assert_eq!(
+            frame.header,
+            FrameHeader {
+                sample_count: 2,
+                ..header(StreamId::Gyro)
+            }
+        );
+        assert!(frame
+            .samples::<Sample>()
+            .unwrap()
+            .eq(samples.iter().copied()));
+    }
+
+    #[test]
+    fn combined_round_trip_with_optional_fields() {
+        let mut rng = XorShift::new(3);
+        for flags in 0..=(FLAG_TEMPERATURE | FLAG_QUATERNION) {
+            let sample = random_combined(&mut rng, flags);
+            assert_eq!(sample.flags(), flags);
+            let header = FrameHeader {
+                flags,
+                scale: FrameHeader::combined_scale(1, 3),
+                ..header(StreamId::Combined)
+            };
+            let mut buf = [0u8; 64];
+            let mut writer = FrameWriter::new(&mut buf, header).unwrap();
+            writer.push(&sample).unwrap();
+            let len = writer.finish();
+            assert_eq!(len, HEADER_LEN + StreamId::Combined.sample_len(flags));
+
+            let frame = Frame::decode(&buf[..len]).unwrap();
+            assert_eq!(frame.header.scale, 0x31);
+            let mut samples = frame.samples::<CombinedSample>().unwrap();
+            assert_eq!(samples.next(), Some(sample));
+            assert_eq!(samples.next(), None);
+        }
+    }
+
+    #[test]
+    fn sample_type_must_match_stream() {
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            FrameWriter::<CombinedSample>::new(&mut buf, header(StreamId::Accel)).err(),
+            Some(FrameError::WrongStream)
+        );
+        let len = FrameWriter::<Sample>::new(&mut buf, header(StreamId::Accel))
+            .unwrap()
+            .finish();
+        let frame = Frame::decode(&buf[..len]).unwrap();
+        assert_eq!(
+            frame.samples::<CombinedSample>().err(),
+            Some(FrameError::WrongStream)
+        );
+    }
+
+    #[test]
+    fn writer_stops_at_capacity() {
+        let mut buf = [0u8; 110];
+        let mut writer = FrameWriter::new(&mut buf, header(StreamId::Accel)).unwrap();
+        let mut pushed = 0;
+        while writer.push(&Sample::default()).is_ok() {
+            pushed += 1;
+        }
+        assert_eq!(pushed, header(StreamId::Accel).capacity(110));
+        assert_eq!(pushed, 10);
+        assert!(writer.is_full());
+    }
+
+    #[test]
+    fn legacy_layout() {
+        let sample = Sample {
+            timestamp_ms: 0x0403_0201,
+            xyz: [1, -2, 0x0605],
+        };
+        let mut buf = [0u8; 120];
+        let mut writer = LegacyWriter::new(&mut buf, 2);
+        while writer.push(&sample).is_ok() {}
+        assert_eq!(writer.sample_count(), LEGACY_MAX_SAMPLES);
+        assert_eq!(writer.finish(), 110);
+        for chunk in buf[..110].chunks(LEGACY_SAMPLE_LEN) {
+            assert_eq!(chunk, [1, 2, 3, 4, 2, 1, 0, 0xfe, 0xff, 5, 6]);
+        }
+
+        // Only whole samples, however short the buffer.
+        let mut buf = [0u8; 20];
+        let mut writer = LegacyWriter::new(&mut buf, 0);
+        writer.push(&sample).unwrap();
+        assert_eq!(writer.push(&sample), Err(FrameError::BufferFull));
+        assert_eq!(writer.finish(), LEGACY_SAMPLE_LEN);
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut buf = [0u8; 32];
+        let len = FrameWriter::new(&mut buf, header(StreamId::Accel))
+            .map(|mut w| {
+                w.push(&Sample::default()).unwrap();
+                w.finish()
+            })
+            .unwrap();
+
+        assert_eq!(Frame::decode(&buf[..4]).unwrap_err(), FrameError::TooShort);
+        assert_eq!(
+            Frame::decode(&buf[..len - 1]).unwrap_err(),
+            FrameError::LengthMismatch
+        );
+        buf[2] = FLAG_TEMPERATURE;
+        assert_eq!(
+            Frame::decode(&buf[..len]).unwrap_err(),
+            FrameError::UnsupportedFlags(FLAG_TEMPERATURE)
+        );
+        buf[1] = 0x7F;
+        assert_eq!(
+            Frame::decode(&buf[..len]).unwrap_err(),
+            FrameError::UnknownStream(0x7F)
+        );
+        buf[0] = FRAME_VERSION + 1;
+        assert_eq!(
+            Frame::decode(&buf[..len]).unwrap_err(),
+            FrameError::UnsupportedVersion(FRAME_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let mut samples = [
+            Sample {
+                timestamp_ms: u32::MAX - 5,
+                xyz: [100, -100, 0],
+            },
+            Sample {
+                timestamp_ms: 4,
+                xyz: [101, -101, 0],
+            },
+            Sample {
+                timestamp_ms: 14,
+                xyz: [100, -100, 1],
+            },
+            Sample {
+                timestamp_ms: 24,
+                xyz: [99, -100, 1],
+            },
+        ];
+        let header = FrameHeader {
+            flags: FLAG_COMPRESSED,
+            ..header(StreamId::Accel)
+        };
+        let mut buf = [0u8; 64];
+        let mut writer = FrameWriter::new(&mut buf, header).unwrap();
+        for s in &samples {
+            writer.push(s).unwrap();
+        }
+        let len = writer.finish();
+        // Keyframe, then one block with the steady 10 ms interval in 0 bits and 2 bits per field:
+        // zigzag deltas 2 1 0, 1 2 2, 1 0 0.
+        assert_eq!(len, HEADER_LEN + SAMPLE_LEN + 2 + 3);
+        assert_eq!(
+            buf[HEADER_LEN + SAMPLE_LEN..len],
+            [0, 2, 0b01_00_01_10, 0b00_01_10_10, 0]
+        );
+
+        let frame = Frame::decode(&buf[..len]).unwrap();
+        assert!(frame.header.is_compressed());
+        assert!(frame
+            .samples::<Sample>()
+            .unwrap()
+            .eq(samples.iter().copied()));
+        assert_eq!(
+            Frame::decode(&buf[..len - 1]).unwrap_err(),
+            FrameError::LengthMismatch
+        );
+
+        // Deltas that wrap around still round trip, at full width.
+        samples[2] = Sample {
+            timestamp_ms: 1_000,
+            xyz: [i16::MIN, i16::MAX, -1],
+        };
+        samples[3].xyz = [i16::MAX, i16::MIN, 1];
+        let mut writer = FrameWriter::new(&mut buf, header).unwrap();
+        for s in &samples {
+            writer.push(s).unwrap();
+        }
+        let len = writer.finish();
+        let frame = Frame::decode(&buf[..len]).unwrap();
+        assert!(frame
+            .samples::<Sample>()
+            .unwrap()
+            .eq(samples.iter().copied()));
+    }
+
+    #[test]
+    fn compression_fits_more_samples() {
+        // A slowly moving 100 Hz signal with a little noise, as the sensor produces at rest.
+        let mut rng = XorShift::new(7);
+        let mut sample = CombinedSample::default();
+        let mut next = |rng: &mut XorShift| {
+            sample.timestamp_ms += 10;
+            for field in sample.accel.iter_mut().chain(&mut sample.gyro) {
+                *field = field.wrapping_add(rng.below(41) as i16 - 20);
+            }
+            sample
+        };
+        let header = FrameHeader {
+            flags: FLAG_COMPRESSED,
+            ..header(StreamId::Combined)
+        };
+        let mut buf = [0u8; 244];
+        let mut writer = FrameWriter::new(&mut buf, header).unwrap();
+        while writer.push(&next(&mut rng)).is_ok() {}
+        assert!(writer.sample_count() >= 3 * header.capacity(244));
+    }
+
+    #[test]
+    fn rejects_bad_blocks() {
+        let header = FrameHeader {
+            flags: FLAG_COMPRESSED,
+            sample_count: 2,
+            ..header(StreamId::Gyro)
+        };
+        let mut buf = [0u8; 32];
+        header.encode(&mut buf).unwrap();
+        let block = HEADER_LEN + SAMPLE_LEN;
+        // An `i16` field can't change by more than 16 bits.
+        buf[block..block + 2].copy_from_slice(&[0, 17]);
+        assert_eq!(
+            Frame::decode(&buf[..block + 9]).unwrap_err(),
+            FrameError::InvalidDelta
+        );
+        buf[block..block + 2].copy_from_slice(&[33, 0]);
+        assert_eq!(
+            Frame::decode(&buf[..block + 7]).unwrap_err(),
+            FrameError::InvalidDelta
+        );
+        // One sample of 8 + 3 * 8 bits.
+        buf[block..block + 2].copy_from_slice(&[8, 8]);
+        assert!(Frame::decode(&buf[..block + 6]).is_ok());
+        assert_eq!(
+            Frame::decode(&buf[..block + 5]).unwrap_err(),
+            FrameError::LengthMismatch
+        );
+        assert_eq!(
+            Frame::decode(&buf[..block + 7]).unwrap_err(),
+            FrameError::LengthMismatch
+        );
+    }
+
+    #[test]
+    fn stream_mode_bits() {
+        for value in 0..=u8::MAX {
+            match StreamMode::from_u8(value) {
+                Some(mode) => assert_eq!(mode.to_u8(), value),
+                None => assert!(
+                    value > 0x1F || value & 0x07 > 0x01 || value & 0x19 == 0x08,
+                    "{:#04x}",
+                    value
+                ),
+            }
+        }
+        assert_eq!(StreamMode::from_u8(0x02), None);
+        assert_eq!(StreamMode::from_u8(0x08), None);
+        assert_eq!(
+            StreamMode::from_u8(0x03),
+            Some(StreamMode {
+                combined: true,
+                temperature: true,
+                quaternion: false,
+                compressed: false,
+                framed: false,
+            })
+        );
+        assert_eq!(
+            StreamMode::from_u8(0x18),
+            Some(StreamMode {
+                compressed: true,
+                framed: true,
+                ..StreamMode::default()
+            })
+        );
+    }
+
+    #[test]
+    fn format_descriptor_round_trip() {
+        let mut buf = [0u8; 16];
+        let len = FormatDescriptor::CURRENT.encode(&mut buf).unwrap();
+        assert_eq!(buf[0], FRAME_VERSION);
+        assert_eq!(buf[1] as usize, HEADER_LEN);
+        let streams: [(u8, u8); 3] = [
+            (StreamId::Accel as u8, SAMPLE_LEN as u8),
+            (StreamId::Gyro as u8, SAMPLE_LEN as u8),
+            (StreamId::Combined as u8, 16),
+        ];
+        assert!(FormatDescriptor::decode_streams(&buf[..len])
+            .unwrap()
+            .eq(streams.iter().copied()));
+    }
+
+    #[test]
+    fn fuzz_decode_random_input() {
+        let mut rng = XorShift::new(0x5EED);
+        let mut buf = [0u8; 256];
+        for _ in 0..20_000 {
+            let len = rng.below(buf.len() + 1);
+            rng.fill(&mut buf[..len]);
+            // Bias towards valid headers so the payload checks get exercised too.
+            if len > 2 && rng.below(2) == 0 {
+                let stream = StreamId::ALL[rng.below(StreamId::ALL.len())];
+                buf[0] = FRAME_VERSION;
+                buf[1] = stream as u8;
+                buf[2] &= stream.supported_flags();
+            }
+            if let Ok(frame) = Frame::decode(&buf[..len]) {
+                let count = match frame.header.stream {
+                    StreamId::Combined => frame.samples::<CombinedSample>().unwrap().count(),
+                    _ => frame.samples::<Sample>().unwrap().count(),
+                };
+                assert_eq!(count, frame.header.sample_count as usize);
+            }
+        }
+    }
+
+    fn check_random_round_trip<S: FrameSample + Copy + PartialEq + core::fmt::Debug>(
+        rng: &mut XorShift,
+        header: FrameHeader,
+        mut sample: impl FnMut(&mut XorShift) -> S,
+    ) {
+        let mut buf = [0u8; 512];
+        let mut expected = [None; 64];
+        let capacity = HEADER_LEN + rng.below(buf.len() - HEADER_LEN);
+        let mut writer = FrameWriter::new(&mut buf[..capacity], header).unwrap();
+        let mut count = 0;
+        while count < expected.len() {
+            let s = sample(rng);
+            let (full, fits) = (writer.is_full(), writer.fits(&s));
+            if writer.push(&s).is_err() {
+                // `is_full` may give up early on compressed frames, but never too late.
+                assert!(full && !fits);
+                break;
+            }
+            assert!(fits);
+            expected[count] = Some(s);
+            count += 1;
+        }
+        let len = writer.finish();
+
+        let frame = Frame::decode(&buf[..len]).unwrap();
+        assert_eq!(frame.header.sample_count as usize, count);
+        assert_eq!(frame.header.sequence, header.sequence);
+        assert!(frame
+            .samples::<S>()
+            .unwrap()
+            .map(Some)
+            .eq(expected[..count].iter().copied()));
+    }
+
+    #[test]
+    fn fuzz_round_trip_random_frames() {
+        let mut rng = XorShift::new(42);
+        for _ in 0..2_000 {
+            let stream = StreamId::ALL[rng.below(StreamId::ALL.len())];
+            let flags = rng.next_u32() as u8 & stream.supported_flags();
+            let header = FrameHeader {
+                stream,
+                flags,
+                scale: rng.next_u32() as u8,
+                sample_count: 0,
+                sample_rate_hz: rng.next_u32() as u16,
+                sequence: rng.next_u32() as u16,
+            };
+            match stream {
+                StreamId::Combined => {
+                    check_random_round_trip(&mut rng, header, |rng| random_combined(rng, flags))
+                }
+                _ => check_random_round_trip(&mut rng, header, random_sample),
+            }
+        }
+    }
+}
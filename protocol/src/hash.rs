@@ -0,0 +1,123 @@
+//! Checksums for firmware images: CRC-32 to catch transfer errors cheaply and SHA-256 to identify
+//! the image, both computed incrementally as the image arrives in chunks.
+
+use sha2::Digest;
+
+/// CRC-32 as used by zlib and Ethernet (reflected, polynomial `0xEDB88320`).
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.0;
+        for &byte in data {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        self.0 = crc;
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// SHA-256 (FIPS 180-4), from the `sha2` crate.
+#[derive(Clone, Debug, Default)]
+pub struct Sha256(sha2::Sha256);
+
+impl Sha256 {
+    pub const DIGEST_LEN: usize = 32;
+
+    pub fn new() -> Self {
+        Self(sha2::Sha256::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; Self::DIGEST_LEN] {
+        self.0.finalize().into()
+    }
+}
+
+/// SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> [u8; Sha256::DIGEST_LEN] {
+    let mut sha = Sha256::new();
+    sha.update(data);
+    sha.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{hex, XorShift};
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn sha256_test_vectors() {
+        assert_eq!(
+            sha256(b""),
+            hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+        // Two blocks, from FIPS 180-4.
+        assert_eq!(
+            sha256(
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmno\
+                  ijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"
+            ),
+            hex("cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1")
+        );
+    }
+
+    #[test]
+    fn incremental_matches_one_shot() {
+        let mut rng = XorShift::new(46);
+        let mut data = [0; 1000];
+        rng.fill(&mut data);
+        let mut sha = Sha256::new();
+        let mut crc = Crc32::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let take = (rng.below(130) + 1).min(rest.len());
+            sha.update(&rest[..take]);
+            crc.update(&rest[..take]);
+            rest = &rest[take..];
+        }
+        assert_eq!(sha.finish(), sha256(&data));
+        assert_eq!(crc.finish(), crc32(&data));
+    }
+}
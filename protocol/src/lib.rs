@@ -0,0 +1,21 @@
+//! Wire formats shared between the firmware and host tools.
+//!
+//! Everything in here is `no_std`, free of hardware dependencies and covered by host tests:
+//! run them with `cargo test-host` from the repository root.
+#![no_std]
+
+pub mod broadcast;
+pub mod config;
+pub mod console;
+pub mod dfu;
+pub mod download;
+pub mod ed25519;
+pub mod frame;
+pub mod hash;
+pub mod link;
+pub mod pre_trigger;
+pub mod recording;
+pub mod stats;
+
+#[cfg(test)]
+mod test_util;
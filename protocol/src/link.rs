@@ -0,0 +1,74 @@
+//! Connection parameters reported by the `link_params` characteristic.
+//!
+//! | offset | size | field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 4    | connection interval in microseconds (0 = unknown)   |
+//! | 4      | 2    | peripheral latency in connection events             |
+//! | 6      | 2    | supervision timeout in milliseconds                 |
+//! | 8      | 2    | ATT MTU                                             |
+//! | 10     | 1    | TX PHY (HCI value: 1 = 1M, 2 = 2M, 3/4 = coded)     |
+//! | 11     | 1    | RX PHY                                              |
+//! | 12     | 1    | requested profile: 0 = idle, 1 = streaming          |
+//!
+//! All fields are little endian.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkParams {
+    pub conn_interval_us: u32,
+    pub peripheral_latency: u16,
+    pub supervision_timeout_ms: u16,
+    pub att_mtu: u16,
+    pub tx_phy: u8,
+    pub rx_phy: u8,
+    pub streaming: bool,
+}
+
+impl LinkParams {
+    pub const ENCODED_LEN: usize = 13;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0u8; Self::ENCODED_LEN];
+        out[0..4].copy_from_slice(&self.conn_interval_us.to_le_bytes());
+        out[4..6].copy_from_slice(&self.peripheral_latency.to_le_bytes());
+        out[6..8].copy_from_slice(&self.supervision_timeout_ms.to_le_bytes());
+        out[8..10].copy_from_slice(&self.att_mtu.to_le_bytes());
+        out[10] = self.tx_phy;
+        out[11] = self.rx_phy;
+        out[12] = self.streaming as u8;
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<LinkParams> {
+        let buf: &[u8; Self::ENCODED_LEN] = buf.try_into().ok()?;
+        Some(LinkParams {
+            conn_interval_us: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            peripheral_latency: u16::from_le_bytes([buf[4], buf[5]]),
+            supervision_timeout_ms: u16::from_le_bytes([buf[6], buf[7]]),
+            att_mtu: u16::from_le_bytes([buf[8], buf[9]]),
+            tx_phy: buf[10],
+            rx_phy: buf[11],
+            streaming: buf[12] != 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let params = LinkParams {
+            conn_interval_us: 7_500,
+            peripheral_latency: 4,
+            supervision_timeout_ms: 4_000,
+            att_mtu: 517,
+            tx_phy: 2,
+            rx_phy: 1,
+            streaming: true,
+        };
+        assert_eq!(LinkParams::decode(&params.encode()), Some(params));
+        assert_eq!(LinkParams::decode(&params.encode()[..12]), None);
+    }
+}
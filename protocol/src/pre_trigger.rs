@@ -0,0 +1,174 @@
+//! The pre-trigger buffer: samples taken between read windows, so a window opened by the motion
+//! interrupt starts with the lead-up to it rather than after the motion crossed the threshold.
+//!
+//! While `pre_trigger` and motion detection are on, the firmware reads the sensor at the motion
+//! sample interval between windows as well, which keeps it and the CPU busier. The samples of the
+//! last `pre_trigger` milliseconds are published at the start of the next triggered window, whose
+//! epoch moves back to the first of them. The buffer holds [`CAPACITY`] samples, so the setting is
+//! only accepted while it needs no more at the motion sample interval, see [`holds`].
+
+/// Longest pre-trigger buffer accepted.
+pub const PRE_TRIGGER_MAX_MS: u16 = 2000;
+
+/// Samples kept at most. They are published at once, so this stays well below the length of the
+/// firmware's sensor channel.
+pub const CAPACITY: usize = 64;
+
+/// Whether [`CAPACITY`] samples cover `len_ms` at a sample every `interval_ms`. An interval of 0
+/// (as fast as possible) never does, unless the buffer is off.
+pub fn holds(len_ms: u16, interval_ms: u64) -> bool {
+    // Both ends of the span count, hence one more sample than intervals.
+    len_ms == 0 || (interval_ms != 0 && len_ms as u64 / interval_ms < CAPACITY as u64)
+}
+
+/// Samples with the time they were taken, oldest first. When full, the oldest is dropped.
+pub struct PreTrigger<T> {
+    samples: [Option<(u32, T)>; CAPACITY],
+    /// Index of the oldest sample.
+    start: usize,
+    len: usize,
+}
+
+impl<T> Default for PreTrigger<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> PreTrigger<T> {
+    pub const fn new() -> Self {
+        Self {
+            samples: [const { None }; CAPACITY],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Keep `sample`, taken at `timestamp_ms`, dropping the oldest sample when full.
+    pub fn push(&mut self, timestamp_ms: u32, sample: T) {
+        if self.len == CAPACITY {
+            self.pop();
+        }
+        self.samples[(self.start + self.len) % CAPACITY] = Some((timestamp_ms, sample));
+        self.len += 1;
+    }
+
+    /// Drop the samples taken more than `len_ms` before `now_ms` and return when the oldest of the
+    /// rest was taken.
+    pub fn trim(&mut self, now_ms: u32, len_ms: u16) -> Option<u32> {
+        let since = now_ms.saturating_sub(len_ms as u32);
+        while self
+            .oldest()
+            .is_some_and(|timestamp_ms| timestamp_ms < since)
+        {
+            self.pop();
+        }
+        self.oldest()
+    }
+
+    /// Take the samples, oldest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        core::iter::from_fn(|| self.pop().map(|(_, sample)| sample))
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    fn oldest(&self) -> Option<u32> {
+        self.samples[self.start]
+            .as_ref()
+            .map(|&(timestamp_ms, _)| timestamp_ms)
+    }
+
+    fn pop(&mut self) -> Option<(u32, T)> {
+        let sample = self.samples[self.start].take()?;
+        self.start = (self.start + 1) % CAPACITY;
+        self.len -= 1;
+        Some(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer with a sample every `interval_ms` from 0 to `last_ms`, each holding its timestamp.
+    fn filled(interval_ms: u32, last_ms: u32) -> PreTrigger<u32> {
+        let mut buffer = PreTrigger::new();
+        for timestamp_ms in (0..=last_ms).step_by(interval_ms as usize) {
+            buffer.push(timestamp_ms, timestamp_ms);
+        }
+        buffer
+    }
+
+    #[test]
+    fn trims_to_the_window() {
+        let mut buffer = filled(10, 300);
+        assert_eq!(buffer.len(), 31);
+        // The sample exactly `len_ms` back is kept and becomes the epoch.
+        assert_eq!(buffer.trim(305, 100), Some(210));
+        assert!(buffer.drain().eq((210..=300).step_by(10)));
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.trim(305, 100), None);
+
+        let mut buffer = filled(10, 300);
+        assert_eq!(buffer.trim(300, 100), Some(200));
+        // Nothing recent enough leaves it empty.
+        assert_eq!(buffer.trim(1000, 100), None);
+        assert!(buffer.is_empty());
+        // Shortly after boot the window reaches back to 0.
+        let mut buffer = filled(10, 50);
+        assert_eq!(buffer.trim(50, PRE_TRIGGER_MAX_MS), Some(0));
+        assert_eq!(buffer.len(), 6);
+    }
+
+    #[test]
+    fn drops_the_oldest_when_full() {
+        let mut buffer = filled(10, 999);
+        assert_eq!(buffer.len(), CAPACITY);
+        let first = (100 - CAPACITY as u32) * 10;
+        assert_eq!(buffer.trim(990, PRE_TRIGGER_MAX_MS), Some(first));
+        assert!(buffer.drain().eq((first..=990).step_by(10)));
+
+        // Still in order after wrapping around more than once.
+        for timestamp_ms in 0..3 * CAPACITY as u32 + 5 {
+            buffer.push(timestamp_ms, timestamp_ms);
+        }
+        let first = 2 * CAPACITY as u32 + 5;
+        assert_eq!(buffer.trim(first + 100, 100), Some(first));
+        assert!(buffer.drain().eq(first..3 * CAPACITY as u32 + 5));
+    }
+
+    #[test]
+    fn clear_empties() {
+        let mut buffer = filled(10, 100);
+        buffer.clear();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.trim(100, 100), None);
+        buffer.push(7, 7);
+        assert!(buffer.drain().eq([7]));
+    }
+
+    #[test]
+    fn holds_what_fits() {
+        assert!(holds(0, 0));
+        assert!(holds(630, 10));
+        assert!(!holds(640, 10));
+        assert!(!holds(1000, 10));
+        assert!(holds(PRE_TRIGGER_MAX_MS, 32));
+        assert!(!holds(1, 0));
+        // The span actually covered at the largest accepted setting fits.
+        let mut buffer = filled(10, 1000);
+        assert_eq!(buffer.trim(1000, 630), Some(370));
+        assert_eq!(buffer.len(), CAPACITY);
+    }
+}
@@ -0,0 +1,577 @@
+//! On-device recording: read windows written to a flash log partition while no one may be
+//! listening, to be downloaded later.
+//!
+//! The log is append-only. It is a sequence of blocks, each one flash sector, written whole and in
+//! order from the start of the partition; the first erased block is where the next one goes. When
+//! the partition is full recording stops, nothing is overwritten.
+//!
+//! Block layout, all integers little endian:
+//!
+//! | offset | size | field                                                         |
+//! |--------|------|---------------------------------------------------------------|
+//! | 0      | 4    | [`MAGIC`], whose last byte is the format version              |
+//! | 4      | 2    | session id                                                    |
+//! | 6      | 2    | block number within the session                               |
+//! | 8      | 2    | payload length                                                |
+//! | 10     | 2    | samples in the block                                          |
+//! | 12     | 4    | timestamp of the last sample in the block, ms since the epoch |
+//! | 16     | 4    | CRC-32 of bytes 0..16 and the payload                         |
+//! | 20     | ..   | payload, the rest of the block is `0xff`                      |
+//!
+//! A session is one read window. The payload of its block 0 starts with a [`SessionHeader`]. The
+//! rest of the payloads are records, each a `u16` length followed by a frame in the format of
+//! [`crate::frame`], so recorded and streamed samples decode the same way. The blocks of a deleted
+//! session have the block number [`DELETED`] and no payload, see [`crate::download`].
+use crate::config::ConfigPacket;
+use crate::hash::Crc32;
+
+/// Length of a block, one flash sector.
+pub const BLOCK_LEN: usize = 4096;
+pub const MAGIC: [u8; 4] = *b"MPL1";
+pub const HEADER_LEN: usize = 20;
+/// Most payload bytes a block holds.
+pub const PAYLOAD_LEN: usize = BLOCK_LEN - HEADER_LEN;
+/// Length prefix of each record.
+const RECORD_PREFIX_LEN: usize = 2;
+/// Block number of the blocks of a deleted session.
+pub const DELETED: u16 = 0xffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LogError {
+    /// Reading or writing the flash failed.
+    Flash,
+    /// No erased block is left.
+    Full,
+    /// No session was started, or it ended because the log filled up.
+    NoSession,
+    /// The record doesn't fit a block.
+    TooLarge,
+    /// The block was never written, or not by this format version.
+    NotABlock,
+    /// The block's CRC-32 doesn't match, it was torn by a reset or the flash wore out.
+    Crc,
+    /// Not possible while a session is being recorded.
+    Busy,
+}
+
+/// The header of a block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlockHeader {
+    pub session: u16,
+    pub block: u16,
+    pub payload_len: u16,
+    pub samples: u16,
+    pub end_ms: u32,
+    pub crc32: u32,
+}
+
+impl BlockHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0; HEADER_LEN];
+        out[..4].copy_from_slice(&MAGIC);
+        out[4..6].copy_from_slice(&self.session.to_le_bytes());
+        out[6..8].copy_from_slice(&self.block.to_le_bytes());
+        out[8..10].copy_from_slice(&self.payload_len.to_le_bytes());
+        out[10..12].copy_from_slice(&self.samples.to_le_bytes());
+        out[12..16].copy_from_slice(&self.end_ms.to_le_bytes());
+        out[16..20].copy_from_slice(&self.crc32.to_le_bytes());
+        out
+    }
+
+    /// Decode the header at the start of `buf`, without checking the CRC-32.
+    pub fn decode(buf: &[u8]) -> Result<BlockHeader, LogError> {
+        let buf = buf.get(..HEADER_LEN).ok_or(LogError::NotABlock)?;
+        if buf[..4] != MAGIC {
+            return Err(LogError::NotABlock);
+        }
+        let header = BlockHeader {
+            session: u16_at(buf, 4),
+            block: u16_at(buf, 6),
+            payload_len: u16_at(buf, 8),
+            samples: u16_at(buf, 10),
+            end_ms: u32_at(buf, 12),
+            crc32: u32_at(buf, 16),
+        };
+        if header.payload_len as usize > PAYLOAD_LEN {
+            return Err(LogError::NotABlock);
+        }
+        Ok(header)
+    }
+}
+
+/// Check a whole block and return its header and payload.
+pub fn decode_block(block: &[u8; BLOCK_LEN]) -> Result<(BlockHeader, &[u8]), LogError> {
+    let header = BlockHeader::decode(block)?;
+    let payload = &block[HEADER_LEN..HEADER_LEN + header.payload_len as usize];
+    if block_crc(&block[..HEADER_LEN], payload) != header.crc32 {
+        return Err(LogError::Crc);
+    }
+    Ok((header, payload))
+}
+
+/// The records in `payload`, after the session header of block 0. Stops at a record that runs past
+/// the end.
+pub fn records(payload: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = payload;
+    core::iter::from_fn(move || {
+        let len = u16::from_le_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+        let record = rest.get(RECORD_PREFIX_LEN..RECORD_PREFIX_LEN + len)?;
+        rest = &rest[RECORD_PREFIX_LEN + len..];
+        Some(record)
+    })
+}
+
+/// What was recorded and how, at the start of every session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SessionHeader {
+    /// Milliseconds since boot when the session started. The device has no clock.
+    pub start_ms: u32,
+    /// Milliseconds between samples.
+    pub sample_interval_ms: u16,
+    pub config: ConfigPacket,
+}
+
+impl SessionHeader {
+    pub const ENCODED_LEN: usize = 4 + 2 + ConfigPacket::ENCODED_LEN;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0; Self::ENCODED_LEN];
+        out[..4].copy_from_slice(&self.start_ms.to_le_bytes());
+        out[4..6].copy_from_slice(&self.sample_interval_ms.to_le_bytes());
+        out[6..].copy_from_slice(&self.config.encode());
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<SessionHeader> {
+        let buf = buf.get(..Self::ENCODED_LEN)?;
+        Some(SessionHeader {
+            start_ms: u32_at(buf, 0),
+            sample_interval_ms: u16_at(buf, 4),
+            config: ConfigPacket::decode(&buf[6..]).ok()?,
+        })
+    }
+}
+
+/// The log partition.
+pub trait LogFlash {
+    type Error;
+
+    /// Size of the partition in bytes.
+    fn capacity(&self) -> u32;
+
+    /// Erase the sector at `offset`, a multiple of [`BLOCK_LEN`], and write `data` to it.
+    fn write_sector(&mut self, offset: u32, data: &[u8; BLOCK_LEN]) -> Result<(), Self::Error>;
+
+    /// Erase the sector at `offset`, a multiple of [`BLOCK_LEN`].
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error>;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// The session being recorded.
+#[derive(Clone, Copy, Debug)]
+struct OpenSession {
+    id: u16,
+    block: u16,
+    /// Payload bytes in the block buffer.
+    len: usize,
+    samples: u16,
+    end_ms: u32,
+}
+
+/// Appends sessions to the log, see the module docs.
+pub struct Recorder {
+    /// Blocks in the partition, 0 until mounted.
+    blocks: u32,
+    /// The first erased block.
+    next_block: u32,
+    next_session: u16,
+    session: Option<OpenSession>,
+    /// The block being filled, written once full or when the session ends.
+    block: [u8; BLOCK_LEN],
+}
+
+impl Recorder {
+    pub const fn new() -> Self {
+        Self {
+            blocks: 0,
+            next_block: 0,
+            next_session: 0,
+            session: None,
+            block: [0xff; BLOCK_LEN],
+        }
+    }
+
+    /// Find where the log ends and which session id comes next.
+    pub fn mount<F: LogFlash>(&mut self, flash: &mut F) -> Result<(), LogError> {
+        let blocks = flash.capacity() / BLOCK_LEN as u32;
+        let mut next_session = 0;
+        let mut next_block = blocks;
+        let mut header = [0; HEADER_LEN];
+        for index in 0..blocks {
+            flash
+                .read(index * BLOCK_LEN as u32, &mut header)
+                .map_err(|_| LogError::Flash)?;
+            if header.iter().all(|b| *b == 0xff) {
+                next_block = index;
+                break;
+            }
+            // Torn or foreign blocks stay in the log, the download skips them.
+            if let Ok(header) = BlockHeader::decode(&header) {
+                next_session = next_session.max(header.session.wrapping_add(1));
+            }
+        }
+        *self = Recorder {
+            blocks,
+            next_block,
+            next_session,
+            ..Recorder::new()
+        };
+        Ok(())
+    }
+
+    /// Blocks in the partition.
+    pub fn capacity(&self) -> u32 {
+        self.blocks
+    }
+
+    /// Blocks written so far.
+    pub fn used(&self) -> u32 {
+        self.next_block
+    }
+
+    /// The id of the session being recorded.
+    pub fn session(&self) -> Option<u16> {
+        self.session.map(|s| s.id)
+    }
+
+    /// Start a session, ending the one before if need be, and return its id.
+    pub fn start<F: LogFlash>(
+        &mut self,
+        flash: &mut F,
+        header: SessionHeader,
+    ) -> Result<u16, LogError> {
+        self.finish(flash)?;
+        if self.next_block >= self.blocks {
+            return Err(LogError::Full);
+        }
+        let id = self.next_session;
+        self.next_session = id.wrapping_add(1);
+        self.block[HEADER_LEN..HEADER_LEN + SessionHeader::ENCODED_LEN]
+            .copy_from_slice(&header.encode());
+        self.session = Some(OpenSession {
+            id,
+            block: 0,
+            len: SessionHeader::ENCODED_LEN,
+            samples: 0,
+            end_ms: 0,
+        });
+        Ok(id)
+    }
+
+    /// Append a frame of `samples` samples, the last of them taken at `end_ms`.
+    pub fn append<F: LogFlash>(
+        &mut self,
+        flash: &mut F,
+        frame: &[u8],
+        samples: u16,
+        end_ms: u32,
+    ) -> Result<(), LogError> {
+        let mut session = self.session.ok_or(LogError::NoSession)?;
+        let len = RECORD_PREFIX_LEN + frame.len();
+        if len > PAYLOAD_LEN - SessionHeader::ENCODED_LEN {
+            return Err(LogError::TooLarge);
+        }
+        if session.len + len > PAYLOAD_LEN {
+            self.write_block(flash, &session)?;
+            if self.next_block >= self.blocks {
+                self.session = None;
+                return Err(LogError::Full);
+            }
+            session = OpenSession {
+                block: session.block + 1,
+                len: 0,
+                samples: 0,
+                ..session
+            };
+        }
+        let at = HEADER_LEN + session.len;
+        self.block[at..at + RECORD_PREFIX_LEN].copy_from_slice(&(frame.len() as u16).to_le_bytes());
+        self.block[at + RECORD_PREFIX_LEN..at + len].copy_from_slice(frame);
+        session.len += len;
+        session.samples = session.samples.saturating_add(samples);
+        session.end_ms = end_ms;
+        self.session = Some(session);
+        Ok(())
+    }
+
+    /// Write what is left of the session, if one is open.
+    pub fn finish<F: LogFlash>(&mut self, flash: &mut F) -> Result<(), LogError> {
+        match self.session.take() {
+            Some(session) => self.write_block(flash, &session),
+            None => Ok(()),
+        }
+    }
+
+    /// Erase the last block of the log and return whether any are left. Erasing from the end keeps
+    /// the log whole if a reset interrupts it.
+    pub fn erase_last<F: LogFlash>(&mut self, flash: &mut F) -> Result<bool, LogError> {
+        if self.session.is_some() {
+            return Err(LogError::Busy);
+        }
+        if self.next_block == 0 {
+            return Ok(false);
+        }
+        flash
+            .erase_sector((self.next_block - 1) * BLOCK_LEN as u32)
+            .map_err(|_| LogError::Flash)?;
+        self.next_block -= 1;
+        Ok(self.next_block > 0)
+    }
+
+    fn write_block<F: LogFlash>(
+        &mut self,
+        flash: &mut F,
+        session: &OpenSession,
+    ) -> Result<(), LogError> {
+        let payload_end = HEADER_LEN + session.len;
+        self.block[payload_end..].fill(0xff);
+        let mut header = BlockHeader {
+            session: session.id,
+            block: session.block,
+            payload_len: session.len as u16,
+            samples: session.samples,
+            end_ms: session.end_ms,
+            crc32: 0,
+        }
+        .encode();
+        let crc = block_crc(&header, &self.block[HEADER_LEN..payload_end]);
+        header[16..].copy_from_slice(&crc.to_le_bytes());
+        self.block[..HEADER_LEN].copy_from_slice(&header);
+        // A failed sector is skipped, its contents are unknown.
+        let offset = self.next_block * BLOCK_LEN as u32;
+        self.next_block += 1;
+        flash
+            .write_sector(offset, &self.block)
+            .map_err(|_| LogError::Flash)
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn block_crc(header: &[u8], payload: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&header[..16]);
+    crc.update(payload);
+    crc.finish()
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::XorShift;
+
+    const BLOCKS: usize = 6;
+
+    /// A partition in RAM.
+    struct MemFlash {
+        data: [u8; BLOCKS * BLOCK_LEN],
+    }
+
+    impl MemFlash {
+        fn new() -> Self {
+            Self {
+                data: [0xff; BLOCKS * BLOCK_LEN],
+            }
+        }
+
+        fn block(&self, index: usize) -> &[u8; BLOCK_LEN] {
+            self.data[index * BLOCK_LEN..][..BLOCK_LEN]
+                .try_into()
+                .unwrap()
+        }
+    }
+
+    impl LogFlash for MemFlash {
+        type Error = ();
+
+        fn capacity(&self) -> u32 {
+            self.data.len() as u32
+        }
+
+        fn write_sector(&mut self, offset: u32, data: &[u8; BLOCK_LEN]) -> Result<(), ()> {
+            assert_eq!(offset as usize % BLOCK_LEN, 0);
+            self.data[offset as usize..offset as usize + BLOCK_LEN].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn erase_sector(&mut self, offset: u32) -> Result<(), ()> {
+            self.data[offset as usize..offset as usize + BLOCK_LEN].fill(0xff);
+            Ok(())
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+    }
+
+    fn session_header(start_ms: u32) -> SessionHeader {
+        SessionHeader {
+            start_ms,
+            sample_interval_ms: 10,
+            config: ConfigPacket {
+                accel_scale: 1,
+                gyro_scale: 3,
+                buzz_frequency_mode: 0,
+                filter: 2,
+                motion_detection: true,
+            },
+        }
+    }
+
+    /// Record `frames` frames of random length with 10 samples each, the last at 100 ms per frame.
+    fn record(recorder: &mut Recorder, flash: &mut MemFlash, frames: usize, seed: u64) {
+        let mut rng = XorShift::new(seed);
+        let mut frame = [0; 512];
+        for i in 0..frames {
+            let len = rng.below(frame.len()) + 1;
+            rng.fill(&mut frame[..len]);
+            let end_ms = 100 * (i as u32 + 1);
+            recorder.append(flash, &frame[..len], 10, end_ms).unwrap();
+        }
+    }
+
+    #[test]
+    fn records_sessions_across_blocks() {
+        let mut flash = MemFlash::new();
+        let mut recorder = Recorder::new();
+        recorder.mount(&mut flash).unwrap();
+        assert_eq!((recorder.used(), recorder.capacity()), (0, BLOCKS as u32));
+        assert_eq!(recorder.start(&mut flash, session_header(1234)), Ok(0));
+        record(&mut recorder, &mut flash, 40, 0x5eed);
+        recorder.finish(&mut flash).unwrap();
+        assert_eq!(recorder.session(), None);
+        let used = recorder.used() as usize;
+        assert!(used > 2);
+
+        // Read it back the way a download would.
+        let mut rng = XorShift::new(0x5eed);
+        let mut expected = [0; 512];
+        let (mut frames, mut samples) = (0, 0);
+        for index in 0..used {
+            let (header, payload) = decode_block(flash.block(index)).unwrap();
+            assert_eq!((header.session, header.block), (0, index as u16));
+            let payload = if index == 0 {
+                assert_eq!(SessionHeader::decode(payload), Some(session_header(1234)));
+                &payload[SessionHeader::ENCODED_LEN..]
+            } else {
+                payload
+            };
+            for record in records(payload) {
+                let len = rng.below(expected.len()) + 1;
+                rng.fill(&mut expected[..len]);
+                assert_eq!(record, &expected[..len]);
+                frames += 1;
+            }
+            samples += header.samples as usize;
+            assert_eq!(header.end_ms, 100 * frames);
+        }
+        assert_eq!((frames, samples), (40, 400));
+        assert_eq!(decode_block(flash.block(used)), Err(LogError::NotABlock));
+    }
+
+    #[test]
+    fn continues_the_log_after_a_restart() {
+        let mut flash = MemFlash::new();
+        let mut recorder = Recorder::new();
+        recorder.mount(&mut flash).unwrap();
+        recorder.start(&mut flash, session_header(0)).unwrap();
+        record(&mut recorder, &mut flash, 3, 2);
+        // Starting a session ends the open one.
+        assert_eq!(recorder.start(&mut flash, session_header(50)), Ok(1));
+        record(&mut recorder, &mut flash, 3, 3);
+        recorder.finish(&mut flash).unwrap();
+        assert_eq!(recorder.used(), 2);
+
+        let mut recorder = Recorder::new();
+        recorder.mount(&mut flash).unwrap();
+        assert_eq!(recorder.used(), 2);
+        assert_eq!(recorder.start(&mut flash, session_header(90)), Ok(2));
+        recorder.finish(&mut flash).unwrap();
+        let (header, payload) = decode_block(flash.block(2)).unwrap();
+        assert_eq!((header.session, header.samples), (2, 0));
+        assert_eq!(SessionHeader::decode(payload), Some(session_header(90)));
+        assert_eq!(decode_block(flash.block(1)).unwrap().0.session, 1);
+    }
+
+    #[test]
+    fn stops_when_the_log_is_full() {
+        let mut flash = MemFlash::new();
+        let mut recorder = Recorder::new();
+        recorder.mount(&mut flash).unwrap();
+        recorder.start(&mut flash, session_header(0)).unwrap();
+        let frame = [0x5a; 500];
+        let result = loop {
+            if let Err(e) = recorder.append(&mut flash, &frame, 1, 0) {
+                break e;
+            }
+        };
+        assert_eq!(result, LogError::Full);
+        assert_eq!(recorder.session(), None);
+        assert_eq!(recorder.used(), BLOCKS as u32);
+        assert_eq!(
+            recorder.append(&mut flash, &frame, 1, 0),
+            Err(LogError::NoSession)
+        );
+        assert_eq!(
+            recorder.start(&mut flash, session_header(0)),
+            Err(LogError::Full)
+        );
+        for index in 0..BLOCKS {
+            assert!(decode_block(flash.block(index)).is_ok());
+        }
+
+        let mut recorder = Recorder::new();
+        recorder.mount(&mut flash).unwrap();
+        assert_eq!(recorder.used(), BLOCKS as u32);
+    }
+
+    #[test]
+    fn detects_damaged_blocks() {
+        let mut flash = MemFlash::new();
+        let mut recorder = Recorder::new();
+        recorder.mount(&mut flash).unwrap();
+        recorder.start(&mut flash, session_header(0)).unwrap();
+        record(&mut recorder, &mut flash, 2, 4);
+        assert_eq!(
+            recorder.append(&mut flash, &[0; PAYLOAD_LEN], 1, 0),
+            Err(LogError::TooLarge)
+        );
+        recorder.finish(&mut flash).unwrap();
+        flash.data[100] ^= 0x01;
+        assert_eq!(decode_block(flash.block(0)), Err(LogError::Crc));
+        flash.data[0] = b'X';
+        assert_eq!(decode_block(flash.block(0)), Err(LogError::NotABlock));
+        // Still counts as written.
+        let mut recorder = Recorder::new();
+        recorder.mount(&mut flash).unwrap();
+        assert_eq!(recorder.used(), 1);
+    }
+}
@@ -0,0 +1,84 @@
+//! Streaming throughput figures reported by the `stream_stats` characteristic.
+//!
+//! | offset | size | field                                                   |
+//! |--------|------|---------------------------------------------------------|
+//! | 0      | 2    | samples sent per second                                 |
+//! | 2      | 2    | notifications sent per second                           |
+//! | 4      | 4    | frame bytes sent per second                             |
+//! | 8      | 4    | samples dropped for lagging connections (total)         |
+//! | 12     | 4    | sends that waited for controller TX credits (total)     |
+//! | 16     | 4    | connection interval in microseconds                     |
+//! | 20     | 1    | notifications the pacer allows per connection interval |
+//!
+//! All fields are little endian.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ThroughputStats {
+    pub samples_per_s: u16,
+    pub notifications_per_s: u16,
+    pub bytes_per_s: u32,
+    pub dropped_samples: u32,
+    pub blocked_sends: u32,
+    pub conn_interval_us: u32,
+    pub budget: u8,
+}
+
+impl ThroughputStats {
+    pub const ENCODED_LEN: usize = 21;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0u8; Self::ENCODED_LEN];
+        out[0..2].copy_from_slice(&self.samples_per_s.to_le_bytes());
+        out[2..4].copy_from_slice(&self.notifications_per_s.to_le_bytes());
+        out[4..8].copy_from_slice(&self.bytes_per_s.to_le_bytes());
+        out[8..12].copy_from_slice(&self.dropped_samples.to_le_bytes());
+        out[12..16].copy_from_slice(&self.blocked_sends.to_le_bytes());
+        out[16..20].copy_from_slice(&self.conn_interval_us.to_le_bytes());
+        out[20] = self.budget;
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<ThroughputStats> {
+        let buf: &[u8; Self::ENCODED_LEN] = buf.try_into().ok()?;
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        Some(ThroughputStats {
+            samples_per_s: u16::from_le_bytes([buf[0], buf[1]]),
+            notifications_per_s: u16::from_le_bytes([buf[2], buf[3]]),
+            bytes_per_s: u32_at(4),
+            dropped_samples: u32_at(8),
+            blocked_sends: u32_at(12),
+            conn_interval_us: u32_at(16),
+            budget: buf[20],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::XorShift;
+
+    #[test]
+    fn round_trip() {
+        let mut rng = XorShift::new(7);
+        for _ in 0..1_000 {
+            let stats = ThroughputStats {
+                samples_per_s: rng.next_u32() as u16,
+                notifications_per_s: rng.next_u32() as u16,
+                bytes_per_s: rng.next_u32(),
+                dropped_samples: rng.next_u32(),
+                blocked_sends: rng.next_u32(),
+                conn_interval_us: rng.next_u32(),
+                budget: rng.next_u32() as u8,
+            };
+            assert_eq!(ThroughputStats::decode(&stats.encode()), Some(stats));
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let bytes = ThroughputStats::default().encode();
+        assert_eq!(ThroughputStats::decode(&bytes[1..]), None);
+    }
+}
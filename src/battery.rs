@@ -0,0 +1,146 @@
+//! Battery voltage, sampled with the ADC on GPIO2 through a resistor divider, and what the
+//! firmware does when it runs low.
+//!
+//! Readings are published on [`BATTERY`]. Below [`LOW_PERCENT`] the LED shows
+//! [`LedState::LowBattery`] when idle, below [`CRITICAL_PERCENT`] sampling is slowed down to
+//! [`CRITICAL_MIN_SAMPLE_INTERVAL_MS`] as well.
+use defmt::{info, warn, Format};
+use embassy_time::{Duration, Timer};
+use esp_hal::analog::adc::{Adc, AdcCalCurve, AdcConfig, Attenuation};
+use esp_hal::peripherals::{ADC1, GPIO2};
+
+use crate::led::LedState;
+use crate::shared::{BATTERY, BATTERY_DIVIDER, LED_STATE};
+
+/// How often the battery is measured.
+const SAMPLE_PERIOD: Duration = Duration::from_secs(10);
+/// ADC readings averaged per measurement.
+const READINGS: u32 = 16;
+/// Below this the battery is taken to be missing (e.g. running from USB, or the divider is not
+/// fitted), and no reading is published.
+const NO_BATTERY_MV: u16 = 2500;
+
+pub const LOW_PERCENT: u8 = 15;
+pub const CRITICAL_PERCENT: u8 = 5;
+/// Shortest sample interval while the battery is critically low (10 Hz).
+pub const CRITICAL_MIN_SAMPLE_INTERVAL_MS: u64 = 100;
+
+/// Resting voltage of a single LiPo cell (mV) against its charge (%), from full to empty.
+const DISCHARGE_CURVE: [(u16, u8); 11] = [
+    (4200, 100),
+    (4100, 90),
+    (3990, 80),
+    (3890, 70),
+    (3800, 60),
+    (3720, 50),
+    (3670, 40),
+    (3620, 30),
+    (3550, 20),
+    (3450, 10),
+    (3270, 0),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct BatteryStatus {
+    /// Battery voltage, after the divider.
+    pub millivolts: u16,
+    pub percent: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum BatteryState {
+    Normal,
+    Low,
+    Critical,
+}
+
+impl BatteryStatus {
+    pub fn state(self) -> BatteryState {
+        match self.percent {
+            p if p < CRITICAL_PERCENT => BatteryState::Critical,
+            p if p < LOW_PERCENT => BatteryState::Low,
+            _ => BatteryState::Normal,
+        }
+    }
+}
+
+/// Charge of a LiPo cell at `millivolts`, interpolated along the discharge curve.
+pub fn percent(millivolts: u16) -> u8 {
+    let mut upper = DISCHARGE_CURVE[0];
+    if millivolts >= upper.0 {
+        return upper.1;
+    }
+    for lower in DISCHARGE_CURVE.into_iter().skip(1) {
+        if millivolts >= lower.0 {
+            let span = (upper.0 - lower.0) as u32;
+            let above = (millivolts - lower.0) as u32;
+            return lower.1 + ((upper.1 - lower.1) as u32 * above / span) as u8;
+        }
+        upper = lower;
+    }
+    0
+}
+
+/// The battery state, `Normal` until the first reading or without a battery.
+pub fn state() -> BatteryState {
+    BATTERY
+        .try_get()
+        .map_or(BatteryState::Normal, BatteryStatus::state)
+}
+
+/// The LED state to show when nothing else is going on.
+pub fn idle_led_state() -> LedState {
+    match state() {
+        BatteryState::Normal => LedState::Ready,
+        BatteryState::Low | BatteryState::Critical => LedState::LowBattery,
+    }
+}
+
+/// `interval_ms`, slowed down while the battery is critically low. 0 (off) stays off.
+pub fn limit_sample_interval(interval_ms: u64) -> u64 {
+    match (state(), interval_ms) {
+        (BatteryState::Critical, 1..) => interval_ms.max(CRITICAL_MIN_SAMPLE_INTERVAL_MS),
+        _ => interval_ms,
+    }
+}
+
+#[embassy_executor::task]
+pub async fn battery_task(adc: ADC1<'static>, pin: GPIO2<'static>) {
+    let mut config = AdcConfig::new();
+    let mut pin = config.enable_pin_with_cal::<_, AdcCalCurve<ADC1>>(pin, Attenuation::_11dB);
+    let mut adc = Adc::new(adc, config).into_async();
+    let sender = BATTERY.sender();
+    let mut previous = BatteryState::Normal;
+    loop {
+        let mut sum = 0;
+        for _ in 0..READINGS {
+            sum += adc.read_oneshot(&mut pin).await as u32;
+        }
+        let divider = *BATTERY_DIVIDER.lock().await;
+        let millivolts = ((sum / READINGS) as f32 * divider) as u16;
+        if millivolts < NO_BATTERY_MV {
+            Timer::after(SAMPLE_PERIOD).await;
+            continue;
+        }
+        let status = BatteryStatus {
+            millivolts,
+            percent: percent(millivolts),
+        };
+        // Only a new percentage is worth notifying, the voltage is kept up to date regardless.
+        sender.send_if_modified(|current| {
+            let changed = current.is_none_or(|current| current.percent != status.percent);
+            *current = Some(status);
+            changed
+        });
+        let state = status.state();
+        if state != previous {
+            match state {
+                BatteryState::Normal => info!("[battery] {} mV, back to normal", millivolts),
+                _ => warn!("[battery] {} mV, {:?}", millivolts, state),
+            }
+            LED_STATE.signal(idle_led_state());
+            previous = state;
+        }
+        Timer::after(SAMPLE_PERIOD).await;
+    }
+}
@@ -15,7 +15,11 @@ use esp_hal::ledc::Ledc;
 use esp_hal::timer::systimer::SystemTimer;
 use esp_hal::timer::timg::TimerGroup;
 use esp_wifi::ble::controller::BleConnector;
+use mputest::battery::battery_task;
+use mputest::button::pairing_button_task;
 use mputest::led::{led_blink_task, LedState};
+use mputest::ota::confirm_task;
+use mputest::recording::recorder_task;
 use mputest::sensor::init::{configure_sensor, initialize_sensor};
 use mputest::sensor::motion::motion_reading;
 use mputest::shared::LED_STATE;
@@ -35,11 +39,18 @@ async fn main(spawner: Spawner) {
     rtt_target::rtt_init_defmt!();
 
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
-    let peripherals = esp_hal::init(config);
+    let mut peripherals = esp_hal::init(config);
 
     let led = Output::new(peripherals.GPIO15, Level::High, OutputConfig::default());
     spawner.spawn(led_blink_task(led)).ok();
 
+    // The BOOT button of the devkit.
+    let button = Input::new(
+        peripherals.GPIO9,
+        InputConfig::default().with_pull(Pull::Up),
+    );
+    spawner.spawn(pairing_button_task(button)).ok();
+
     let ledc = Ledc::new(peripherals.LEDC);
     let buzzer_gpio = peripherals.GPIO21;
 
@@ -50,7 +61,11 @@ async fn main(spawner: Spawner) {
 
     info!("Embassy initialized!");
 
-    let rng = esp_hal::rng::Rng::new(peripherals.RNG);
+    // The TRNG needs the ADC only until it is downgraded.
+    let mut trng = esp_hal::rng::Trng::new(peripherals.RNG, peripherals.ADC1.reborrow());
+    let mut seed = [0; 32];
+    trng.read(&mut seed);
+    let rng = trng.downgrade();
     let timer1 = TimerGroup::new(peripherals.TIMG0);
     let wifi_init =
         esp_wifi::init(timer1.timer0, rng).expect("Failed to initialize WIFI/BLE controller");
@@ -99,6 +114,10 @@ async fn main(spawner: Spawner) {
     };
     LED_STATE.signal(LedState::Ready);
 
+    spawner
+        .spawn(battery_task(peripherals.ADC1, peripherals.GPIO2))
+        .ok();
+
     spawner
         .spawn(buzzer::buzzer_task(ledc, buzzer_gpio.into()))
         .ok();
@@ -106,5 +125,10 @@ async fn main(spawner: Spawner) {
     spawner
         .spawn(motion_reading(sensor, sensor_config, motion_int))
         .ok();
-    ble::run(ble_controller).await;
+
+    spawner.spawn(recorder_task()).ok();
+
+    // A new image that gets this far and keeps running is kept.
+    spawner.spawn(confirm_task()).ok();
+    ble::run(ble_controller, seed).await;
 }
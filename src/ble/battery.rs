@@ -0,0 +1,22 @@
+//! Battery Level notifications.
+use defmt::warn;
+use trouble_host::prelude::*;
+
+use super::gatt::Server;
+use crate::shared::BATTERY;
+
+/// Notify `conn` of every new battery level, until the connection closes. The first reading is
+/// stored right away, so reads return it.
+pub async fn level_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+    let Some(mut battery) = BATTERY.receiver() else {
+        warn!("[battery] no receiver left for the battery level");
+        return;
+    };
+    let level = &server.battery_service.level;
+    loop {
+        let status = battery.changed().await;
+        if level.notify(conn, &status.percent).await.is_err() {
+            warn!("[battery] error notifying battery level");
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Broadcast mode: a motion summary in the advertising data, for passive monitoring of many
+//! devices without connecting. The encoding is in [`mpu_protocol::broadcast`].
+//!
+//! The sensor task records every sample it takes, and each time the advertising data is refreshed
+//! the samples since the last refresh are summarised. The device stays connectable meanwhile.
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::Vec;
+use micromath::F32Ext;
+use mpu6050_dmp::accel::AccelF32;
+use mpu6050_dmp::gyro::GyroF32;
+use mpu_protocol::broadcast::Summary;
+
+use crate::shared::{BATTERY, STREAMING};
+
+/// Shortest interval between summaries, each one restarts advertising.
+pub const BROADCAST_INTERVAL_MIN_MS: u16 = 100;
+
+/// Standard gravity, in m/s².
+const G: f32 = 9.806_65;
+
+pub type Payload = Vec<u8, { Summary::MAX_ENCODED_LEN }>;
+
+/// Sums over the samples since the last summary.
+struct Accumulator {
+    samples: u32,
+    /// Squared deviation of the acceleration magnitude from 1 g, in g².
+    accel_sq: f32,
+    /// Squared rate magnitude, in (°/s)².
+    gyro_sq: f32,
+    /// Tilt of the latest sample, kept until the next one.
+    tilt_decidegrees: i16,
+}
+
+static ACCUMULATOR: Mutex<CriticalSectionRawMutex, RefCell<Accumulator>> =
+    Mutex::new(RefCell::new(Accumulator {
+        samples: 0,
+        accel_sq: 0.0,
+        gyro_sq: 0.0,
+        tilt_decidegrees: 0,
+    }));
+
+/// Add a sample, scaled to g and °/s, to the next summary.
+pub fn record(accel: &AccelF32, gyro: &GyroF32) {
+    let (ax, ay, az) = (accel.x(), accel.y(), accel.z());
+    let (gx, gy, gz) = (gyro.x(), gyro.y(), gyro.z());
+    let magnitude = (ax * ax + ay * ay + az * az).sqrt();
+    ACCUMULATOR.lock(|accumulator| {
+        let mut a = accumulator.borrow_mut();
+        a.samples += 1;
+        a.accel_sq += (magnitude - 1.0) * (magnitude - 1.0);
+        a.gyro_sq += gx * gx + gy * gy + gz * gz;
+        if magnitude > 0.0 {
+            a.tilt_decidegrees =
+                ((az / magnitude).clamp(-1.0, 1.0).acos().to_degrees() * 10.0) as i16;
+        }
+    });
+}
+
+/// Summarise the samples recorded since the last call, as BTHome service data.
+pub fn payload(packet_id: u8) -> Payload {
+    let summary = ACCUMULATOR.lock(|accumulator| {
+        let mut a = accumulator.borrow_mut();
+        let rms = |sum: f32| match a.samples {
+            0 => 0.0,
+            n => (sum / n as f32).sqrt(),
+        };
+        let summary = Summary {
+            packet_id,
+            battery_percent: BATTERY.try_get().map(|battery| battery.percent),
+            moving: STREAMING.try_get().unwrap_or(false),
+            tilt_decidegrees: a.tilt_decidegrees,
+            accel_rms_mm_s2: (rms(a.accel_sq) * G * 1000.0).min(u16::MAX as f32) as u16,
+            gyro_rms_mdeg_s: (rms(a.gyro_sq) * 1000.0).min(u16::MAX as f32) as u16,
+        };
+        a.samples = 0;
+        a.accel_sq = 0.0;
+        a.gyro_sq = 0.0;
+        summary
+    });
+    let mut out = [0; Summary::MAX_ENCODED_LEN];
+    let len = summary.encode(&mut out);
+    Vec::from_slice(&out[..len]).unwrap_or_default()
+}
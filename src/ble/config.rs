@@ -0,0 +1,68 @@
+//! Config packet writes and their acks.
+//!
+//! A packet is validated as a whole when it is written. A valid one is handed to the sensor task,
+//! which applies it between samples; the ack follows once it did. An invalid one is acked at once
+//! with the reason and the unchanged config.
+use defmt::{info, warn};
+use mpu_protocol::config::{AckStatus, ConfigAck, ConfigPacket};
+use trouble_host::prelude::*;
+
+use super::gatt::Server;
+use crate::sensor::config::SensorConfig;
+use crate::shared::{CONFIG_APPLIED, CONFIG_PACKET};
+
+/// Handle a write to the `config_packet` characteristic.
+pub async fn handle_packet<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    data: &[u8],
+) {
+    match ConfigPacket::decode(data).and_then(SensorConfig::try_from) {
+        Ok(config) => {
+            info!("[config] packet accepted");
+            CONFIG_PACKET.signal(config);
+        }
+        Err(e) => {
+            warn!("[config] packet rejected: {:?}", e);
+            let config = server
+                .imu_service
+                .config_ack
+                .get(server)
+                .ok()
+                .and_then(|ack| ConfigAck::decode(&ack))
+                .map(|ack| ack.config)
+                .unwrap_or_default();
+            send_ack(server, conn, AckStatus::from(e), config).await;
+        }
+    }
+}
+
+/// Ack config packets once the sensor task applied them, until the connection closes.
+pub async fn config_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+    let Ok(mut applied) = CONFIG_APPLIED.subscriber() else {
+        warn!("[config] no subscriber left for config acks");
+        return;
+    };
+    loop {
+        let config = ConfigPacket::from(applied.next_message_pure().await);
+        send_ack(server, conn, AckStatus::Applied, config).await;
+    }
+}
+
+async fn send_ack<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    status: AckStatus,
+    config: ConfigPacket,
+) {
+    let ack = ConfigAck { status, config }.encode();
+    if server
+        .imu_service
+        .config_ack
+        .notify(conn, &ack)
+        .await
+        .is_err()
+    {
+        warn!("[config] error notifying ack");
+    }
+}
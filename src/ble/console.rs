@@ -0,0 +1,209 @@
+//! Text command console over the Nordic UART service.
+//!
+//! Commands written to `rx` are parsed with [`mpu_protocol::console`] and replied to on `tx`, one
+//! notification per chunk of each CR LF terminated reply line. Settings go through the same
+//! [`settings`](super::settings) dispatcher as writes to their typed characteristics.
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn};
+use embassy_time::Instant;
+use heapless::{String, Vec};
+use mpu_protocol::console::{lines, Command, Setting};
+use trouble_host::prelude::*;
+
+use super::gatt::Server;
+use super::link::LinkState;
+use super::security::PAIRING_WINDOW_DURATION;
+use super::settings;
+use crate::error_log;
+use crate::recording::RECORDER;
+use crate::shared::{BATTERY, CALIBRATE, DROPPED_SAMPLES, PAIRING_WINDOW, RECORDING, STREAM_MODE};
+
+/// Largest write accepted on `rx` and largest notification sent on `tx`.
+pub const CONSOLE_CHUNK_LEN: usize = 128;
+
+/// Longest reply line, without the line ending.
+const LINE_LEN: usize = 96;
+
+type Line = String<LINE_LEN>;
+
+/// Run the commands in `input`, a write to the `rx` characteristic.
+pub async fn handle_input<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    link: &LinkState,
+    input: &[u8],
+) {
+    let reply = Reply { server, conn };
+    let Ok(input) = core::str::from_utf8(input) else {
+        reply.send("error: input is not UTF-8").await;
+        return;
+    };
+    for line in lines(input) {
+        info!("[console] {}", line);
+        match Command::parse(line) {
+            Ok(command) => run(command, server, link, &reply).await,
+            Err(e) => {
+                let mut text = Line::new();
+                write!(text, "error: {}", e.message()).ok();
+                reply.send(&text).await;
+            }
+        }
+    }
+}
+
+async fn run<P: PacketPool>(
+    command: Command,
+    server: &Server<'_>,
+    link: &LinkState,
+    reply: &Reply<'_, '_, '_, '_, P>,
+) {
+    let mut text = Line::new();
+    match command {
+        Command::Help => {
+            reply
+                .send("commands: help, status, calibrate, dump errors, pair, get <setting>, set <setting> <value>")
+                .await;
+            reply.send("settings:").await;
+            for setting in Setting::ALL {
+                write!(text, "  {}", setting.name()).ok();
+                reply.send(&text).await;
+                text.clear();
+            }
+            return;
+        }
+        Command::Status => {
+            let params = link.get();
+            let mode = *STREAM_MODE.lock().await;
+            write!(
+                text,
+                "uptime {} s, stream_mode {}, dropped {}, errors {}",
+                Instant::now().as_secs(),
+                mode.to_u8(),
+                DROPPED_SAMPLES.load(Ordering::Relaxed),
+                error_log::total()
+            )
+            .ok();
+            reply.send(&text).await;
+            text.clear();
+            write!(
+                text,
+                "att mtu {}, interval {} us, phy {}/{}",
+                reply.conn.raw().att_mtu(),
+                params.conn_interval_us,
+                params.tx_phy,
+                params.rx_phy
+            )
+            .ok();
+            reply.send(&text).await;
+            text.clear();
+            let (used, capacity) = {
+                let recorder = RECORDER.lock().await;
+                (recorder.used(), recorder.capacity())
+            };
+            let recording = if *RECORDING.lock().await { "on" } else { "off" };
+            write!(
+                text,
+                "log {} of {} blocks used, recording {}",
+                used, capacity, recording
+            )
+            .ok();
+            if let Some(battery) = BATTERY.try_get() {
+                reply.send(&text).await;
+                text.clear();
+                write!(
+                    text,
+                    "battery {} mV, {} %",
+                    battery.millivolts, battery.percent
+                )
+                .ok();
+            }
+        }
+        Command::Calibrate => {
+            CALIBRATE.signal(());
+            // The sensor task calibrates between read windows, see `motion_reading`.
+            text.push_str("calibrating when no read window is open, keep the sensor still")
+                .ok();
+        }
+        Command::Pair => {
+            PAIRING_WINDOW.signal(());
+            write!(
+                text,
+                "pairing window open for {} s",
+                PAIRING_WINDOW_DURATION.as_secs()
+            )
+            .ok();
+        }
+        Command::DumpErrors => {
+            let entries = error_log::entries();
+            write!(
+                text,
+                "{} errors since boot, {} kept",
+                error_log::total(),
+                entries.len()
+            )
+            .ok();
+            reply.send(&text).await;
+            for entry in entries.iter() {
+                text.clear();
+                write!(
+                    text,
+                    "  {}.{:03} s: {}",
+                    entry.uptime_ms / 1000,
+                    entry.uptime_ms % 1000,
+                    entry.message
+                )
+                .ok();
+                reply.send(&text).await;
+            }
+            return;
+        }
+        Command::Get(setting) => {
+            match settings::get(server, setting) {
+                Some(value) => write!(text, "{} {}", setting.name(), value),
+                None => write!(text, "error: setting unavailable"),
+            }
+            .ok();
+        }
+        Command::Set(setting, value) => {
+            match settings::apply(server, setting, value).await {
+                Ok(()) => write!(text, "{} {}", setting.name(), value),
+                Err(e) => {
+                    warn!("[console] error setting {}: {:?}", setting.name(), e);
+                    write!(text, "error: {}", e.message())
+                }
+            }
+            .ok();
+        }
+    }
+    reply.send(&text).await;
+}
+
+/// Sends reply lines to the client.
+struct Reply<'a, 'values, 'stack, 'server, P: PacketPool> {
+    server: &'a Server<'values>,
+    conn: &'a GattConnection<'stack, 'server, P>,
+}
+
+impl<P: PacketPool> Reply<'_, '_, '_, '_, P> {
+    /// Notify `line` and a CR LF, split to fit the ATT MTU.
+    async fn send(&self, line: &str) {
+        let tx = &self.server.console_service.tx;
+        // A notification carries a 1 byte opcode and a 2 byte handle.
+        let chunk_len = (self.conn.raw().att_mtu() as usize)
+            .saturating_sub(3)
+            .clamp(1, CONSOLE_CHUNK_LEN);
+        let mut text: Vec<u8, { LINE_LEN + 2 }> = Vec::new();
+        text.extend_from_slice(line.as_bytes()).ok();
+        text.extend_from_slice(b"\r\n").ok();
+        for chunk in text.chunks(chunk_len) {
+            // Chunks are never longer than the characteristic.
+            let chunk = Vec::from_slice(chunk).unwrap_or_default();
+            if tx.notify(self.conn, &chunk).await.is_err() {
+                warn!("[console] error notifying reply");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Device Information values that are only known at runtime.
+use core::fmt::Write;
+
+use defmt::warn;
+use esp_hal::efuse::Efuse;
+use heapless::String;
+
+use super::gatt::Server;
+
+/// Fill in the serial number and hardware revision of the Device Information Service.
+pub fn init(server: &Server<'_>) {
+    let info = &server.device_info;
+    let mut serial: String<32> = String::new();
+    for byte in Efuse::mac_address() {
+        write!(serial, "{:02X}", byte).ok();
+    }
+    let mut revision: String<32> = String::new();
+    write!(
+        revision,
+        "ESP32-C6 v{}.{}",
+        Efuse::major_chip_version(),
+        Efuse::minor_chip_version()
+    )
+    .ok();
+    for (characteristic, value) in [
+        (&info.serial_number, serial),
+        (&info.hardware_revision, revision),
+    ] {
+        if characteristic.set(server, &value.into_bytes()).is_err() {
+            warn!("[device_info] error setting a characteristic");
+        }
+    }
+}
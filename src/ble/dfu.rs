@@ -0,0 +1,144 @@
+//! The DFU service: firmware images written over BLE, see [`mpu_protocol::dfu`] for the protocol.
+//!
+//! The update state is shared by all connections and survives a disconnect, so a client can
+//! reconnect and resume. Once an image is verified and activated the device restarts into it.
+//!
+//! Images must be signed with the key whose public half was in `DFU_PUBLIC_KEY` at build time.
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Timer;
+use mpu_protocol::dfu::{Chunk, DfuError, Request, State, Status, Updater, SECTOR_SIZE};
+use mpu_protocol::ed25519::PUBLIC_KEY_LEN;
+use trouble_host::prelude::*;
+
+use super::gatt::Server;
+use crate::error_log;
+use crate::ota::ImagePartition;
+
+/// Largest write accepted on `dfu_data`: a chunk header and up to 508 image bytes.
+pub const DFU_CHUNK_LEN: usize = 512;
+
+/// The key images are signed with, `None` if the firmware was built without one.
+const PUBLIC_KEY: Option<[u8; PUBLIC_KEY_LEN]> =
+    include!(concat!(env!("OUT_DIR"), "/dfu_public_key.rs"));
+
+/// Time for the last status notification to go out before restarting into a new image.
+const RESTART_DELAY_MS: u64 = 500;
+
+struct Dfu {
+    updater: Updater,
+    /// Where the image goes, looked up on every start request.
+    partition: Option<ImagePartition>,
+}
+
+static DFU: Mutex<CriticalSectionRawMutex, Dfu> = Mutex::new(Dfu {
+    updater: Updater::new(PUBLIC_KEY),
+    partition: None,
+});
+
+/// Handle a write to `dfu_control`.
+pub async fn handle_control<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    data: &[u8],
+) {
+    let status = {
+        let mut dfu = DFU.lock().await;
+        let dfu = &mut *dfu;
+        match Request::decode(data) {
+            Ok(request) => {
+                info!("[dfu] {:?}", request);
+                if let Request::Start(_) = request {
+                    dfu.partition = ImagePartition::inactive().ok();
+                }
+                let result = match dfu.partition.as_mut() {
+                    Some(partition) => dfu.updater.control(partition, request),
+                    None => {
+                        dfu.updater.reject(DfuError::Flash);
+                        Err(DfuError::Flash)
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("[dfu] {:?} rejected: {:?}", request, e);
+                    match e {
+                        DfuError::Flash => error_log::record("writing the firmware image failed"),
+                        DfuError::Signature if PUBLIC_KEY.is_none() => {
+                            warn!("[dfu] built without DFU_PUBLIC_KEY, no image is accepted")
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("[dfu] malformed request: {:?}", data);
+                dfu.updater.reject(e);
+            }
+        }
+        dfu.updater.status()
+    };
+    publish(server, conn, status).await;
+    if status.state == State::Complete {
+        info!("[dfu] restarting into the new image");
+        Timer::after_millis(RESTART_DELAY_MS).await;
+        esp_hal::system::software_reset();
+    }
+}
+
+/// Handle a write to `dfu_data`. The status is notified on errors and for every sector written,
+/// not for every chunk.
+pub async fn handle_data<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    data: &[u8],
+) {
+    let (status, notify) = {
+        let mut dfu = DFU.lock().await;
+        let dfu = &mut *dfu;
+        let before = dfu.updater.status().received;
+        let result = match (Chunk::decode(data), dfu.partition.as_mut()) {
+            (Ok(chunk), Some(partition)) => dfu.updater.write(partition, chunk),
+            (Ok(_), None) => {
+                dfu.updater.reject(DfuError::State);
+                Err(DfuError::State)
+            }
+            (Err(e), _) => {
+                dfu.updater.reject(e);
+                Err(e)
+            }
+        };
+        let status = dfu.updater.status();
+        if let Err(e) = result {
+            warn!(
+                "[dfu] chunk rejected: {:?}, {} bytes received",
+                e, status.received
+            );
+            if e == DfuError::Flash {
+                error_log::record("writing the firmware image failed");
+            }
+        }
+        let sector_done = status.received / SECTOR_SIZE as u32 != before / SECTOR_SIZE as u32;
+        (status, result.is_err() || sector_done)
+    };
+    if notify {
+        publish(server, conn, status).await;
+    } else {
+        server.dfu_service.status.set(server, &status.encode()).ok();
+    }
+}
+
+async fn publish<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    status: Status,
+) {
+    if server
+        .dfu_service
+        .status
+        .notify(conn, &status.encode())
+        .await
+        .is_err()
+    {
+        warn!("[dfu] error notifying status");
+    }
+}
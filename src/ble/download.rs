@@ -0,0 +1,158 @@
+//! The log service: sessions recorded to flash listed, downloaded, deleted and formatted over BLE,
+//! see [`mpu_protocol::download`] for the protocol.
+//!
+//! Requests are answered with notifications on `log_data`, which also keeps the last response so
+//! a client whose ATT MTU is too small for a listing can read it whole.
+use defmt::{info, warn};
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use heapless::Vec;
+use mpu_protocol::download::{Downloader, Request, Response, TransferError};
+use trouble_host::prelude::*;
+
+use super::gatt::Server;
+use crate::error_log;
+use crate::recording::{LogPartition, RECORDER};
+
+/// Size of the `log_data` characteristic, the largest attribute value ATT allows.
+pub const LOG_DATA_LEN: usize = 512;
+
+/// Shared by all connections, a request is answered whole before the next one starts.
+static DOWNLOADER: Mutex<CriticalSectionRawMutex, Downloader> = Mutex::new(Downloader::new());
+
+/// Handle a write to `log_control`.
+pub async fn handle_control<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    data: &[u8],
+) {
+    let opcode = data.first().copied().unwrap_or(0);
+    let request = match Request::decode(data) {
+        Ok(request) => request,
+        Err(error) => {
+            warn!("[download] malformed request: {:?}", data);
+            send(
+                server,
+                conn,
+                Response::Error {
+                    request: opcode,
+                    error,
+                },
+            )
+            .await;
+            return;
+        }
+    };
+    info!("[download] {:?}", request);
+    let mut downloader = DOWNLOADER.lock().await;
+    let result = match LogPartition::find() {
+        Ok(mut partition) => run(server, conn, &mut downloader, &mut partition, request).await,
+        Err(_) => Err(TransferError::Flash),
+    };
+    let response = match result {
+        Ok(()) => Response::Done(opcode),
+        Err(error) => {
+            warn!("[download] {:?} failed: {:?}", request, error);
+            if error == TransferError::Flash {
+                error_log::record("accessing the recording log failed");
+            }
+            Response::Error {
+                request: opcode,
+                error,
+            }
+        }
+    };
+    send(server, conn, response).await;
+}
+
+async fn run<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    downloader: &mut Downloader,
+    partition: &mut LogPartition,
+    request: Request,
+) -> Result<(), TransferError> {
+    match request {
+        Request::List => {
+            downloader.rewind();
+            loop {
+                // The recorder is only kept waiting while the flash is read, not while notifying.
+                let info = downloader.next_session(&*RECORDER.lock().await, partition)?;
+                let Some(info) = info else {
+                    break;
+                };
+                if !send(server, conn, Response::Session(info)).await {
+                    break;
+                }
+            }
+        }
+        Request::Read {
+            session,
+            mut offset,
+            len,
+        } => {
+            let end = offset.saturating_add(len);
+            let mut data = [0; LOG_DATA_LEN - Response::DATA_HEADER_LEN];
+            // A notification carries a 1 byte opcode and a 2 byte handle.
+            let chunk_len = (conn.raw().att_mtu() as usize)
+                .saturating_sub(3 + Response::DATA_HEADER_LEN)
+                .clamp(1, data.len());
+            while offset < end {
+                let want = chunk_len.min((end - offset) as usize);
+                let n = downloader.read(
+                    &*RECORDER.lock().await,
+                    partition,
+                    session,
+                    offset,
+                    &mut data[..want],
+                )?;
+                if n == 0 {
+                    break;
+                }
+                let response = Response::Data {
+                    session,
+                    offset,
+                    data: &data[..n],
+                };
+                // The client resumes from the last offset it received.
+                if !send(server, conn, response).await {
+                    break;
+                }
+                offset += n as u32;
+            }
+        }
+        Request::Delete(session) => {
+            downloader.delete(&*RECORDER.lock().await, partition, session)?;
+            info!("[download] session {} deleted", session);
+        }
+        Request::Format => {
+            // A block at a time, erasing stalls the device.
+            while downloader.erase_last(&mut *RECORDER.lock().await, partition)? {
+                yield_now().await;
+            }
+            info!("[download] log formatted");
+        }
+    }
+    Ok(())
+}
+
+/// Keep `response` in `log_data` and notify it, returning false if that failed.
+async fn send<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    response: Response<'_>,
+) -> bool {
+    let mut value: Vec<u8, LOG_DATA_LEN> = Vec::new();
+    value.resize_default(LOG_DATA_LEN).ok();
+    // Data chunks are sized to fit, the other responses are short.
+    let len = response.encode(&mut value).unwrap_or(0);
+    value.truncate(len);
+    let data = &server.log_service.data;
+    data.set(server, &value).ok();
+    if data.notify(conn, &value).await.is_err() {
+        warn!("[download] error notifying response");
+        return false;
+    }
+    true
+}
@@ -1,151 +1,176 @@
 use defmt::{info, warn};
-use mpu6050_dmp::accel::AccelFullScale;
-use mpu6050_dmp::config::DigitalLowPassFilter;
-use mpu6050_dmp::gyro::GyroFullScale;
+use heapless::Vec;
+use mpu_protocol::console::{Setting, Value};
+use mpu_protocol::dfu::Request;
 use trouble_host::prelude::*;
 
+use super::config;
+use super::console::{self, CONSOLE_CHUNK_LEN};
+use super::dfu;
+use super::download;
 use super::gatt::Server;
-use crate::sensor::config::{
-    AccelFullScaleFromU8, DigitalLowPassFilterFromU8, GyroFullScaleFromU8,
-};
-use crate::shared::{
-    ACCEL_SCALE, BUZZ_FREQUENCY_MODE, CONTINUOUS_SAMPLE_INTERVAL_MS, FILTER, GYRO_SCALE,
-    MARK_EPOCH, MAX_BUZZ_VALUE, MIN_BUZZ_VALUE, MOTION_DETECTION, MOTION_READ_DURATION_S,
-    MOTION_SAMPLE_INTERVAL_MS, PLAY_SOUND, READ,
-};
-use crate::{define_async_write_handler, define_write_handler};
+use super::link::LinkState;
+use super::name::DeviceName;
+use super::notify_task::Subscriptions;
+use super::security;
+use super::settings;
+use crate::define_write_handler;
+use crate::shared::{MARK_EPOCH, READ};
 /// Stream Events until the connection closes.
 ///
 /// Handles GATT events (especially Writes) and updates shared runtime config/signals.
 pub async fn gatt_events_task<P: PacketPool>(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, P>,
+    link: &LinkState,
+    subscriptions: &Subscriptions,
+    device_name: &DeviceName,
 ) -> Result<(), Error> {
-    let motion_read_duration = &server.imu_service.motion_read_duration;
-    let motion_sample_interval = &server.imu_service.motion_sample_interval;
-    let continuous_sample_interval = &server.imu_service.continuous_sample_interval;
-    let play_sound = &server.imu_service.play_sound;
-    let accel_scale = &server.imu_service.accel_scale;
-    let gyro_scale = &server.imu_service.gyro_scale;
-    let buzz_frequency_mode = &server.imu_service.buzz_frequency_mode;
-    let min_buzz_value = &server.imu_service.min_buzz_value;
-    let max_buzz_value = &server.imu_service.max_buzz_value;
-    let digital_low_pass_filter = &server.imu_service.digital_low_pass_filter;
     let read = &server.imu_service.read;
     let mark_epoch = &server.imu_service.mark_epoch;
-    let motion_detection = &server.imu_service.motion_detection;
+    let config_packet = &server.imu_service.config_packet;
+    let console_rx = &server.console_service.rx;
+    let dfu_control = &server.dfu_service.control;
+    let dfu_data = &server.dfu_service.data;
+    let log_control = &server.log_service.control;
 
     let reason = loop {
         match conn.next().await {
             GattConnectionEvent::Disconnected { reason } => break reason,
+            GattConnectionEvent::ConnectionParamsUpdated {
+                conn_interval,
+                peripheral_latency,
+                supervision_timeout,
+            } => link.update(|p| {
+                p.conn_interval_us = conn_interval.as_micros() as u32;
+                p.peripheral_latency = peripheral_latency;
+                p.supervision_timeout_ms = supervision_timeout.as_millis() as u16;
+            }),
+            GattConnectionEvent::PhyUpdated { tx_phy, rx_phy } => link.update(|p| {
+                p.tx_phy = tx_phy as u8;
+                p.rx_phy = rx_phy as u8;
+            }),
+            GattConnectionEvent::Bonded { bond_info } => {
+                info!("[gatt] bonded: {:?}", bond_info);
+                security::store_bond(&bond_info);
+            }
             GattConnectionEvent::Gatt { event } => {
-                match &event {
+                let mut console_input: Option<Vec<u8, CONSOLE_CHUNK_LEN>> = None;
+                let mut dfu_request: Option<Vec<u8, { Request::MAX_ENCODED_LEN }>> = None;
+                let mut log_request: Option<
+                    Vec<u8, { mpu_protocol::download::Request::MAX_ENCODED_LEN }>,
+                > = None;
+                let result = match &event {
                     GattEvent::Read(_event) => {
                         // Add any ad-hoc read handling here if needed
+                        Ok(())
+                    }
+                    GattEvent::Write(event)
+                        if !security::write_allowed(server, device_name, conn, event.handle()) =>
+                    {
+                        // Centrals pair on this error and retry.
+                        warn!("[gatt] write to {} needs an encrypted link", event.handle());
+                        Err(AttErrorCode::INSUFFICIENT_ENCRYPTION)
                     }
                     GattEvent::Write(event) => match event.handle() {
-                        h if h == motion_read_duration.handle => {
-                            handle_u16_write(event.data(), |value| async move {
-                                info!("motion_read_duration: {}", value);
-                                *MOTION_READ_DURATION_S.lock().await = value;
-                            })
-                            .await;
-                        }
-                        h if h == motion_sample_interval.handle => {
-                            handle_u64_write(event.data(), |value| async move {
-                                *MOTION_SAMPLE_INTERVAL_MS.lock().await = value as u64;
-                            })
-                            .await;
-                        }
-                        h if h == continuous_sample_interval.handle => {
-                            handle_u64_write(event.data(), |value| async move {
-                                *CONTINUOUS_SAMPLE_INTERVAL_MS.lock().await = value as u64;
-                            })
-                            .await;
-                        }
-                        h if h == play_sound.handle => {
-                            handle_u8_write(event.data(), |value| PLAY_SOUND.signal(value != 0));
-                        }
-                        h if h == gyro_scale.handle => {
-                            handle_u8_write(event.data(), |value| {
-                                match GyroFullScale::from_u8(value) {
-                                    Some(scale) => GYRO_SCALE.signal(scale),
-                                    None => warn!("Invalid Gyro Scale value: {}", value),
-                                }
-                            });
-                        }
-                        h if h == accel_scale.handle => {
-                            handle_u8_write(event.data(), |value| {
-                                match AccelFullScale::from_u8(value) {
-                                    Some(scale) => ACCEL_SCALE.signal(scale),
-                                    None => warn!("Invalid Accel Scale value: {}", value),
-                                }
-                            });
-                        }
-                        h if h == buzz_frequency_mode.handle => {
-                            handle_u8_write(event.data(), |value| {
-                                BUZZ_FREQUENCY_MODE.signal(value.into())
-                            });
+                        h if h == read.handle => {
+                            handle_u8_write(event.data(), |value| READ.signal(value != 0))
                         }
-                        h if h == min_buzz_value.handle => {
-                            handle_f32_write(event.data(), |value| MIN_BUZZ_VALUE.signal(value));
+                        h if h == mark_epoch.handle => handle_u8_write(event.data(), |value| {
+                            if value != 0 {
+                                MARK_EPOCH.signal(())
+                            }
+                        }),
+                        h if h == config_packet.handle => {
+                            // Invalid packets are reported through `config_ack`.
+                            config::handle_packet(server, conn, event.data()).await;
+                            Ok(())
                         }
-                        h if h == max_buzz_value.handle => {
-                            handle_f32_write(event.data(), |value| MAX_BUZZ_VALUE.signal(value));
+                        h if h == console_rx.handle => {
+                            // Run once the write is acknowledged, the replies are notifications.
+                            console_input = Vec::from_slice(event.data()).ok();
+                            Ok(())
                         }
-                        h if h == digital_low_pass_filter.handle => {
-                            handle_u8_write(event.data(), |value| {
-                                match DigitalLowPassFilter::from_u8(value) {
-                                    Some(filter) => FILTER.signal(filter),
-                                    None => {
-                                        warn!("Invalid Digital Low Pass Filter value: {}", value)
-                                    }
-                                }
-                            });
+                        h if h == dfu_control.handle => {
+                            // Finishing takes a while and may restart, so it runs once the write
+                            // is acknowledged. The outcome is notified on `dfu_status`.
+                            dfu_request = Vec::from_slice(event.data()).ok();
+                            Ok(())
                         }
-                        h if h == read.handle => {
-                            handle_u8_write(event.data(), |value| READ.signal(value != 0));
+                        h if h == dfu_data.handle => {
+                            dfu::handle_data(server, conn, event.data()).await;
+                            Ok(())
                         }
-                        h if h == motion_detection.handle => {
-                            handle_u8_write(event.data(), |value| {
-                                MOTION_DETECTION.signal(value != 0)
-                            });
+                        h if h == log_control.handle => {
+                            // Answered with notifications once the write is acknowledged.
+                            log_request = Vec::from_slice(event.data()).ok();
+                            Ok(())
                         }
-                        h if h == mark_epoch.handle => {
-                            handle_u8_write(event.data(), |value| {
-                                if value != 0 {
-                                    MARK_EPOCH.signal(())
-                                }
-                            });
+                        h if device_name.handles(server, h) => {
+                            device_name.apply(server, event.data())
                         }
-                        _ => {}
+                        h => match settings::setting_for_handle(server, h) {
+                            Some(setting) => {
+                                handle_setting_write(server, setting, event.data()).await
+                            }
+                            None => {
+                                subscriptions.update(server, h, event.data());
+                                Ok(())
+                            }
+                        },
                     },
-                    _ => {}
+                    _ => Ok(()),
                 };
 
-                // Accept + reply: ensure GATT response is sent
-                match event.accept() {
+                // Accept + reply: ensure GATT response is sent. A rejected write leaves the
+                // characteristic unchanged.
+                let reply = match result {
+                    Ok(()) => event.accept(),
+                    Err(code) => event.reject(code),
+                };
+                match reply {
                     Ok(reply) => reply.send().await,
                     Err(e) => warn!("[gatt] error sending response: {:?}", e),
                 };
+                if let Some(input) = console_input {
+                    console::handle_input(server, conn, link, &input).await;
+                }
+                if let Some(request) = dfu_request {
+                    dfu::handle_control(server, conn, &request).await;
+                }
+                if let Some(request) = log_request {
+                    download::handle_control(server, conn, &request).await;
+                }
             }
-            _ => {} // ignore other GATT connection events
         }
     };
     info!("[gatt] disconnected: {:?}", reason);
     Ok(())
 }
 
-define_write_handler!(handle_u8_write, u8, 1, |d: &[u8]| d[0]);
-
-define_write_handler!(handle_f32_write, f32, 4, |d: &[u8]| f32::from_le_bytes([
-    d[0], d[1], d[2], d[3]
-]));
-
-define_async_write_handler!(handle_u16_write, u16, 2, |d: &[u8]| u16::from_le_bytes([
-    d[0], d[1]
-]));
+/// Apply a write to the characteristic of `setting`, the same way the console sets it.
+async fn handle_setting_write(
+    server: &Server<'_>,
+    setting: Setting,
+    data: &[u8],
+) -> Result<(), AttErrorCode> {
+    let Some(value) = Value::decode(setting.kind(), data) else {
+        warn!(
+            "[gatt] Write Event: invalid data length for {}: {:?}",
+            setting.name(),
+            data
+        );
+        return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+    };
+    settings::apply(server, setting, value).await.map_err(|e| {
+        warn!(
+            "[gatt] invalid {} value {:?}: {:?}",
+            setting.name(),
+            value,
+            e
+        );
+        AttErrorCode::VALUE_NOT_ALLOWED
+    })
+}
 
-define_async_write_handler!(handle_u64_write, u64, 8, |d: &[u8]| u64::from_le_bytes([
-    d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
-]));
+define_write_handler!(handle_u8_write, u8, 1, |d: &[u8]| d[0]);
@@ -1,17 +1,63 @@
 use heapless::Vec;
+use mpu_protocol::config::{AckStatus, ConfigAck, ConfigPacket};
+use mpu_protocol::dfu::{Request, Status};
+use mpu_protocol::download;
+use mpu_protocol::frame::FormatDescriptor;
+use mpu_protocol::link::LinkParams;
+use mpu_protocol::stats::ThroughputStats;
 use trouble_host::prelude::*;
 
+use super::console::CONSOLE_CHUNK_LEN;
+use super::dfu::DFU_CHUNK_LEN;
+use super::download::LOG_DATA_LEN;
+use super::name::NAME_MAX_LEN;
+use super::notify_task::MAX_FRAME_LEN;
+
+use crate::sensor::config::SensorConfig;
 use crate::shared::{
-    DEFAULT_ACCEL_SCALE, DEFAULT_BUZZ_FREQUENCY_MODE, DEFAULT_CONTINUOUS_SAMPLE_INTERVAL_MS,
-    DEFAULT_FILTER, DEFAULT_GYRO_SCALE, DEFAULT_MAX_BUZZ_VALUE, DEFAULT_MIN_BUZZ_VALUE,
-    DEFAULT_MOTION_DETECTION, DEFAULT_MOTION_READ_DURATION_S, DEFAULT_MOTION_SAMPLE_INTERVAL_MS,
-    DEFAULT_PLAY_SOUND,
+    DEFAULT_ACCEL_SCALE, DEFAULT_BATTERY_DIVIDER, DEFAULT_BROADCAST_INTERVAL_MS,
+    DEFAULT_BUZZ_FREQUENCY_MODE, DEFAULT_CONTINUOUS_SAMPLE_INTERVAL_MS, DEFAULT_FILTER,
+    DEFAULT_GYRO_SCALE, DEFAULT_MAX_BUZZ_VALUE, DEFAULT_MIN_BUZZ_VALUE, DEFAULT_MOTION_DETECTION,
+    DEFAULT_MOTION_READ_DURATION_S, DEFAULT_MOTION_SAMPLE_INTERVAL_MS,
+    DEFAULT_PERIODIC_INTERVAL_MS, DEFAULT_PLAY_SOUND, DEFAULT_PRE_TRIGGER_MS, DEFAULT_RECORDING,
+    DEFAULT_STREAM_MODE,
 };
 
+/// Firmware version and the commit it was built from, e.g. `0.1.0+1a2b3c4`.
+pub const FIRMWARE_REVISION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_HASH"));
+
+/// A read-only string characteristic value.
+fn text<const N: usize>(text: &str) -> Vec<u8, N> {
+    Vec::from_slice(text.as_bytes()).unwrap_or_default()
+}
+
+/// Value of the `stream_format` characteristic.
+fn stream_format() -> Vec<u8, 16> {
+    let mut value = Vec::new();
+    value.resize_default(16).ok();
+    let len = FormatDescriptor::CURRENT.encode(&mut value).unwrap_or(0);
+    value.truncate(len);
+    value
+}
+
+/// Value of the `config_ack` characteristic before any packet was written.
+fn default_config_ack() -> [u8; ConfigAck::ENCODED_LEN] {
+    ConfigAck {
+        status: AckStatus::Applied,
+        config: ConfigPacket::from(SensorConfig::default()),
+    }
+    .encode()
+}
+
 /// GATT Server definition
-#[gatt_server]
+#[gatt_server(connections_max = super::CONNECTIONS_MAX)]
 pub struct Server {
     pub imu_service: MyService,
+    pub console_service: ConsoleService,
+    pub device_info: DeviceInformationService,
+    pub battery_service: BatteryService,
+    pub dfu_service: DfuService,
+    pub log_service: LogService,
 }
 
 #[gatt_service(uuid = "12345678-1234-5678-1234-56789abcdef0")]
@@ -19,30 +65,31 @@ pub struct MyService {
     #[characteristic(
         uuid = "12345678-1234-5678-1234-56789abcdeff",
         read,
-        value = Vec::from_slice(b"v1.0.1").unwrap()
+        value = text(FIRMWARE_REVISION)
     )]
-    pub firmware_version: Vec<u8, 16>,
+    pub firmware_version: Vec<u8, 32>,
 
     #[characteristic(
         uuid = "12345678-1234-5678-1234-56789abcdef1",
         read,
         notify,
-        value = Vec::from_slice(&[0; 11]).unwrap()
+        value = Vec::new()
     )]
-    pub sensor_accel: Vec<u8, 110>,
+    pub sensor_accel: Vec<u8, MAX_FRAME_LEN>,
 
     #[characteristic(
         uuid = "12345678-1234-5678-1234-56789abcdef2",
         read,
         notify,
-        value = Vec::from_slice(&[0; 11]).unwrap()
+        value = Vec::new()
     )]
-    pub sensor_gyro: Vec<u8, 110>,
+    pub sensor_gyro: Vec<u8, MAX_FRAME_LEN>,
 
     #[characteristic(
         uuid = "12345678-1234-5678-1234-56789abcdef3",
         write,
         read,
+        notify,
         value = DEFAULT_CONTINUOUS_SAMPLE_INTERVAL_MS
     )]
     pub continuous_sample_interval: u64,
@@ -51,6 +98,7 @@ pub struct MyService {
         uuid = "12345678-1234-5678-1234-56789abcdef4",
         write,
         read,
+        notify,
         value = DEFAULT_MOTION_READ_DURATION_S
     )]
     pub motion_read_duration: u16,
@@ -58,6 +106,7 @@ pub struct MyService {
         uuid = "12345678-1234-5678-1234-56789abcdef5",
         write,
         read,
+        notify,
         value = DEFAULT_PLAY_SOUND
     )]
     pub play_sound: bool,
@@ -65,6 +114,7 @@ pub struct MyService {
         uuid = "12345678-1234-5678-1234-56789abcdef6",
         write,
         read,
+        notify,
         value = DEFAULT_MOTION_SAMPLE_INTERVAL_MS
     )]
     pub motion_sample_interval: u64,
@@ -73,6 +123,7 @@ pub struct MyService {
         uuid = "12345678-1234-5678-1234-56789abcdef7",
         write,
         read,
+        notify,
         value = DEFAULT_ACCEL_SCALE as u8
     )]
     pub accel_scale: u8,
@@ -81,6 +132,7 @@ pub struct MyService {
         uuid = "12345678-1234-5678-1234-56789abcdef8",
         write,
         read,
+        notify,
         value = DEFAULT_GYRO_SCALE as u8
     )]
     pub gyro_scale: u8,
@@ -89,14 +141,15 @@ pub struct MyService {
         uuid = "12345678-1234-5678-1234-56789abcdef9",
         write,
         read,
+        notify,
         value = DEFAULT_BUZZ_FREQUENCY_MODE as u8
     )]
     pub buzz_frequency_mode: u8,
-    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdefa", write, read, value = DEFAULT_MIN_BUZZ_VALUE)]
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdefa", write, read, notify, value = DEFAULT_MIN_BUZZ_VALUE)]
     pub min_buzz_value: f32,
-    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdefb", write, read, value = DEFAULT_MAX_BUZZ_VALUE)]
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdefb", write, read, notify, value = DEFAULT_MAX_BUZZ_VALUE)]
     pub max_buzz_value: f32,
-    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdefc", write, read, value = DEFAULT_FILTER as u8)]
+    #[characteristic(uuid = "12345678-1234-5678-1234-56789abcdefc", write, read, notify, value = DEFAULT_FILTER as u8)]
     pub digital_low_pass_filter: u8,
     #[characteristic(
         uuid = "12345678-1234-5678-1234-56789abcdefd",
@@ -116,7 +169,221 @@ pub struct MyService {
         uuid = "12345678-1234-5678-1234-56789abcdff0",
         write,
         read,
+        notify,
         value = DEFAULT_MOTION_DETECTION
     )]
     pub motion_detection: bool,
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff1",
+        read,
+        value = stream_format()
+    )]
+    pub stream_format: Vec<u8, 16>,
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff2",
+        read,
+        notify,
+        value = [0; ThroughputStats::ENCODED_LEN]
+    )]
+    pub stream_stats: [u8; ThroughputStats::ENCODED_LEN],
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff3",
+        read,
+        notify,
+        value = [0; LinkParams::ENCODED_LEN]
+    )]
+    pub link_params: [u8; LinkParams::ENCODED_LEN],
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff4",
+        write,
+        read,
+        notify,
+        value = DEFAULT_STREAM_MODE.to_u8()
+    )]
+    pub stream_mode: u8,
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff5",
+        read,
+        notify,
+        value = Vec::new()
+    )]
+    pub sensor_combined: Vec<u8, MAX_FRAME_LEN>,
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff6",
+        read,
+        // The macro declares a `STREAM_PSM` static for this field, so use the full path.
+        value = super::l2cap::STREAM_PSM
+    )]
+    pub stream_psm: u16,
+    /// A whole `ConfigPacket`, validated and applied at once.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff7",
+        write,
+        value = Vec::new()
+    )]
+    pub config_packet: Vec<u8, 16>,
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff8",
+        read,
+        notify,
+        value = default_config_ack()
+    )]
+    pub config_ack: [u8; ConfigAck::ENCODED_LEN],
+    /// `SecurityMode` as `u8`, restored from flash at boot.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdff9",
+        write,
+        read,
+        notify,
+        value = 0
+    )]
+    pub security: u8,
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdffa",
+        write,
+        read,
+        notify,
+        value = DEFAULT_BATTERY_DIVIDER
+    )]
+    pub battery_divider: f32,
+    /// Advertised name, the same as the GAP Device Name. See `name`.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdffb",
+        write,
+        read,
+        value = Vec::new()
+    )]
+    pub device_name: Vec<u8, NAME_MAX_LEN>,
+    /// Milliseconds between broadcast summaries, 0 for off. See `broadcast`.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdffc",
+        write,
+        read,
+        notify,
+        value = DEFAULT_BROADCAST_INTERVAL_MS
+    )]
+    pub broadcast_interval: u16,
+    /// Milliseconds between periodic advertising events carrying samples, 0 for off. See
+    /// `periodic`.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdffd",
+        write,
+        read,
+        notify,
+        value = DEFAULT_PERIODIC_INTERVAL_MS
+    )]
+    pub periodic_interval: u16,
+    /// Whether read windows are recorded to flash. See `recording`.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdffe",
+        write,
+        read,
+        notify,
+        value = DEFAULT_RECORDING
+    )]
+    pub recording: bool,
+    /// Milliseconds of samples before the motion interrupt, see `mpu_protocol::pre_trigger`.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abcdfff",
+        write,
+        read,
+        notify,
+        value = DEFAULT_PRE_TRIGGER_MS
+    )]
+    pub pre_trigger: u16,
+}
+
+/// Nordic UART Service, so generic BLE terminal apps can talk to the console.
+#[gatt_service(uuid = "6E400001-B5A3-F393-E0A9-E50E24DCCA9E")]
+pub struct ConsoleService {
+    /// Commands from the client, see `console`.
+    #[characteristic(
+        uuid = "6E400002-B5A3-F393-E0A9-E50E24DCCA9E",
+        write,
+        write_without_response,
+        value = Vec::new()
+    )]
+    pub rx: Vec<u8, CONSOLE_CHUNK_LEN>,
+    /// Replies to the client.
+    #[characteristic(
+        uuid = "6E400003-B5A3-F393-E0A9-E50E24DCCA9E",
+        notify,
+        value = Vec::new()
+    )]
+    pub tx: Vec<u8, CONSOLE_CHUNK_LEN>,
+}
+
+/// Firmware updates, see `dfu`.
+#[gatt_service(uuid = "12345678-1234-5678-1234-56789abce000")]
+pub struct DfuService {
+    /// Start, finish or abort an update.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abce001",
+        write,
+        value = Vec::new()
+    )]
+    pub control: Vec<u8, { Request::MAX_ENCODED_LEN }>,
+    /// Image chunks, each prefixed with its offset.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abce002",
+        write,
+        write_without_response,
+        value = Vec::new()
+    )]
+    pub data: Vec<u8, DFU_CHUNK_LEN>,
+    /// State, error of the last request and bytes received.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abce003",
+        read,
+        notify,
+        value = Status::default().encode()
+    )]
+    pub status: [u8; Status::ENCODED_LEN],
+}
+
+/// Sessions recorded to flash, see `download`.
+#[gatt_service(uuid = "12345678-1234-5678-1234-56789abce100")]
+pub struct LogService {
+    /// List, read, delete or format.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abce101",
+        write,
+        value = Vec::new()
+    )]
+    pub control: Vec<u8, { download::Request::MAX_ENCODED_LEN }>,
+    /// Responses, the last one is kept for reading.
+    #[characteristic(
+        uuid = "12345678-1234-5678-1234-56789abce102",
+        read,
+        notify,
+        value = Vec::new()
+    )]
+    pub data: Vec<u8, LOG_DATA_LEN>,
+}
+
+/// Standard Device Information Service. The serial number and hardware revision are read from the
+/// chip at boot, see `device_info`.
+#[gatt_service(uuid = service::DEVICE_INFORMATION)]
+pub struct DeviceInformationService {
+    #[characteristic(uuid = characteristic::MANUFACTURER_NAME_STRING, read, value = text("jrjdavidson"))]
+    pub manufacturer_name: Vec<u8, 32>,
+    #[characteristic(uuid = characteristic::MODEL_NUMBER_STRING, read, value = text("Motion reporter"))]
+    pub model_number: Vec<u8, 32>,
+    /// The efuse MAC address in hex.
+    #[characteristic(uuid = characteristic::SERIAL_NUMBER_STRING, read, value = Vec::new())]
+    pub serial_number: Vec<u8, 32>,
+    // The macro declares a `FIRMWARE_REVISION` static for this field, so use the full path.
+    #[characteristic(uuid = characteristic::FIRMWARE_REVISION_STRING, read, value = text(crate::ble::gatt::FIRMWARE_REVISION))]
+    pub firmware_revision: Vec<u8, 32>,
+    /// Chip and revision, e.g. `ESP32-C6 v0.1`.
+    #[characteristic(uuid = characteristic::HARDWARE_REVISION_STRING, read, value = Vec::new())]
+    pub hardware_revision: Vec<u8, 32>,
+}
+
+/// Standard Battery Service.
+#[gatt_service(uuid = service::BATTERY)]
+pub struct BatteryService {
+    /// Battery level in percent, see `battery`. Stays 100 without a battery.
+    #[characteristic(uuid = characteristic::BATTERY_LEVEL, read, notify, value = 100)]
+    pub level: u8,
 }
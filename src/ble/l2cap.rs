@@ -0,0 +1,94 @@
+//! L2CAP connection oriented channel for the sensor stream.
+//!
+//! GATT notifications stay the default. A client that can open an LE credit based channel on
+//! [`STREAM_PSM`] gets the same frames as SDUs on that channel instead, for as long as it is open.
+//! The receiver hands out credits as it consumes data, so sends wait for the client rather than
+//! for a pacing budget.
+use defmt::{info, warn, Debug2Format};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use trouble_host::prelude::*;
+
+use super::notify_task::MAX_FRAME_LEN;
+
+/// PSM the stream channel is accepted on, from the dynamic LE range.
+pub const STREAM_PSM: u16 = 0x0081;
+
+/// The open stream channel of one connection, if any.
+pub struct StreamChannel<'d, P: PacketPool> {
+    writer: Mutex<NoopRawMutex, Option<L2capChannelWriter<'d, P>>>,
+}
+
+impl<'d, P: PacketPool> Default for StreamChannel<'d, P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d, P: PacketPool> StreamChannel<'d, P> {
+    pub fn new() -> Self {
+        Self {
+            writer: Mutex::new(None),
+        }
+    }
+
+    pub async fn is_open(&self) -> bool {
+        self.writer.lock().await.is_some()
+    }
+
+    /// Send `frames` over the channel, returning how many were sent: all of them, none when no
+    /// channel is open, or those before a send failed.
+    ///
+    /// If sending fails the channel is dropped, so the stream falls back to notifications.
+    pub async fn send<C: Controller>(
+        &self,
+        stack: &Stack<'_, C, P>,
+        frames: &[impl AsRef<[u8]>],
+    ) -> usize {
+        let mut writer = self.writer.lock().await;
+        let Some(channel) = writer.as_mut() else {
+            return 0;
+        };
+        for (sent, frame) in frames.iter().enumerate() {
+            if let Err(e) = channel.send(stack, frame.as_ref()).await {
+                warn!(
+                    "[l2cap] error sending frame, closing stream channel: {:?}",
+                    Debug2Format(&e)
+                );
+                channel.disconnect();
+                *writer = None;
+                return sent;
+            }
+        }
+        frames.len()
+    }
+}
+
+/// Accept stream channels on `conn`, one at a time, until the connection closes.
+pub async fn l2cap_task<'d, C: Controller, P: PacketPool>(
+    stack: &'d Stack<'d, C, P>,
+    conn: &GattConnection<'_, '_, P>,
+    channel: &StreamChannel<'d, P>,
+) {
+    let config = L2capChannelConfig {
+        mtu: Some(MAX_FRAME_LEN as u16),
+        ..Default::default()
+    };
+    loop {
+        let (writer, mut reader) =
+            match L2capChannel::accept(stack, conn.raw(), &[STREAM_PSM], &config).await {
+                Ok(ch) => ch.split(),
+                Err(e) => {
+                    // The connection is gone, or every channel slot is taken.
+                    warn!("[l2cap] error accepting channel: {:?}", Debug2Format(&e));
+                    return;
+                }
+            };
+        info!("[l2cap] stream channel open on PSM {:#x}", STREAM_PSM);
+        *channel.writer.lock().await = Some(writer);
+        // The client sends nothing, receiving only returns once the channel closes.
+        while reader.receive_sdu(stack).await.is_ok() {}
+        *channel.writer.lock().await = None;
+        info!("[l2cap] stream channel closed");
+    }
+}
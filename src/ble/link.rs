@@ -0,0 +1,195 @@
+//! Connection parameter and PHY management.
+//!
+//! Streaming asks for the 2M PHY, the largest LL packets and a short connection interval; once
+//! the read window ends the link relaxes to a long interval with peripheral latency, so an idle
+//! connection costs little power. The central has the final say, the values it settles on are
+//! reported through the `link_params` characteristic.
+use core::cell::Cell;
+
+use bt_hci::cmd::le::{
+    LeConnUpdate, LeReadLocalSupportedFeatures, LeReadPhy, LeSetDataLength, LeSetPhy,
+};
+use bt_hci::controller::{ControllerCmdAsync, ControllerCmdSync};
+use defmt::{info, warn, Debug2Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use mpu_protocol::link::LinkParams;
+use trouble_host::prelude::*;
+
+use super::gatt::Server;
+use crate::shared::STREAMING;
+
+/// Largest LL payload (Data Length Extension) and the time it takes to send on the 1M PHY.
+const MAX_TX_OCTETS: u16 = 251;
+const MAX_TX_TIME_US: u16 = 2120;
+
+const STREAMING_PARAMS: ConnectParams = ConnectParams {
+    min_connection_interval: Duration::from_micros(7_500),
+    max_connection_interval: Duration::from_millis(15),
+    max_latency: 0,
+    event_length: Duration::from_ticks(0),
+    supervision_timeout: Duration::from_secs(4),
+};
+
+const IDLE_PARAMS: ConnectParams = ConnectParams {
+    min_connection_interval: Duration::from_millis(100),
+    max_connection_interval: Duration::from_millis(200),
+    max_latency: 4,
+    event_length: Duration::from_ticks(0),
+    supervision_timeout: Duration::from_secs(6),
+};
+
+/// Controller commands needed to manage the link.
+pub trait LinkController:
+    Controller
+    + ControllerCmdSync<LeSetDataLength>
+    + ControllerCmdSync<LeReadPhy>
+    + ControllerCmdSync<LeReadLocalSupportedFeatures>
+    + ControllerCmdAsync<LeSetPhy>
+    + ControllerCmdAsync<LeConnUpdate>
+{
+}
+
+impl<C> LinkController for C where
+    C: Controller
+        + ControllerCmdSync<LeSetDataLength>
+        + ControllerCmdSync<LeReadPhy>
+        + ControllerCmdSync<LeReadLocalSupportedFeatures>
+        + ControllerCmdAsync<LeSetPhy>
+        + ControllerCmdAsync<LeConnUpdate>
+{
+}
+
+/// Link parameters of one connection, shared by the tasks serving it.
+pub struct LinkState {
+    params: Mutex<NoopRawMutex, Cell<LinkParams>>,
+    changed: Signal<NoopRawMutex, ()>,
+}
+
+impl Default for LinkState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkState {
+    pub fn new() -> Self {
+        Self {
+            params: Mutex::new(Cell::new(LinkParams::default())),
+            changed: Signal::new(),
+        }
+    }
+
+    pub fn get(&self) -> LinkParams {
+        self.params.lock(|p| p.get())
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut LinkParams)) {
+        self.params.lock(|p| {
+            let mut params = p.get();
+            f(&mut params);
+            p.set(params);
+        });
+        self.changed.signal(());
+    }
+
+    /// The connection interval, once the central has reported one.
+    pub fn conn_interval(&self) -> Option<Duration> {
+        match self.get().conn_interval_us {
+            0 => None,
+            us => Some(Duration::from_micros(us as u64)),
+        }
+    }
+}
+
+/// Apply the link profile matching the read window state, from the start of the connection, and
+/// report parameter changes, until the connection closes.
+pub async fn link_task<C: LinkController, P: PacketPool>(
+    stack: &Stack<'_, C, P>,
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    link: &LinkState,
+) {
+    let link_params = &server.imu_service.link_params;
+    request_data_length(stack, conn).await;
+    match conn.raw().read_phy(stack).await {
+        Ok((tx, rx)) => link.update(|p| {
+            p.tx_phy = tx as u8;
+            p.rx_phy = rx as u8;
+        }),
+        Err(e) => warn!("[link] error reading PHY: {:?}", Debug2Format(&e)),
+    }
+
+    let Some(mut streaming) = STREAMING.receiver() else {
+        warn!("[link] no receiver left for the read window state");
+        return;
+    };
+    // A read window may already be open; otherwise the connection starts out idle, whatever
+    // parameters the central connected with.
+    let open = streaming.try_changed() == Some(true);
+    apply_profile(stack, conn, link, open).await;
+    loop {
+        match select(streaming.changed(), link.changed.wait()).await {
+            Either::First(streaming) => apply_profile(stack, conn, link, streaming).await,
+            Either::Second(()) => {
+                let mut params = link.get();
+                params.att_mtu = conn.raw().att_mtu();
+                info!("[link] {:?}", params);
+                if link_params.notify(conn, &params.encode()).await.is_err() {
+                    warn!("[link] error notifying link parameters");
+                }
+            }
+        }
+    }
+}
+
+async fn apply_profile<C: LinkController, P: PacketPool>(
+    stack: &Stack<'_, C, P>,
+    conn: &GattConnection<'_, '_, P>,
+    link: &LinkState,
+    streaming: bool,
+) {
+    info!(
+        "[link] requesting {} parameters",
+        if streaming { "streaming" } else { "idle" }
+    );
+    let params = if streaming {
+        // 2M halves the airtime per packet; it is kept when idle since that costs nothing.
+        if let Err(e) = conn.raw().set_phy(stack, PhyKind::Le2M).await {
+            warn!("[link] error requesting 2M PHY: {:?}", Debug2Format(&e));
+        }
+        request_data_length(stack, conn).await;
+        &STREAMING_PARAMS
+    } else {
+        &IDLE_PARAMS
+    };
+    if let Err(e) = conn.raw().update_connection_params(stack, params).await {
+        warn!(
+            "[link] error requesting connection parameters: {:?}",
+            Debug2Format(&e)
+        );
+    }
+    link.update(|p| p.streaming = streaming);
+}
+
+/// Ask the controller for the largest LL packets, so a full-MTU notification needs as few
+/// packets as possible. The ATT MTU itself is requested by the central; we accept up to the
+/// packet pool size (`TROUBLE_HOST_DEFAULT_PACKET_POOL_MTU` in `.cargo/config.toml`).
+async fn request_data_length<C: LinkController, P: PacketPool>(
+    stack: &Stack<'_, C, P>,
+    conn: &GattConnection<'_, '_, P>,
+) {
+    let handle = conn.raw().handle();
+    match stack
+        .command(LeSetDataLength::new(handle, MAX_TX_OCTETS, MAX_TX_TIME_US))
+        .await
+    {
+        Ok(_) => info!("[link] requested data length {}", MAX_TX_OCTETS),
+        Err(e) => warn!(
+            "[link] data length extension not available: {:?}",
+            Debug2Format(&e)
+        ),
+    }
+}
@@ -1,35 +1,79 @@
+pub mod battery;
+pub mod broadcast;
+pub mod config;
+pub mod console;
+pub mod device_info;
+pub mod dfu;
+pub mod download;
 pub mod events;
 pub mod gatt;
 pub mod handler_macros;
+pub mod l2cap;
+pub mod link;
+pub mod name;
 pub mod notify_task;
-use defmt::{error, info};
-use embassy_futures::join::join;
-use embassy_futures::select::select;
+pub mod pacing;
+pub mod periodic;
+pub mod security;
+pub mod settings;
+use core::cell::Cell;
+
+use defmt::info;
+use embassy_futures::join::{join5, join_array};
+use embassy_futures::select::{select3, select4, Either4};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer};
+use mpu_protocol::console::{Setting, Value};
+use rand_chacha::ChaCha12Rng;
+use rand_core::SeedableRng;
 use trouble_host::prelude::*;
 
 /// Max number of connections
-const CONNECTIONS_MAX: usize = 2;
+pub const CONNECTIONS_MAX: usize = 2;
+
+/// Advertising sets: the connectable one and the one carrying the periodic advertising train.
+const ADV_SETS: usize = 2;
 
 /// Max number of L2CAP channels.
-const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
+const L2CAP_CHANNELS_MAX: usize = 2 + CONNECTIONS_MAX; // Signal + att, and a stream channel per connection
 
+use battery::level_task;
+use config::config_task;
 use events::gatt_events_task;
 use gatt::Server;
-use notify_task::run_task;
+use l2cap::{l2cap_task, StreamChannel};
+use link::{link_task, LinkController, LinkState};
+use name::{DeviceName, NAME_MAX_LEN};
+use notify_task::{run_task, Subscriptions};
+use periodic::{periodic_task, PeriodicController, Train};
+use security::{advertising_filter, SecurityController, PAIRING_WINDOW_DURATION};
+use settings::{notify_task, sync_task};
+
+use crate::shared::{BROADCAST_INTERVAL_MS, PAIRING_WINDOW, PERIODIC_INTERVAL_MS};
 
-/// Run the BLE stack.
-pub async fn run<C>(controller: C)
+/// Run the BLE stack. `seed` seeds the key generation for pairing and must come from a true random
+/// source.
+pub async fn run<C>(controller: C, seed: [u8; 32])
 where
-    C: Controller,
+    C: LinkController + SecurityController + PeriodicController,
 {
     // Using a fixed "random" address can be useful for testing. In real scenarios, one would
     // use e.g. the MAC 6 byte array as the address (how to get that varies by the platform).
     let address: Address = Address::random(esp_hal::efuse::Efuse::mac_address());
     info!("Our address = {:?}", address);
 
-    let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> =
-        HostResources::new();
-    let stack = trouble_host::new(controller, &mut resources).set_random_address(address);
+    let mut resources: HostResources<
+        DefaultPacketPool,
+        CONNECTIONS_MAX,
+        L2CAP_CHANNELS_MAX,
+        ADV_SETS,
+    > = HostResources::new();
+    let stack = trouble_host::new(controller, &mut resources)
+        .set_random_address(address)
+        .set_random_generator_seed(&mut ChaCha12Rng::from_seed(seed));
+    let security_mode = security::restore(&stack);
     let Host {
         mut peripheral,
         runner,
@@ -37,33 +81,168 @@ where
     } = stack.build();
 
     info!("Starting advertising and GATT service");
-    if let Ok(server) = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
-        name: "Motion reporter",
-        appearance: &appearance::sensor::GENERIC_SENSOR,
-    })) {
-        info!(" server created");
-        let _ = join(ble_task(runner), async move {
+    let mut device_name_store = [0; NAME_MAX_LEN];
+    let mut table = AttributeTable::new();
+    let device_name = DeviceName::new(&mut table, &mut device_name_store);
+    let server = Server::new(table);
+    info!(" server created");
+    settings::store(&server, Setting::Security, Value::U8(security_mode as u8));
+    device_info::init(&server);
+    device_name.init(&server);
+    let stack = &stack;
+    let server = &server;
+    let device_name = &device_name;
+    let slots = Slots::new();
+    let slots = &slots;
+    let train = Train::new();
+    let train = &train;
+    let _ = join5(
+        ble_task(runner),
+        sync_task(server),
+        periodic_task(stack, train),
+        async move {
+            // Keep advertising while a connection slot is free.
+            let mut pairing_window_end: Option<Instant> = None;
+            let mut broadcast_interval = BROADCAST_INTERVAL_MS
+                .receiver()
+                .expect("the advertiser is the only broadcast interval receiver");
+            let mut periodic_interval = PERIODIC_INTERVAL_MS
+                .receiver()
+                .expect("the advertiser is the only periodic interval receiver");
+            let mut packet_id: u8 = 0;
             loop {
-                match advertise("Motion reporter", &mut peripheral, &server).await {
-                    Ok(conn) => {
-                        // set up tasks when the connection is established to a central, so they don't run when no one is connected.
-                        info!("[adv] connection established, starting tasks");
-                        let a = gatt_events_task(&server, &conn);
-                        let b = run_task(&server, &conn);
-                        // run until any task ends (usually because the connection has been closed),
-                        // then return to advertising state.
-                        select(a, b).await;
+                slots.wait_idle().await;
+                let window_end = pairing_window_end.filter(|end| *end > Instant::now());
+                let filter = advertising_filter(stack, server, window_end.is_some()).await;
+                let window_closed = async {
+                    match window_end {
+                        Some(end) => Timer::at(end).await,
+                        None => core::future::pending().await,
                     }
-                    Err(e) => {
+                };
+                let name = device_name.get(server);
+                let interval_ms = broadcast_interval.try_get().unwrap_or(0);
+                let summary = (interval_ms != 0).then(|| {
+                    packet_id = packet_id.wrapping_add(1);
+                    broadcast::payload(packet_id)
+                });
+                let periodic_ms = periodic_interval.try_get().unwrap_or(0);
+                let summary_due = async {
+                    match interval_ms {
+                        0 => core::future::pending().await,
+                        ms => Timer::after_millis(ms as u64).await,
+                    }
+                };
+                let data = AdvertisingData {
+                    name: &name,
+                    summary: summary.as_deref(),
+                    filter_policy: filter,
+                    periodic_ms,
+                };
+                // Advertising restarts whenever the pairing window opens or closes, the device
+                // is renamed, a broadcast summary is due or switched on or off, or the periodic
+                // train changes.
+                match select4(
+                    advertise(stack, &mut peripheral, server, train, data),
+                    PAIRING_WINDOW.wait(),
+                    window_closed,
+                    select4(
+                        device_name.changed(),
+                        summary_due,
+                        broadcast_interval.changed(),
+                        periodic_interval.changed(),
+                    ),
+                )
+                .await
+                {
+                    Either4::First(Ok(conn)) => slots.hand_over(conn).await,
+                    Either4::First(Err(e)) => {
                         panic!("[adv] error: {:?}", e);
                     }
+                    Either4::Second(()) => {
+                        info!("[adv] pairing window open");
+                        pairing_window_end = Some(Instant::now() + PAIRING_WINDOW_DURATION);
+                    }
+                    Either4::Third(()) => {
+                        info!("[adv] pairing window closed");
+                        pairing_window_end = None;
+                    }
+                    Either4::Fourth(Either4::First(())) => {
+                        info!("[adv] advertising the new name")
+                    }
+                    Either4::Fourth(_) => {}
                 }
             }
-        })
-        .await;
-    } else {
-        error!("Error starting server");
-    };
+        },
+        join_array(core::array::from_fn::<_, CONNECTIONS_MAX, _>(|_| {
+            serve(stack, server, device_name, slots)
+        })),
+    )
+    .await;
+}
+
+/// Connections handed from the advertiser to the connection slots serving them.
+struct Slots<'values, 'server> {
+    idle: Cell<usize>,
+    freed: Signal<NoopRawMutex, ()>,
+    connections: Channel<NoopRawMutex, GattConnection<'values, 'server, DefaultPacketPool>, 1>,
+}
+
+impl<'values, 'server> Slots<'values, 'server> {
+    fn new() -> Self {
+        Self {
+            idle: Cell::new(CONNECTIONS_MAX),
+            freed: Signal::new(),
+            connections: Channel::new(),
+        }
+    }
+
+    async fn wait_idle(&self) {
+        while self.idle.get() == 0 {
+            self.freed.wait().await;
+        }
+    }
+
+    /// Claim an idle slot for `conn`.
+    async fn hand_over(&self, conn: GattConnection<'values, 'server, DefaultPacketPool>) {
+        self.idle.set(self.idle.get() - 1);
+        self.connections.send(conn).await;
+    }
+
+    fn release(&self) {
+        self.idle.set(self.idle.get() + 1);
+        self.freed.signal(());
+    }
+}
+
+/// Serve the connections handed to one slot, one after the other.
+async fn serve<'values, 'server, C: LinkController>(
+    stack: &'values Stack<'values, C, DefaultPacketPool>,
+    server: &'server Server<'values>,
+    device_name: &DeviceName,
+    slots: &Slots<'values, 'server>,
+) {
+    loop {
+        let conn = slots.connections.receive().await;
+        // set up tasks when the connection is established to a central, so they don't run when no one is connected.
+        info!("[adv] connection established, starting tasks");
+        let link = LinkState::new();
+        let stream = StreamChannel::new();
+        let subscriptions = Subscriptions::new();
+        let a = gatt_events_task(server, &conn, &link, &subscriptions, device_name);
+        let b = run_task(stack, server, &conn, &link, &stream, &subscriptions);
+        let c = link_task(stack, server, &conn, &link);
+        // Failing to accept a stream channel must not end the connection.
+        let d = l2cap_task(stack, &conn, &stream);
+        let e = config_task(server, &conn);
+        let f = notify_task(server, &conn);
+        let g = level_task(server, &conn);
+        // run until any task ends (usually because the connection has been closed),
+        // then free the slot for the next central.
+        select3(a, b, join5(c, d, e, f, g)).await;
+        drop(conn);
+        slots.release();
+    }
 }
 
 async fn ble_task<C: Controller, P: PacketPool>(mut runner: Runner<'_, C, P>) {
@@ -73,29 +252,88 @@ async fn ble_task<C: Controller, P: PacketPool>(mut runner: Runner<'_, C, P>) {
         }
     }
 }
-async fn advertise<'values, 'server, C: Controller>(
-    name: &'values str,
+
+/// What to advertise, see [`advertise`].
+struct AdvertisingData<'a> {
+    name: &'a str,
+    /// Broadcast summary, see `broadcast`.
+    summary: Option<&'a [u8]>,
+    filter_policy: AdvFilterPolicy,
+    /// Interval of the periodic advertising train, 0 for none.
+    periodic_ms: u16,
+}
+
+/// Advertise until a central connects. The connectable set uses legacy advertising PDUs, so every
+/// scanner sees it; it goes through the extended advertising commands because a controller may
+/// refuse legacy commands once the periodic train used extended ones.
+async fn advertise<'values, 'server, C: PeriodicController>(
+    stack: &'values Stack<'values, C, DefaultPacketPool>,
     peripheral: &mut Peripheral<'values, C, DefaultPacketPool>,
     server: &'server Server<'values>,
+    train: &Train,
+    data: AdvertisingData<'_>,
 ) -> Result<GattConnection<'values, 'server, DefaultPacketPool>, BleHostError<C::Error>> {
+    let AdvertisingData {
+        name,
+        summary,
+        filter_policy,
+        periodic_ms,
+    } = data;
+    let flags = AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED);
+    let uuids = AdStructure::ServiceUuids16(&[[0x0f, 0x08]]);
+    let name = AdStructure::CompleteLocalName(name.as_bytes());
     let mut advertiser_data = [0; 31];
-    let len = AdStructure::encode_slice(
-        &[
-            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-            AdStructure::ServiceUuids16(&[[0x0f, 0x08]]),
-            AdStructure::CompleteLocalName(name.as_bytes()),
-        ],
-        &mut advertiser_data[..],
-    )?;
-    let advertiser = peripheral
-        .advertise(
-            &Default::default(),
-            Advertisement::ConnectableScannableUndirected {
+    let mut scan_data = [0; 31];
+    // A broadcast summary takes the room of the name, which moves to the scan response.
+    let (len, scan_len) = match summary {
+        Some(summary) => {
+            let service_data = AdStructure::ServiceData16 {
+                uuid: mpu_protocol::broadcast::SERVICE_UUID.to_le_bytes(),
+                data: summary,
+            };
+            (
+                AdStructure::encode_slice(&[flags, service_data], &mut advertiser_data[..])?,
+                AdStructure::encode_slice(&[uuids, name], &mut scan_data[..])?,
+            )
+        }
+        None => (
+            AdStructure::encode_slice(&[flags, uuids, name], &mut advertiser_data[..])?,
+            0,
+        ),
+    };
+    let mut periodic_data = [0; 31];
+    let periodic_len = AdStructure::encode_slice(&[name], &mut periodic_data[..])?;
+    let sets = [
+        AdvertisementSet {
+            params: AdvertisementParameters {
+                filter_policy,
+                ..Default::default()
+            },
+            data: Advertisement::ConnectableScannableUndirected {
                 adv_data: &advertiser_data[..len],
-                scan_data: &[],
+                scan_data: &scan_data[..scan_len],
             },
-        )
+        },
+        // Scanners find the periodic train through this set, always the second one.
+        AdvertisementSet {
+            params: AdvertisementParameters::default(),
+            data: Advertisement::ExtNonconnectableNonscannableUndirected {
+                anonymous: false,
+                adv_data: &periodic_data[..periodic_len],
+            },
+        },
+    ];
+    let mut handles = AdvertisementSet::handles(&sets);
+    let count = if Train::wanted(periodic_ms) {
+        ADV_SETS
+    } else {
+        1
+    };
+    train.prepare(stack, periodic_ms).await;
+    let advertiser = peripheral
+        .advertise_ext(&sets[..count], &mut handles[..count])
         .await?;
+    train.start(stack, periodic_ms).await;
     info!("[adv] advertising");
     let conn = advertiser.accept().await?.with_attribute_server(server)?;
     info!("[adv] connection established");
@@ -0,0 +1,151 @@
+//! The device name, advertised and exposed as the GAP Device Name and the `device_name`
+//! characteristic.
+//!
+//! Without a custom name the device is called `Motion reporter` followed by the last two bytes of
+//! its MAC address, so units in one room can be told apart. A name written to either
+//! characteristic is kept in the [`Area::Name`] flash sector.
+use core::fmt::Write;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::signal::Signal;
+use esp_hal::efuse::Efuse;
+use heapless::{String, Vec};
+use trouble_host::prelude::*;
+
+use super::gatt::Server;
+use crate::error_log;
+use crate::storage::{self, Area};
+
+/// Longest name, what is left of the advertising data besides the flags and service UUID.
+pub const NAME_MAX_LEN: usize = 22;
+
+pub type Name = String<NAME_MAX_LEN>;
+
+/// Bytes of a name characteristic.
+type NameValue = Vec<u8, NAME_MAX_LEN>;
+
+/// First byte of the name sector, followed by the length and the name.
+const NAME_MAGIC: u8 = 0x4e;
+
+/// The GAP Device Name characteristic, which trouble-host only offers read-only, and a signal
+/// for the advertiser when the name changes.
+pub struct DeviceName {
+    gap: Characteristic<NameValue>,
+    changed: Signal<NoopRawMutex, ()>,
+}
+
+impl DeviceName {
+    /// Add the GAP service, with a writable Device Name, and the GATT service to `table`.
+    /// `store` holds the Device Name value.
+    pub fn new<'d, M: RawMutex, const MAX: usize>(
+        table: &mut AttributeTable<'d, M, MAX>,
+        store: &'d mut [u8; NAME_MAX_LEN],
+    ) -> Self {
+        let mut gap = table.add_service(Service::new(service::GAP));
+        let name = gap
+            .add_characteristic(
+                characteristic::DEVICE_NAME,
+                &[CharacteristicProp::Read, CharacteristicProp::Write],
+                NameValue::from_slice(load().as_bytes()).unwrap_or_default(),
+                store,
+            )
+            .build();
+        gap.add_characteristic_ro(
+            characteristic::APPEARANCE,
+            &appearance::sensor::GENERIC_SENSOR,
+        );
+        gap.build();
+        table.add_service(Service::new(service::GATT));
+        Self {
+            gap: name,
+            changed: Signal::new(),
+        }
+    }
+
+    /// Copy the name into the `device_name` characteristic.
+    pub fn init(&self, server: &Server<'_>) {
+        let value = server.get(&self.gap).unwrap_or_default();
+        if server.imu_service.device_name.set(server, &value).is_err() {
+            warn!("[name] error setting device_name");
+        }
+    }
+
+    /// The name in effect.
+    pub fn get(&self, server: &Server<'_>) -> Name {
+        let value = server.get(&self.gap).unwrap_or_default();
+        core::str::from_utf8(&value)
+            .ok()
+            .and_then(|name| Name::try_from(name).ok())
+            .unwrap_or_else(default_name)
+    }
+
+    /// Whether `handle` is one of the name characteristics.
+    pub fn handles(&self, server: &Server<'_>, handle: u16) -> bool {
+        handle == self.gap.handle || handle == server.imu_service.device_name.handle
+    }
+
+    /// Apply a write to one of the name characteristics: keep the name in flash, mirror it in the
+    /// other characteristic and restart advertising with it.
+    pub fn apply(&self, server: &Server<'_>, data: &[u8]) -> Result<(), AttErrorCode> {
+        let name = core::str::from_utf8(data)
+            .ok()
+            .filter(|name| valid(name))
+            .ok_or(AttErrorCode::VALUE_NOT_ALLOWED)?;
+        let value = NameValue::from_slice(data)
+            .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)?;
+        info!("[name] renamed to {}", name);
+        // The written characteristic is updated when the write is accepted.
+        server.set(&self.gap, &value).ok();
+        server.imu_service.device_name.set(server, &value).ok();
+        if let Err(e) = store(name) {
+            warn!("[name] error storing the name: {:?}", e);
+            error_log::record("storing device name failed");
+        }
+        self.changed.signal(());
+        Ok(())
+    }
+
+    /// Wait until the name is changed.
+    pub async fn changed(&self) {
+        self.changed.wait().await
+    }
+}
+
+/// `Motion reporter` and the last two bytes of the MAC address, e.g. `Motion reporter 3F2A`.
+pub fn default_name() -> Name {
+    let mac = Efuse::mac_address();
+    let mut name = Name::new();
+    write!(name, "Motion reporter {:02X}{:02X}", mac[4], mac[5]).ok();
+    name
+}
+
+/// Printable and not blank, so the device stays recognisable in a scan.
+fn valid(name: &str) -> bool {
+    !name.trim().is_empty() && !name.chars().any(char::is_control)
+}
+
+/// The stored name, or the default one.
+fn load() -> Name {
+    let mut buf = [0; 2 + NAME_MAX_LEN];
+    if let Err(e) = storage::read(Area::Name, &mut buf) {
+        warn!("[name] error reading the name sector: {:?}", e);
+    }
+    let [magic, len, ref name @ ..] = buf;
+    if magic != NAME_MAGIC {
+        return default_name();
+    }
+    name.get(..len as usize)
+        .and_then(|name| core::str::from_utf8(name).ok())
+        .filter(|name| valid(name))
+        .and_then(|name| Name::try_from(name).ok())
+        .unwrap_or_else(default_name)
+}
+
+fn store(name: &str) -> Result<(), storage::StorageError> {
+    let mut buf = [0xff; 2 + NAME_MAX_LEN];
+    buf[0] = NAME_MAGIC;
+    buf[1] = name.len() as u8;
+    buf[2..2 + name.len()].copy_from_slice(name.as_bytes());
+    storage::write(Area::Name, &buf)
+}
@@ -1,57 +1,389 @@
+use core::cell::Cell;
+use core::sync::atomic::Ordering;
+
 use crate::{
-    ble::gatt::Server,
-    shared::{ToBytes, SENSOR_CHANNEL},
+    ble::{
+        gatt::Server,
+        l2cap::StreamChannel,
+        link::LinkState,
+        pacing::{Pacer, ThroughputMeter},
+    },
+    error_log,
+    shared::{SensorData, SensorSubscriber, DROPPED_SAMPLES, SENSOR_CHANNEL, STREAM_MODE},
 };
-use defmt::{debug, error};
+use defmt::{debug, error, info, warn};
 
-use embassy_time::Timer;
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pubsub::WaitResult;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use heapless::Vec;
-use trouble_host::{gatt::GattConnection, PacketPool};
+use mpu_protocol::frame::{
+    CombinedSample, FrameHeader, FrameWriter, LegacyWriter, Sample, StreamId, FLAG_COMPRESSED,
+};
+use trouble_host::prelude::*;
+
+/// Size of the sensor stream characteristics, the largest attribute value ATT allows.
+pub const MAX_FRAME_LEN: usize = 512;
+
+/// How often to look for a stream mode change or a stream channel while no stream the client
+/// subscribed to carries the selected mode. Neither signals this task.
+const SUBSCRIPTION_RECHECK: Duration = Duration::from_millis(500);
+
+/// The stream characteristics the client of one connection subscribed to, tracked from its CCCD
+/// writes.
+pub struct Subscriptions {
+    accel: Cell<bool>,
+    gyro: Cell<bool>,
+    combined: Cell<bool>,
+    changed: Signal<NoopRawMutex, ()>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self {
+            accel: Cell::new(false),
+            gyro: Cell::new(false),
+            combined: Cell::new(false),
+            changed: Signal::new(),
+        }
+    }
+
+    /// Record a write to `handle` if it is the CCCD of a stream characteristic.
+    pub fn update(&self, server: &Server<'_>, handle: u16, data: &[u8]) {
+        let s = &server.imu_service;
+        let stream = [
+            (s.sensor_accel.cccd_handle, &self.accel),
+            (s.sensor_gyro.cccd_handle, &self.gyro),
+            (s.sensor_combined.cccd_handle, &self.combined),
+        ]
+        .into_iter()
+        .find_map(|(cccd, subscribed)| (cccd == Some(handle)).then_some(subscribed));
+        if let Some(subscribed) = stream {
+            // Bit 0 enables notifications.
+            let notify = data.first().is_some_and(|flags| flags & 0x01 != 0);
+            info!("[custom_task] stream CCCD {} notify: {}", handle, notify);
+            subscribed.set(notify);
+            self.changed.signal(());
+        }
+    }
+
+    /// Whether the client subscribed to a stream that carries the combined or the split frames.
+    fn carries(&self, combined: bool) -> bool {
+        if combined {
+            self.combined.get()
+        } else {
+            self.accel.get() || self.gyro.get()
+        }
+    }
+}
 
-pub async fn run_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+/// Largest frame that fits in a single notification on this connection.
+fn frame_len(att_mtu: u16) -> usize {
+    // A notification carries a 1 byte opcode and a 2 byte handle.
+    (att_mtu as usize).saturating_sub(3).min(MAX_FRAME_LEN)
+}
+
+/// Stream sensor frames to `conn`, paced to its connection interval, or over its stream channel
+/// while one is open.
+///
+/// Samples stay queued while the client has not subscribed to the streams of the selected mode,
+/// but only in this connection's place in `SENSOR_CHANNEL`: past its length (1 s at 100 Hz) the
+/// oldest are lost, and counted in `DROPPED_SAMPLES` when the stream starts.
+pub async fn run_task<C: Controller, P: PacketPool>(
+    stack: &Stack<'_, C, P>,
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    link: &LinkState,
+    stream: &StreamChannel<'_, P>,
+    subscriptions: &Subscriptions,
+) {
     let sensor_accel = &server.imu_service.sensor_accel;
     let sensor_gyro = &server.imu_service.sensor_gyro;
-    let mut buf: Vec<u8, 18> = Vec::new();
-    let mut accel_batch: Vec<u8, 110> = Vec::new();
-    let mut gyro_batch: Vec<u8, 110> = Vec::new();
+    let sensor_combined = &server.imu_service.sensor_combined;
+    let stream_stats = &server.imu_service.stream_stats;
+    let blocked_sends = || conn.raw().metrics(|m| m.blocked_sends);
+    let mut pacer = Pacer::new(blocked_sends());
+    let mut meter = ThroughputMeter::new();
+    let mut accel_frame: Vec<u8, MAX_FRAME_LEN> = Vec::new();
+    let mut gyro_frame: Vec<u8, MAX_FRAME_LEN> = Vec::new();
+    let mut att_mtu = 0;
+    let mut sequence: u16 = 0;
+    // Sample that had to start a new frame because its scale, sample rate or fields changed.
+    let mut pending: Option<SensorData> = None;
+    let Ok(mut queue) = SENSOR_CHANNEL.subscriber() else {
+        error!("[custom_task] no subscriber left for the sensor stream");
+        return;
+    };
     loop {
-        let mut count = 1;
-        accel_batch.clear();
-        gyro_batch.clear();
-        buf.clear();
-        let data = SENSOR_CHANNEL.receive().await;
-        data.write_to_vec(&mut buf);
-        debug!("[custom_task] notifying result");
-
-        //timestamp is at 12..16, accel data at 0..7 (including scale bit at 0), gyro data at 7..14( including scale bit at 7)
-        accel_batch.extend_from_slice(&buf[14..18]).ok();
-        accel_batch.extend_from_slice(&buf[0..7]).ok();
-        gyro_batch.extend_from_slice(&buf[14..18]).ok();
-        gyro_batch.extend_from_slice(&buf[7..14]).ok();
-        while count < 10 {
-            match SENSOR_CHANNEL.try_receive() {
-                Ok(data) => {
-                    buf.clear();
-                    data.write_to_vec(&mut buf);
-                    accel_batch.extend_from_slice(&buf[14..18]).ok();
-                    accel_batch.extend_from_slice(&buf[0..7]).ok();
-                    gyro_batch.extend_from_slice(&buf[14..18]).ok();
-                    gyro_batch.extend_from_slice(&buf[7..14]).ok();
-                    count += 1;
-                }
-                Err(_) => break, // Channel empty
+        while !stream.is_open().await && !subscriptions.carries(STREAM_MODE.lock().await.combined) {
+            select(
+                subscriptions.changed.wait(),
+                Timer::after(SUBSCRIPTION_RECHECK),
+            )
+            .await;
+        }
+        let first = match pending.take() {
+            Some(data) => data,
+            None => next_sample(&mut queue).await,
+        };
+        if let Some(interval) = link.conn_interval() {
+            pacer.set_conn_interval(interval);
+        }
+        // Waiting here, after the first sample arrived, lets the frames fill up.
+        pacer.ready().await;
+        // The central may exchange the MTU at any time after connecting.
+        if conn.raw().att_mtu() != att_mtu {
+            att_mtu = conn.raw().att_mtu();
+            info!(
+                "[custom_task] ATT MTU {}, sending frames of up to {} bytes",
+                att_mtu,
+                frame_len(att_mtu)
+            );
+        }
+        // The stream channel takes SDUs of up to MAX_FRAME_LEN, see `l2cap_task`.
+        let on_channel = stream.is_open().await;
+        let len = if on_channel {
+            MAX_FRAME_LEN
+        } else {
+            frame_len(att_mtu)
+        };
+        let mode = *STREAM_MODE.lock().await;
+        let flags = if mode.compressed { FLAG_COMPRESSED } else { 0 };
+        let combined_header = combined_header(&first, sequence, flags);
+        // A combined sample doesn't fit the minimum MTU, fall back to the split streams then.
+        let combined = mode.combined && combined_header.capacity(len) > 0;
+        // The stream channel only ever carries frames.
+        let legacy = !mode.uses_frames() && !on_channel;
+
+        accel_frame.resize_default(len).ok();
+        gyro_frame.resize_default(len).ok();
+        // The minimum ATT MTU of 23 leaves room for a header and the first sample, and the flags
+        // match the stream, so this can't fail.
+        let mut frames = if combined {
+            Frames::Combined(FrameWriter::new(&mut accel_frame, combined_header).unwrap())
+        } else if legacy {
+            Frames::Legacy {
+                accel: LegacyWriter::new(&mut accel_frame, first.accel_scale),
+                gyro: LegacyWriter::new(&mut gyro_frame, first.gyro_scale),
+            }
+        } else {
+            Frames::Split {
+                accel: FrameWriter::new(
+                    &mut accel_frame,
+                    header(StreamId::Accel, first.accel_scale, &first, sequence, flags),
+                )
+                .unwrap(),
+                gyro: FrameWriter::new(
+                    &mut gyro_frame,
+                    header(StreamId::Gyro, first.gyro_scale, &first, sequence, flags),
+                )
+                .unwrap(),
+            }
+        };
+        frames.push(&first);
+        // Compressed frames take as many samples as their deltas leave room for.
+        while let Some(data) = try_next_sample(&mut queue) {
+            if !same_frame(&first, &data) || !frames.push(&data) {
+                pending = Some(data);
+                break;
             }
         }
+        let samples = frames.sample_count();
+        debug!(
+            "[custom_task] notifying frame {} with {} samples",
+            sequence, samples
+        );
 
-        if sensor_accel.notify(conn, &accel_batch).await.is_err() {
-            error!("[custom_task] error notifying connection");
-            break;
+        let started = Instant::now();
+        let (first_len, gyro_len) = frames.finish();
+        accel_frame.truncate(first_len);
+        if let Some(len) = gyro_len {
+            gyro_frame.truncate(len);
+        }
+        let sent: &[&Vec<u8, MAX_FRAME_LEN>] = match gyro_len {
+            None => &[&accel_frame],
+            Some(_) => &[&accel_frame, &gyro_frame],
         };
-        if sensor_gyro.notify(conn, &gyro_batch).await.is_err() {
-            error!("[custom_task] error notifying connection");
-            break;
+        // Frames the stream channel took, the rest of the batch is notified.
+        let on_channel_sent = if on_channel {
+            stream.send(stack, sent).await
+        } else {
+            0
         };
-        //throttle notifications, or else will drop connection
-        Timer::after_millis(100).await;
+        let channel_failed = on_channel && on_channel_sent < sent.len();
+        let mut notifications = on_channel_sent as u8;
+        let mut bytes: usize = sent[..on_channel_sent]
+            .iter()
+            .map(|frame| frame.len())
+            .sum();
+        if !on_channel || channel_failed {
+            let characteristics: &[_] = match gyro_len {
+                None => &[(sensor_combined, &subscriptions.combined)],
+                Some(_) => &[
+                    (sensor_accel, &subscriptions.accel),
+                    (sensor_gyro, &subscriptions.gyro),
+                ],
+            };
+            for ((characteristic, subscribed), frame) in
+                characteristics.iter().zip(sent).skip(on_channel_sent)
+            {
+                // Frames built for the channel may be too long for a notification.
+                if !subscribed.get() || frame.len() > frame_len(att_mtu) {
+                    continue;
+                }
+                if characteristic.notify(conn, frame).await.is_err() {
+                    // The connection is most likely closing, which ends this task.
+                    error!("[custom_task] error notifying connection");
+                    error_log::record("stream notification failed");
+                    break;
+                }
+                notifications += 1;
+                bytes += frame.len();
+            }
+            if channel_failed && notifications == 0 {
+                warn!(
+                    "[custom_task] stream channel closed, dropping frame {}",
+                    sequence
+                );
+                DROPPED_SAMPLES.fetch_add(samples as u32, Ordering::Relaxed);
+            }
+        }
+        sequence = sequence.wrapping_add(1);
+
+        let blocked = blocked_sends();
+        let backlog = queue.available() > 0;
+        if on_channel && !channel_failed {
+            pacer.sent_on_channel(backlog);
+        } else {
+            pacer.sent(notifications, started.elapsed(), blocked, backlog);
+        }
+        // Nothing sent means the batch was dropped above, or the connection is closing.
+        let delivered = if notifications == 0 { 0 } else { samples };
+        meter.record(delivered, notifications as usize, bytes);
+
+        let dropped = DROPPED_SAMPLES.load(Ordering::Relaxed);
+        if let Some(stats) = meter.poll(&pacer, dropped, blocked) {
+            debug!("[custom_task] {:?}", stats);
+            if stream_stats.notify(conn, &stats.encode()).await.is_err() {
+                warn!("[custom_task] error notifying stream stats");
+            }
+        }
+    }
+}
+
+pub(super) async fn next_sample(samples: &mut SensorSubscriber) -> SensorData {
+    loop {
+        match samples.next_message().await {
+            WaitResult::Message(data) => return data,
+            WaitResult::Lagged(missed) => count_dropped(missed),
+        }
+    }
+}
+
+pub(super) fn try_next_sample(samples: &mut SensorSubscriber) -> Option<SensorData> {
+    loop {
+        match samples.try_next_message()? {
+            WaitResult::Message(data) => return Some(data),
+            WaitResult::Lagged(missed) => count_dropped(missed),
+        }
+    }
+}
+
+fn count_dropped(missed: u64) {
+    warn!("[custom_task] fell behind, {} samples dropped", missed);
+    DROPPED_SAMPLES.fetch_add(missed as u32, Ordering::Relaxed);
+}
+
+/// Whether `data` can share a frame with `first`.
+pub(crate) fn same_frame(first: &SensorData, data: &SensorData) -> bool {
+    data.accel_scale == first.accel_scale
+        && data.gyro_scale == first.gyro_scale
+        && data.sample_rate_hz == first.sample_rate_hz
+        && data.temperature.is_some() == first.temperature.is_some()
+}
+
+/// The frames filled from one batch of samples.
+// Lives on the task's stack for one batch, boxing the writers would only add heap churn.
+#[allow(clippy::large_enum_variant)]
+enum Frames<'a> {
+    Split {
+        accel: FrameWriter<'a, Sample>,
+        gyro: FrameWriter<'a, Sample>,
+    },
+    Combined(FrameWriter<'a, CombinedSample>),
+    /// The unversioned layout of stream mode 0.
+    Legacy {
+        accel: LegacyWriter<'a>,
+        gyro: LegacyWriter<'a>,
+    },
+}
+
+impl Frames<'_> {
+    /// Add `data` to the frames, returning false if it doesn't fit.
+    fn push(&mut self, data: &SensorData) -> bool {
+        match self {
+            Frames::Split { accel, gyro } => {
+                let (a, g) = (data.accel_sample(), data.gyro_sample());
+                // Both frames have to carry the same samples.
+                accel.fits(&a) && gyro.fits(&g) && accel.push(&a).is_ok() && gyro.push(&g).is_ok()
+            }
+            Frames::Combined(frame) => frame.push(&data.combined_sample()).is_ok(),
+            // Both buffers are the same size and the samples too, so both fit or neither does.
+            Frames::Legacy { accel, gyro } => {
+                accel.push(&data.accel_sample()).is_ok() && gyro.push(&data.gyro_sample()).is_ok()
+            }
+        }
+    }
+
+    fn sample_count(&self) -> usize {
+        match self {
+            Frames::Split { accel, .. } => accel.sample_count(),
+            Frames::Combined(frame) => frame.sample_count(),
+            Frames::Legacy { accel, .. } => accel.sample_count(),
+        }
+    }
+
+    /// Write the headers, returning the length of the first frame and of the gyro frame if any.
+    fn finish(self) -> (usize, Option<usize>) {
+        match self {
+            Frames::Split { accel, gyro } => (accel.finish(), Some(gyro.finish())),
+            Frames::Combined(frame) => (frame.finish(), None),
+            Frames::Legacy { accel, gyro } => (accel.finish(), Some(gyro.finish())),
+        }
+    }
+}
+
+pub(crate) fn combined_header(first: &SensorData, sequence: u16, flags: u8) -> FrameHeader {
+    header(
+        StreamId::Combined,
+        FrameHeader::combined_scale(first.accel_scale, first.gyro_scale),
+        first,
+        sequence,
+        flags | first.combined_sample().flags(),
+    )
+}
+
+fn header(
+    stream: StreamId,
+    scale: u8,
+    first: &SensorData,
+    sequence: u16,
+    flags: u8,
+) -> FrameHeader {
+    FrameHeader {
+        stream,
+        flags,
+        scale,
+        sample_count: 0,
+        sample_rate_hz: first.sample_rate_hz,
+        sequence,
     }
 }
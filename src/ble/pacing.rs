@@ -0,0 +1,149 @@
+//! Flow control for the sensor stream.
+//!
+//! Rather than sleeping a fixed time after every notification, the pacer keeps a budget of
+//! notifications per connection interval. The budget grows while the stack keeps up and halves as
+//! soon as a send had to wait, either for controller TX credits or for room in the host's
+//! outbound queue. Running the packet pool dry is what used to drop the connection.
+use embassy_time::{Duration, Instant, Timer};
+use mpu_protocol::stats::ThroughputStats;
+
+/// Connection interval assumed until the central updates the connection parameters.
+pub const DEFAULT_CONN_INTERVAL: Duration = Duration::from_millis(30);
+
+/// Kept below the host's TX queue (8) and packet pool (16) so incoming packets still fit.
+const MAX_BUDGET: u8 = 6;
+/// Sends in a row without back-pressure before the budget grows by one.
+const GROW_AFTER: u8 = 8;
+/// How long a partly filled frame may wait for more samples when nothing is queued.
+const IDLE_LINGER: Duration = Duration::from_millis(100);
+/// Window over which the throughput rates are averaged.
+const STATS_WINDOW: Duration = Duration::from_secs(1);
+
+pub struct Pacer {
+    conn_interval: Duration,
+    budget: u8,
+    clean_sends: u8,
+    blocked_sends: usize,
+    next_send: Instant,
+}
+
+impl Pacer {
+    pub fn new(blocked_sends: usize) -> Self {
+        Self {
+            conn_interval: DEFAULT_CONN_INTERVAL,
+            budget: 2,
+            clean_sends: 0,
+            blocked_sends,
+            next_send: Instant::now(),
+        }
+    }
+
+    pub fn set_conn_interval(&mut self, conn_interval: Duration) {
+        self.conn_interval = conn_interval;
+    }
+
+    /// Wait until the next batch may be sent.
+    pub async fn ready(&self) {
+        Timer::at(self.next_send).await;
+    }
+
+    /// Record a batch of `notifications` that took `elapsed` to hand to the stack.
+    ///
+    /// `blocked_sends` is the connection's running count of sends that waited for TX credits, and
+    /// `backlog` whether samples are still queued.
+    pub fn sent(
+        &mut self,
+        notifications: u8,
+        elapsed: Duration,
+        blocked_sends: usize,
+        backlog: bool,
+    ) {
+        let congested = blocked_sends != self.blocked_sends || elapsed > self.conn_interval;
+        self.blocked_sends = blocked_sends;
+        if congested {
+            self.budget = (self.budget / 2).max(1);
+            self.clean_sends = 0;
+        } else if self.budget < MAX_BUDGET {
+            self.clean_sends += 1;
+            if self.clean_sends >= GROW_AFTER {
+                self.budget += 1;
+                self.clean_sends = 0;
+            }
+        }
+
+        let now = Instant::now();
+        self.next_send = if backlog {
+            now + self.conn_interval * notifications as u32 / self.budget as u32
+        } else {
+            // Nothing waiting: let samples accumulate instead of sending near-empty frames.
+            now + IDLE_LINGER.max(self.conn_interval)
+        };
+    }
+
+    /// Record a batch sent over the L2CAP stream channel. Its credits already hold the sender
+    /// back, so only the wait for a partly filled frame applies.
+    pub fn sent_on_channel(&mut self, backlog: bool) {
+        let now = Instant::now();
+        self.next_send = if backlog {
+            now
+        } else {
+            now + IDLE_LINGER.max(self.conn_interval)
+        };
+    }
+}
+
+/// Turns what was sent into per-second rates.
+pub struct ThroughputMeter {
+    window_start: Instant,
+    samples: u32,
+    notifications: u32,
+    bytes: u32,
+}
+
+impl Default for ThroughputMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ThroughputMeter {
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            samples: 0,
+            notifications: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn record(&mut self, samples: usize, notifications: usize, bytes: usize) {
+        self.samples += samples as u32;
+        self.notifications += notifications as u32;
+        self.bytes += bytes as u32;
+    }
+
+    /// Returns the rates once per window.
+    pub fn poll(
+        &mut self,
+        pacer: &Pacer,
+        dropped_samples: u32,
+        blocked_sends: usize,
+    ) -> Option<ThroughputStats> {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < STATS_WINDOW {
+            return None;
+        }
+        let per_s = |count: u32| (count as u64 * 1000 / elapsed.as_millis().max(1)) as u32;
+        let stats = ThroughputStats {
+            samples_per_s: per_s(self.samples).min(u16::MAX as u32) as u16,
+            notifications_per_s: per_s(self.notifications).min(u16::MAX as u32) as u16,
+            bytes_per_s: per_s(self.bytes),
+            dropped_samples,
+            blocked_sends: blocked_sends as u32,
+            conn_interval_us: pacer.conn_interval.as_micros() as u32,
+            budget: pacer.budget,
+        };
+        *self = Self::new();
+        Some(stats)
+    }
+}
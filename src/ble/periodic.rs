@@ -0,0 +1,251 @@
+//! Periodic advertising: batched samples on a BLE 5 periodic advertising train, so any number of
+//! scanners synchronised to it receive the same samples without connecting.
+//!
+//! The train hangs off a second, extended advertising set next to the connectable one. Each
+//! periodic advertising event carries the samples since the one before as a compressed combined
+//! frame (see [`mpu_protocol::frame`]), in service data under the IMU service UUID.
+//! trouble-host doesn't wrap periodic advertising yet, so the train is driven with HCI commands.
+use core::cell::Cell;
+
+use bt_hci::cmd::le::{
+    LeClearAdvSets, LeReadNumberOfSupportedAdvSets, LeRemoveAdvSet, LeSetAdvSetRandomAddr,
+    LeSetExtAdvData, LeSetExtAdvEnable, LeSetExtAdvParams, LeSetExtScanResponseData,
+    LeSetPeriodicAdvData, LeSetPeriodicAdvEnable, LeSetPeriodicAdvParams,
+};
+use bt_hci::controller::ControllerCmdSync;
+use bt_hci::param::{AdvHandle, AdvSet, Duration, Operation, PeriodicAdvProps};
+use defmt::{debug, info, warn, Debug2Format};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use mpu_protocol::frame::{FrameWriter, FLAG_COMPRESSED};
+use trouble_host::prelude::*;
+
+use super::notify_task::{combined_header, next_sample, same_frame, try_next_sample};
+use crate::error_log;
+use crate::shared::{SensorData, SENSOR_CHANNEL};
+
+/// The extended advertising set the train belongs to. Set 0 is the connectable one.
+pub const PERIODIC_SET: AdvHandle = AdvHandle::new(1);
+
+/// Shortest periodic advertising interval accepted, so a frame holds a few samples.
+pub const PERIODIC_INTERVAL_MIN_MS: u16 = 20;
+
+/// Periodic advertising data sent in one HCI command.
+const PERIODIC_DATA_LEN: usize = 252;
+
+/// Service data AD structure header: length, type and the 128-bit UUID.
+const SERVICE_DATA_HEADER_LEN: usize = 2 + 16;
+const SERVICE_DATA_128: u8 = 0x21;
+/// UUID of the IMU service, little endian.
+const IMU_SERVICE_UUID: [u8; 16] = 0x12345678_1234_5678_1234_56789abcdef0u128.to_le_bytes();
+
+/// Controller commands for extended and periodic advertising.
+pub trait PeriodicController:
+    Controller
+    + for<'t> ControllerCmdSync<LeSetExtAdvData<'t>>
+    + ControllerCmdSync<LeClearAdvSets>
+    + ControllerCmdSync<LeSetExtAdvParams>
+    + ControllerCmdSync<LeSetAdvSetRandomAddr>
+    + ControllerCmdSync<LeReadNumberOfSupportedAdvSets>
+    + for<'t> ControllerCmdSync<LeSetExtAdvEnable<'t>>
+    + for<'t> ControllerCmdSync<LeSetExtScanResponseData<'t>>
+    + ControllerCmdSync<LeRemoveAdvSet>
+    + ControllerCmdSync<LeSetPeriodicAdvParams>
+    + for<'t> ControllerCmdSync<LeSetPeriodicAdvData<'t>>
+    + ControllerCmdSync<LeSetPeriodicAdvEnable>
+{
+}
+
+impl<C> PeriodicController for C where
+    C: Controller
+        + for<'t> ControllerCmdSync<LeSetExtAdvData<'t>>
+        + ControllerCmdSync<LeClearAdvSets>
+        + ControllerCmdSync<LeSetExtAdvParams>
+        + ControllerCmdSync<LeSetAdvSetRandomAddr>
+        + ControllerCmdSync<LeReadNumberOfSupportedAdvSets>
+        + for<'t> ControllerCmdSync<LeSetExtAdvEnable<'t>>
+        + for<'t> ControllerCmdSync<LeSetExtScanResponseData<'t>>
+        + ControllerCmdSync<LeRemoveAdvSet>
+        + ControllerCmdSync<LeSetPeriodicAdvParams>
+        + for<'t> ControllerCmdSync<LeSetPeriodicAdvData<'t>>
+        + ControllerCmdSync<LeSetPeriodicAdvEnable>
+{
+}
+
+/// The periodic advertising train, started and stopped by the advertiser and filled by
+/// [`periodic_task`].
+pub struct Train {
+    /// Interval of the running train in milliseconds, 0 while it is off.
+    interval_ms: Cell<u16>,
+    started: Signal<NoopRawMutex, ()>,
+}
+
+impl Train {
+    pub fn new() -> Self {
+        Self {
+            interval_ms: Cell::new(0),
+            started: Signal::new(),
+        }
+    }
+
+    /// Whether the advertising sets should include [`PERIODIC_SET`].
+    pub fn wanted(interval_ms: u16) -> bool {
+        interval_ms != 0
+    }
+
+    /// Get the sets ready to be advertised again with a train of `interval_ms` (0 for none).
+    /// The extended set may still be advertising after a connection, and its parameters can only
+    /// change while it is off.
+    pub async fn prepare<C: PeriodicController, P: PacketPool>(
+        &self,
+        stack: &Stack<'_, C, P>,
+        interval_ms: u16,
+    ) {
+        let running = self.interval_ms.get();
+        if running == 0 {
+            return;
+        }
+        let set = [AdvSet {
+            adv_handle: PERIODIC_SET,
+            duration: Duration::from_u16(0),
+            max_ext_adv_events: 0,
+        }];
+        if let Err(e) = stack.command(LeSetExtAdvEnable::new(false, &set)).await {
+            debug!("[periodic] error pausing the set: {:?}", Debug2Format(&e));
+        }
+        if running != interval_ms {
+            self.stop(stack, interval_ms == 0).await;
+        }
+    }
+
+    /// Start the train on [`PERIODIC_SET`], once the extended advertising set exists.
+    pub async fn start<C: PeriodicController, P: PacketPool>(
+        &self,
+        stack: &Stack<'_, C, P>,
+        interval_ms: u16,
+    ) {
+        if interval_ms == 0 || self.interval_ms.get() == interval_ms {
+            return;
+        }
+        let interval = Duration::from_millis(interval_ms as u32);
+        let started = async {
+            stack
+                .command(LeSetPeriodicAdvParams::new(
+                    PERIODIC_SET,
+                    interval,
+                    interval,
+                    PeriodicAdvProps::new(),
+                ))
+                .await?;
+            stack
+                .command(LeSetPeriodicAdvEnable::new(true, PERIODIC_SET))
+                .await
+        };
+        match started.await {
+            Ok(()) => {
+                info!("[periodic] train started, every {} ms", interval_ms);
+                self.interval_ms.set(interval_ms);
+                self.started.signal(());
+            }
+            Err(e) => {
+                warn!(
+                    "[periodic] error starting the train: {:?}",
+                    Debug2Format(&e)
+                );
+                error_log::record("starting periodic advertising failed");
+            }
+        }
+    }
+
+    async fn stop<C: PeriodicController, P: PacketPool>(
+        &self,
+        stack: &Stack<'_, C, P>,
+        remove: bool,
+    ) {
+        self.interval_ms.set(0);
+        if let Err(e) = stack
+            .command(LeSetPeriodicAdvEnable::new(false, PERIODIC_SET))
+            .await
+        {
+            warn!(
+                "[periodic] error stopping the train: {:?}",
+                Debug2Format(&e)
+            );
+        }
+        if remove {
+            if let Err(e) = stack.command(LeRemoveAdvSet::new(PERIODIC_SET)).await {
+                warn!("[periodic] error removing the set: {:?}", Debug2Format(&e));
+            }
+            info!("[periodic] train stopped");
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.interval_ms.get() != 0
+    }
+
+    async fn wait_running(&self) {
+        while !self.running() {
+            self.started.wait().await;
+        }
+    }
+}
+
+impl Default for Train {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fill the train with the samples taken since the previous periodic advertising event.
+pub async fn periodic_task<C: PeriodicController, P: PacketPool>(
+    stack: &Stack<'_, C, P>,
+    train: &Train,
+) {
+    let mut data = [0; PERIODIC_DATA_LEN];
+    data[1] = SERVICE_DATA_128;
+    data[2..SERVICE_DATA_HEADER_LEN].copy_from_slice(&IMU_SERVICE_UUID);
+    let mut sequence: u16 = 0;
+    loop {
+        train.wait_running().await;
+        // Only subscribed while the train runs, so no samples pile up meanwhile.
+        let Ok(mut queue) = SENSOR_CHANNEL.subscriber() else {
+            warn!("[periodic] no subscriber left for the sensor stream");
+            return;
+        };
+        let mut pending: Option<SensorData> = None;
+        while train.running() {
+            let first = match pending.take() {
+                Some(first) => first,
+                None => next_sample(&mut queue).await,
+            };
+            // Collect what arrives until the next event.
+            Timer::after_millis(train.interval_ms.get() as u64).await;
+            let header = combined_header(&first, sequence, FLAG_COMPRESSED);
+            // The header and a keyframe always fit.
+            let mut frame = FrameWriter::new(&mut data[SERVICE_DATA_HEADER_LEN..], header).unwrap();
+            frame.push(&first.combined_sample()).ok();
+            while let Some(sample) = try_next_sample(&mut queue) {
+                if !same_frame(&first, &sample) || frame.push(&sample.combined_sample()).is_err() {
+                    pending = Some(sample);
+                    break;
+                }
+            }
+            let len = SERVICE_DATA_HEADER_LEN + frame.finish();
+            data[0] = (len - 1) as u8;
+            sequence = sequence.wrapping_add(1);
+            if let Err(e) = stack
+                .command(LeSetPeriodicAdvData::new(
+                    PERIODIC_SET,
+                    Operation::Complete,
+                    &data[..len],
+                ))
+                .await
+            {
+                // The set is briefly gone while advertising restarts.
+                debug!("[periodic] error setting the data: {:?}", Debug2Format(&e));
+            }
+        }
+    }
+}
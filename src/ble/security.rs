@@ -0,0 +1,376 @@
+//! LE Secure Connections pairing and bonding, and the security mode that decides which writes
+//! need an encrypted link.
+//!
+//! Pairing is started by the central, usually when a write is rejected for insufficient
+//! encryption. trouble-host only offers Just Works pairing. Bonds and the mode are kept in the
+//! [`Area::Security`] flash sector, so bonded centrals reconnect encrypted without pairing again.
+//!
+//! In [`SecurityMode::AllowList`] the controller only accepts connections from bonded centrals,
+//! except during a pairing window opened with the button or the console.
+use bt_hci::cmd::le::{
+    LeAddDeviceToFilterAcceptList, LeAddDeviceToResolvingList, LeClearFilterAcceptList,
+    LeClearResolvingList, LeReadFilterAcceptListSize, LeReadResolvingListSize,
+    LeSetAddrResolutionEnable,
+};
+use bt_hci::controller::ControllerCmdSync;
+use defmt::{info, warn, Debug2Format, Format};
+use embassy_time::Duration;
+use heapless::Vec;
+use mpu_protocol::console::{Setting, Value};
+use trouble_host::prelude::*;
+use trouble_host::{BondInformation, IdentityResolvingKey, LongTermKey};
+
+use super::gatt::Server;
+use super::name::DeviceName;
+use super::settings;
+use crate::error_log;
+use crate::storage::{self, Area, StorageError};
+
+/// Most bonds kept, the oldest is forgotten to make room for a new one.
+pub const BONDS_MAX: usize = 8;
+
+/// How long a pairing window lets any central connect in [`SecurityMode::AllowList`].
+pub const PAIRING_WINDOW_DURATION: Duration = Duration::from_secs(60);
+
+/// Controller commands for the filter accept list.
+pub trait SecurityController:
+    Controller
+    + ControllerCmdSync<LeReadFilterAcceptListSize>
+    + ControllerCmdSync<LeReadResolvingListSize>
+    + ControllerCmdSync<LeClearFilterAcceptList>
+    + ControllerCmdSync<LeAddDeviceToFilterAcceptList>
+    + ControllerCmdSync<LeClearResolvingList>
+    + ControllerCmdSync<LeAddDeviceToResolvingList>
+    + ControllerCmdSync<LeSetAddrResolutionEnable>
+{
+}
+
+impl<C> SecurityController for C where
+    C: Controller
+        + ControllerCmdSync<LeReadFilterAcceptListSize>
+        + ControllerCmdSync<LeReadResolvingListSize>
+        + ControllerCmdSync<LeClearFilterAcceptList>
+        + ControllerCmdSync<LeAddDeviceToFilterAcceptList>
+        + ControllerCmdSync<LeClearResolvingList>
+        + ControllerCmdSync<LeAddDeviceToResolvingList>
+        + ControllerCmdSync<LeSetAddrResolutionEnable>
+{
+}
+
+/// Which writes need an encrypted link. Reads and subscriptions are always open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum SecurityMode {
+    /// Anyone in range may change settings, pairing is optional.
+    #[default]
+    Open = 0,
+    /// Settings, config packets, console input and read triggers need an encrypted link, so the
+    /// central has to pair (or be bonded) first.
+    Encrypted = 1,
+    /// Like [`SecurityMode::Encrypted`], and only bonded centrals may connect outside a pairing
+    /// window.
+    AllowList = 2,
+}
+
+impl SecurityMode {
+    pub fn from_u8(value: u8) -> Option<SecurityMode> {
+        match value {
+            0 => Some(SecurityMode::Open),
+            1 => Some(SecurityMode::Encrypted),
+            2 => Some(SecurityMode::AllowList),
+            _ => None,
+        }
+    }
+}
+
+/// Load the bonds kept in flash into `stack`, and return the security mode kept with them.
+pub fn restore<C: Controller, P: PacketPool>(stack: &Stack<'_, C, P>) -> SecurityMode {
+    let record = Record::load();
+    for bond in record.bonds.iter() {
+        if let Err(e) = stack.add_bond_information(bond.info.clone()) {
+            warn!("[security] error restoring bond: {:?}", e);
+        }
+    }
+    info!(
+        "[security] mode {:?}, {} bonds",
+        record.mode,
+        record.bonds.len()
+    );
+    record.mode
+}
+
+/// Keep the bond of a central that just paired, replacing any older bond with it.
+pub fn store_bond(bond: &BondInformation) {
+    let mut record = Record::load();
+    let address = bond.identity.bd_addr;
+    record.bonds.retain(|b| b.info.identity.bd_addr != address);
+    if record.bonds.is_full() {
+        record.bonds.remove(0);
+    }
+    record
+        .bonds
+        .push(Bond {
+            info: bond.clone(),
+            kind: identity_kind(&address),
+        })
+        .ok();
+    if let Err(e) = record.save() {
+        warn!("[security] error storing bond: {:?}", e);
+        error_log::record("storing bond failed");
+    }
+}
+
+/// Keep `mode` for the next boot.
+pub fn store_mode(mode: SecurityMode) -> Result<(), StorageError> {
+    let mut record = Record::load();
+    if record.mode == mode {
+        return Ok(());
+    }
+    record.mode = mode;
+    record.save()
+}
+
+/// The security mode in effect.
+fn mode(server: &Server<'_>) -> SecurityMode {
+    match settings::get(server, Setting::Security) {
+        Some(Value::U8(mode)) => SecurityMode::from_u8(mode).unwrap_or_default(),
+        _ => SecurityMode::Open,
+    }
+}
+
+/// Whether the client on `conn` may write to `handle` in the current security mode.
+pub fn write_allowed<P: PacketPool>(
+    server: &Server<'_>,
+    device_name: &DeviceName,
+    conn: &GattConnection<'_, '_, P>,
+    handle: u16,
+) -> bool {
+    mode(server) == SecurityMode::Open
+        || conn.raw().encrypted()
+        || !is_control(server, device_name, handle)
+}
+
+/// The filter policy to advertise with. In [`SecurityMode::AllowList`] this loads the bonded
+/// centrals into the controller's filter accept list, and their IRKs into its resolving list so
+/// phones using private addresses are recognised.
+///
+/// Connections stay filtered if the lists cannot be loaded, a pairing window still lets centrals
+/// in.
+pub async fn advertising_filter<C: SecurityController, P: PacketPool>(
+    stack: &Stack<'_, C, P>,
+    server: &Server<'_>,
+    pairing_window: bool,
+) -> AdvFilterPolicy {
+    if pairing_window || mode(server) != SecurityMode::AllowList {
+        return AdvFilterPolicy::Unfiltered;
+    }
+    if let Err(e) = load_accept_list(stack).await {
+        warn!(
+            "[security] error loading the filter accept list: {:?}",
+            Debug2Format(&e)
+        );
+        error_log::record("loading filter accept list failed");
+    }
+    AdvFilterPolicy::FilterConn
+}
+
+/// Load the bonds into the controller's lists, newest first, as far as they fit.
+async fn load_accept_list<C: SecurityController, P: PacketPool>(
+    stack: &Stack<'_, C, P>,
+) -> Result<(), BleHostError<C::Error>> {
+    let accept_list_size = stack.command(LeReadFilterAcceptListSize::new()).await? as usize;
+    let resolving_list_size = stack.command(LeReadResolvingListSize::new()).await? as usize;
+    // The resolving list can only change while address resolution is off.
+    stack.command(LeSetAddrResolutionEnable::new(false)).await?;
+    stack.command(LeClearFilterAcceptList::new()).await?;
+    stack.command(LeClearResolvingList::new()).await?;
+    let (mut accepted, mut resolved, mut left_out) = (0, 0, 0);
+    for bond in Record::load().bonds.iter().rev() {
+        let entries = bond.kinds().count();
+        let irk = bond.info.identity.irk;
+        if accepted + entries > accept_list_size
+            || (irk.is_some() && resolved + entries > resolving_list_size)
+        {
+            left_out += 1;
+            continue;
+        }
+        let address = bond.info.identity.bd_addr;
+        for kind in bond.kinds() {
+            stack
+                .command(LeAddDeviceToFilterAcceptList::new(kind, address))
+                .await?;
+            if let Some(irk) = irk {
+                stack
+                    .command(LeAddDeviceToResolvingList::new(
+                        kind,
+                        address,
+                        irk.0.to_le_bytes(),
+                        [0; 16],
+                    ))
+                    .await?;
+            }
+        }
+        accepted += entries;
+        if irk.is_some() {
+            resolved += entries;
+        }
+    }
+    stack.command(LeSetAddrResolutionEnable::new(true)).await?;
+    if left_out > 0 {
+        warn!(
+            "[security] {} bonds don't fit the controller's lists ({} and {} entries)",
+            left_out, accept_list_size, resolving_list_size
+        );
+        error_log::record("bonds left out of the filter accept list");
+    }
+    Ok(())
+}
+
+/// The type of the identity address `address`, if the address tells.
+///
+/// trouble-host does not pass on the type from the central's Identity Address Information. A
+/// random static address always has its two most significant bits set, so any other address is
+/// public; public addresses of OUIs from `0xC0` up look the same as random static ones.
+fn identity_kind(address: &BdAddr) -> Option<AddrKind> {
+    (address.raw()[5] & 0xc0 != 0xc0).then_some(AddrKind::PUBLIC)
+}
+
+/// Whether `handle` is the value of a characteristic that changes what the device does. CCCDs are
+/// not, subscribing stays open.
+fn is_control(server: &Server<'_>, device_name: &DeviceName, handle: u16) -> bool {
+    let s = &server.imu_service;
+    settings::setting_for_handle(server, handle).is_some()
+        || device_name.handles(server, handle)
+        || [
+            s.read.handle,
+            s.mark_epoch.handle,
+            s.config_packet.handle,
+            server.console_service.rx.handle,
+            server.dfu_service.control.handle,
+            server.dfu_service.data.handle,
+            server.log_service.control.handle,
+        ]
+        .contains(&handle)
+}
+
+/// A bond with the type of the central's identity address, `None` if it isn't known.
+struct Bond {
+    info: BondInformation,
+    kind: Option<AddrKind>,
+}
+
+impl Bond {
+    /// The address types to list the bond under: its own, or both if it isn't known.
+    fn kinds(&self) -> impl Iterator<Item = AddrKind> {
+        let (first, second) = match self.kind {
+            Some(kind) => (kind, None),
+            None => (AddrKind::PUBLIC, Some(AddrKind::RANDOM)),
+        };
+        core::iter::once(first).chain(second)
+    }
+}
+
+/// The contents of the security flash sector.
+///
+/// | offset | size | field                                                                |
+/// |--------|------|----------------------------------------------------------------------|
+/// | 0      | 1    | magic, `0x5e`                                                        |
+/// | 1      | 1    | version, currently 1                                                 |
+/// | 2      | 1    | security mode                                                        |
+/// | 3      | 1    | bond count                                                           |
+/// | 4      | 40   | per bond: address, address type, IRK flag, IRK, LTK (little endian) |
+///
+/// The address type is `0` public, `1` random or `0xff` unknown.
+struct Record {
+    mode: SecurityMode,
+    bonds: Vec<Bond, BONDS_MAX>,
+}
+
+impl Record {
+    const MAGIC: u8 = 0x5e;
+    const VERSION: u8 = 1;
+    const HEADER_LEN: usize = 4;
+    const BOND_LEN: usize = 6 + 1 + 1 + 16 + 16;
+    const ENCODED_LEN: usize = Self::HEADER_LEN + BONDS_MAX * Self::BOND_LEN;
+    const UNKNOWN_KIND: u8 = 0xff;
+
+    /// The stored record, or the defaults if there is none yet.
+    fn load() -> Record {
+        let mut buf = [0; Self::ENCODED_LEN];
+        if let Err(e) = storage::read(Area::Security, &mut buf) {
+            warn!("[security] error reading the security sector: {:?}", e);
+        }
+        Self::decode(&buf).unwrap_or(Record {
+            mode: SecurityMode::default(),
+            bonds: Vec::new(),
+        })
+    }
+
+    fn save(&self) -> Result<(), StorageError> {
+        storage::write(Area::Security, &self.encode())
+    }
+
+    fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut out = [0xff; Self::ENCODED_LEN];
+        out[..Self::HEADER_LEN].copy_from_slice(&[
+            Self::MAGIC,
+            Self::VERSION,
+            self.mode as u8,
+            self.bonds.len() as u8,
+        ]);
+        for (bond, out) in self.bonds.iter().zip(
+            out[Self::HEADER_LEN..]
+                .as_chunks_mut::<{ Self::BOND_LEN }>()
+                .0,
+        ) {
+            let irk = bond.info.identity.irk.map(|irk| irk.0);
+            out[..6].copy_from_slice(bond.info.identity.bd_addr.raw());
+            out[6] = bond
+                .kind
+                .map_or(Self::UNKNOWN_KIND, |kind| kind.into_inner());
+            out[7] = irk.is_some() as u8;
+            out[8..24].copy_from_slice(&irk.unwrap_or(0).to_le_bytes());
+            out[24..].copy_from_slice(&bond.info.ltk.to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(buf: &[u8; Self::ENCODED_LEN]) -> Option<Record> {
+        let [magic, version, mode, count] = buf[..Self::HEADER_LEN] else {
+            return None;
+        };
+        if magic != Self::MAGIC || version != Self::VERSION {
+            return None;
+        }
+        let mut bonds = Vec::new();
+        for bond in buf[Self::HEADER_LEN..]
+            .as_chunks::<{ Self::BOND_LEN }>()
+            .0
+            .iter()
+            .take(count as usize)
+        {
+            let address: [u8; 6] = bond[..6].try_into().ok()?;
+            let kind = match bond[6] {
+                0 => Some(AddrKind::PUBLIC),
+                1 => Some(AddrKind::RANDOM),
+                _ => None,
+            };
+            let irk = u128::from_le_bytes(bond[8..24].try_into().ok()?);
+            let ltk = u128::from_le_bytes(bond[24..].try_into().ok()?);
+            let identity = Identity {
+                bd_addr: BdAddr::new(address),
+                irk: (bond[7] == 1).then(|| IdentityResolvingKey::new(irk)),
+            };
+            bonds
+                .push(Bond {
+                    info: BondInformation::new(identity, LongTermKey::new(ltk)),
+                    kind,
+                })
+                .ok()?;
+        }
+        Some(Record {
+            mode: SecurityMode::from_u8(mode)?,
+            bonds,
+        })
+    }
+}
@@ -0,0 +1,324 @@
+//! The one place settings are applied, whether they were written to their typed characteristic or
+//! set from the console.
+//!
+//! Every change that reaches a characteristic, from any source, is notified to each connection
+//! that subscribed to it.
+use defmt::{info, warn, Format};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, WaitResult};
+use mpu6050_dmp::accel::AccelFullScale;
+use mpu6050_dmp::config::DigitalLowPassFilter;
+use mpu6050_dmp::gyro::GyroFullScale;
+use mpu_protocol::config::{BuzzFrequencyMode, ConfigPacket};
+use mpu_protocol::console::{Setting, Value};
+use mpu_protocol::frame::StreamMode;
+use mpu_protocol::pre_trigger::{self, PRE_TRIGGER_MAX_MS};
+use trouble_host::prelude::*;
+
+use super::broadcast::BROADCAST_INTERVAL_MIN_MS;
+use super::gatt::Server;
+use super::periodic::PERIODIC_INTERVAL_MIN_MS;
+use super::security::{self, SecurityMode};
+use super::CONNECTIONS_MAX;
+use crate::error_log;
+use crate::sensor::config::{
+    AccelFullScaleFromU8, DigitalLowPassFilterFromU8, GyroFullScaleFromU8,
+};
+use crate::shared::{
+    ACCEL_SCALE, BATTERY_DIVIDER, BROADCAST_INTERVAL_MS, BUZZ_FREQUENCY_MODE,
+    CONTINUOUS_SAMPLE_INTERVAL_MS, FILTER, GYRO_SCALE, MAX_BUZZ_VALUE, MIN_BUZZ_VALUE,
+    MOTION_DETECTION, MOTION_READ_DURATION_S, MOTION_SAMPLE_INTERVAL_MS, PERIODIC_INTERVAL_MS,
+    PLAY_SOUND, PRE_TRIGGER_MS, RECORDING, SENSOR_CONFIG, STREAM_MODE,
+};
+
+/// Settings whose characteristic value changed, one subscriber per connection.
+static CHANGES: PubSubChannel<CriticalSectionRawMutex, Setting, 16, CONNECTIONS_MAX, 0> =
+    PubSubChannel::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum SettingError {
+    /// The value has the wrong type or is out of range for the setting.
+    InvalidValue,
+    /// The quaternion comes from the DMP, which is not enabled.
+    QuaternionNeedsDmp,
+}
+
+impl SettingError {
+    pub const fn message(self) -> &'static str {
+        match self {
+            SettingError::InvalidValue => "invalid value",
+            SettingError::QuaternionNeedsDmp => "the quaternion stream needs the DMP, which is off",
+        }
+    }
+}
+
+/// The setting whose characteristic has `handle`.
+pub fn setting_for_handle(server: &Server<'_>, handle: u16) -> Option<Setting> {
+    let s = &server.imu_service;
+    Setting::ALL.into_iter().find(|setting| {
+        handle
+            == match setting {
+                Setting::MotionReadDuration => s.motion_read_duration.handle,
+                Setting::MotionSampleInterval => s.motion_sample_interval.handle,
+                Setting::ContinuousSampleInterval => s.continuous_sample_interval.handle,
+                Setting::PlaySound => s.play_sound.handle,
+                Setting::AccelScale => s.accel_scale.handle,
+                Setting::GyroScale => s.gyro_scale.handle,
+                Setting::BuzzFrequencyMode => s.buzz_frequency_mode.handle,
+                Setting::MinBuzzValue => s.min_buzz_value.handle,
+                Setting::MaxBuzzValue => s.max_buzz_value.handle,
+                Setting::Filter => s.digital_low_pass_filter.handle,
+                Setting::MotionDetection => s.motion_detection.handle,
+                Setting::StreamMode => s.stream_mode.handle,
+                Setting::Security => s.security.handle,
+                Setting::BatteryDivider => s.battery_divider.handle,
+                Setting::BroadcastInterval => s.broadcast_interval.handle,
+                Setting::PeriodicInterval => s.periodic_interval.handle,
+                Setting::Recording => s.recording.handle,
+                Setting::PreTrigger => s.pre_trigger.handle,
+            }
+    })
+}
+
+/// The value of `setting`, as last written to its characteristic.
+pub fn get(server: &Server<'_>, setting: Setting) -> Option<Value> {
+    let s = &server.imu_service;
+    let value = match setting {
+        Setting::MotionReadDuration => Value::U16(s.motion_read_duration.get(server).ok()?),
+        Setting::MotionSampleInterval => Value::U64(s.motion_sample_interval.get(server).ok()?),
+        Setting::ContinuousSampleInterval => {
+            Value::U64(s.continuous_sample_interval.get(server).ok()?)
+        }
+        Setting::PlaySound => Value::Bool(s.play_sound.get(server).ok()?),
+        Setting::AccelScale => Value::U8(s.accel_scale.get(server).ok()?),
+        Setting::GyroScale => Value::U8(s.gyro_scale.get(server).ok()?),
+        Setting::BuzzFrequencyMode => Value::U8(s.buzz_frequency_mode.get(server).ok()?),
+        Setting::MinBuzzValue => Value::F32(s.min_buzz_value.get(server).ok()?),
+        Setting::MaxBuzzValue => Value::F32(s.max_buzz_value.get(server).ok()?),
+        Setting::Filter => Value::U8(s.digital_low_pass_filter.get(server).ok()?),
+        Setting::MotionDetection => Value::Bool(s.motion_detection.get(server).ok()?),
+        Setting::StreamMode => Value::U8(s.stream_mode.get(server).ok()?),
+        Setting::Security => Value::U8(s.security.get(server).ok()?),
+        Setting::BatteryDivider => Value::F32(s.battery_divider.get(server).ok()?),
+        Setting::BroadcastInterval => Value::U16(s.broadcast_interval.get(server).ok()?),
+        Setting::PeriodicInterval => Value::U16(s.periodic_interval.get(server).ok()?),
+        Setting::Recording => Value::Bool(s.recording.get(server).ok()?),
+        Setting::PreTrigger => Value::U16(s.pre_trigger.get(server).ok()?),
+    };
+    Some(value)
+}
+
+/// Validate `value` and pass it on to the task that uses `setting`, then store it in the
+/// characteristic so reads return it.
+pub async fn apply(
+    server: &Server<'_>,
+    setting: Setting,
+    value: Value,
+) -> Result<(), SettingError> {
+    match (setting, value) {
+        (Setting::MotionReadDuration, Value::U16(v)) => {
+            info!("motion_read_duration: {}", v);
+            *MOTION_READ_DURATION_S.lock().await = v;
+        }
+        (Setting::MotionSampleInterval, Value::U64(v)) => {
+            // The pre-trigger buffer has to keep up with the new rate.
+            if !pre_trigger::holds(*PRE_TRIGGER_MS.lock().await, v) {
+                return Err(SettingError::InvalidValue);
+            }
+            *MOTION_SAMPLE_INTERVAL_MS.lock().await = v;
+        }
+        (Setting::ContinuousSampleInterval, Value::U64(v)) => {
+            *CONTINUOUS_SAMPLE_INTERVAL_MS.lock().await = v;
+        }
+        (Setting::PlaySound, Value::Bool(v)) => PLAY_SOUND.signal(v),
+        (Setting::AccelScale, Value::U8(v)) => {
+            ACCEL_SCALE.signal(AccelFullScale::from_u8(v).ok_or(SettingError::InvalidValue)?)
+        }
+        (Setting::GyroScale, Value::U8(v)) => {
+            GYRO_SCALE.signal(GyroFullScale::from_u8(v).ok_or(SettingError::InvalidValue)?)
+        }
+        (Setting::BuzzFrequencyMode, Value::U8(v)) => BUZZ_FREQUENCY_MODE
+            .signal(BuzzFrequencyMode::from_u8(v).ok_or(SettingError::InvalidValue)?),
+        (Setting::MinBuzzValue, Value::F32(v)) => MIN_BUZZ_VALUE.signal(v),
+        (Setting::MaxBuzzValue, Value::F32(v)) => MAX_BUZZ_VALUE.signal(v),
+        (Setting::Filter, Value::U8(v)) => {
+            FILTER.signal(DigitalLowPassFilter::from_u8(v).ok_or(SettingError::InvalidValue)?)
+        }
+        (Setting::MotionDetection, Value::Bool(v)) => MOTION_DETECTION.signal(v),
+        (Setting::StreamMode, Value::U8(v)) => {
+            let mode = StreamMode::from_u8(v).ok_or(SettingError::InvalidValue)?;
+            if mode.quaternion {
+                return Err(SettingError::QuaternionNeedsDmp);
+            }
+            info!("stream_mode: {:?}", mode);
+            *STREAM_MODE.lock().await = mode;
+        }
+        (Setting::Security, Value::U8(v)) => {
+            let mode = SecurityMode::from_u8(v).ok_or(SettingError::InvalidValue)?;
+            info!("security: {:?}", mode);
+            // Still applies until the next boot if it cannot be kept.
+            if let Err(e) = security::store_mode(mode) {
+                warn!("[settings] error storing the security mode: {:?}", e);
+                error_log::record("storing security mode failed");
+            }
+        }
+        (Setting::BatteryDivider, Value::F32(v)) => {
+            if !(v >= 1.0 && v.is_finite()) {
+                return Err(SettingError::InvalidValue);
+            }
+            *BATTERY_DIVIDER.lock().await = v;
+        }
+        (Setting::BroadcastInterval, Value::U16(v)) => {
+            if v != 0 && v < BROADCAST_INTERVAL_MIN_MS {
+                return Err(SettingError::InvalidValue);
+            }
+            info!("broadcast_interval: {}", v);
+            BROADCAST_INTERVAL_MS.sender().send(v);
+        }
+        (Setting::PeriodicInterval, Value::U16(v)) => {
+            if v != 0 && v < PERIODIC_INTERVAL_MIN_MS {
+                return Err(SettingError::InvalidValue);
+            }
+            info!("periodic_interval: {}", v);
+            PERIODIC_INTERVAL_MS.sender().send(v);
+        }
+        (Setting::Recording, Value::Bool(v)) => {
+            info!("recording: {}", v);
+            *RECORDING.lock().await = v;
+        }
+        (Setting::PreTrigger, Value::U16(v)) => {
+            if v > PRE_TRIGGER_MAX_MS
+                || !pre_trigger::holds(v, *MOTION_SAMPLE_INTERVAL_MS.lock().await)
+            {
+                return Err(SettingError::InvalidValue);
+            }
+            info!("pre_trigger: {}", v);
+            *PRE_TRIGGER_MS.lock().await = v;
+        }
+        _ => return Err(SettingError::InvalidValue),
+    }
+    store(server, setting, value);
+    Ok(())
+}
+
+/// Write the sensor settings in effect back to their characteristics whenever they change, so
+/// reads return what the sensor uses rather than what was last written.
+pub async fn sync_task(server: &Server<'_>) {
+    let Some(mut receiver) = SENSOR_CONFIG.receiver() else {
+        warn!("[settings] no receiver left for the sensor config");
+        return;
+    };
+    loop {
+        let config = ConfigPacket::from(receiver.changed().await);
+        for (setting, value) in [
+            (Setting::AccelScale, Value::U8(config.accel_scale)),
+            (Setting::GyroScale, Value::U8(config.gyro_scale)),
+            (
+                Setting::BuzzFrequencyMode,
+                Value::U8(config.buzz_frequency_mode),
+            ),
+            (Setting::Filter, Value::U8(config.filter)),
+            (
+                Setting::MotionDetection,
+                Value::Bool(config.motion_detection),
+            ),
+        ] {
+            store(server, setting, value);
+        }
+    }
+}
+
+/// Store `value` in the characteristic of `setting`, without applying it, and announce the change
+/// to the connections. Values of the wrong type are ignored.
+pub fn store(server: &Server<'_>, setting: Setting, value: Value) {
+    if get(server, setting) == Some(value) {
+        return;
+    }
+    let s = &server.imu_service;
+    let stored = match (setting, value) {
+        (Setting::MotionReadDuration, Value::U16(v)) => s.motion_read_duration.set(server, &v),
+        (Setting::MotionSampleInterval, Value::U64(v)) => s.motion_sample_interval.set(server, &v),
+        (Setting::ContinuousSampleInterval, Value::U64(v)) => {
+            s.continuous_sample_interval.set(server, &v)
+        }
+        (Setting::PlaySound, Value::Bool(v)) => s.play_sound.set(server, &v),
+        (Setting::AccelScale, Value::U8(v)) => s.accel_scale.set(server, &v),
+        (Setting::GyroScale, Value::U8(v)) => s.gyro_scale.set(server, &v),
+        (Setting::BuzzFrequencyMode, Value::U8(v)) => s.buzz_frequency_mode.set(server, &v),
+        (Setting::MinBuzzValue, Value::F32(v)) => s.min_buzz_value.set(server, &v),
+        (Setting::MaxBuzzValue, Value::F32(v)) => s.max_buzz_value.set(server, &v),
+        (Setting::Filter, Value::U8(v)) => s.digital_low_pass_filter.set(server, &v),
+        (Setting::MotionDetection, Value::Bool(v)) => s.motion_detection.set(server, &v),
+        (Setting::StreamMode, Value::U8(v)) => s.stream_mode.set(server, &v),
+        (Setting::Security, Value::U8(v)) => s.security.set(server, &v),
+        (Setting::BatteryDivider, Value::F32(v)) => s.battery_divider.set(server, &v),
+        (Setting::BroadcastInterval, Value::U16(v)) => s.broadcast_interval.set(server, &v),
+        (Setting::PeriodicInterval, Value::U16(v)) => s.periodic_interval.set(server, &v),
+        (Setting::Recording, Value::Bool(v)) => s.recording.set(server, &v),
+        (Setting::PreTrigger, Value::U16(v)) => s.pre_trigger.set(server, &v),
+        _ => Ok(()),
+    };
+    match stored {
+        Ok(()) => CHANGES.immediate_publisher().publish_immediate(setting),
+        Err(_) => warn!("[settings] error storing {}", setting.name()),
+    }
+}
+
+/// Notify `conn` of every setting change, until the connection closes. Notifications only go out
+/// for characteristics the client subscribed to.
+pub async fn notify_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+    let Ok(mut changes) = CHANGES.subscriber() else {
+        warn!("[settings] no subscriber left for setting changes");
+        return;
+    };
+    loop {
+        let setting = match changes.next_message().await {
+            WaitResult::Message(setting) => setting,
+            WaitResult::Lagged(missed) => {
+                warn!("[settings] missed {} setting changes", missed);
+                continue;
+            }
+        };
+        if let Err(e) = notify(server, conn, setting).await {
+            warn!("[settings] error notifying {}: {:?}", setting.name(), e);
+        }
+    }
+}
+
+async fn notify<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    setting: Setting,
+) -> Result<(), Error> {
+    let s = &server.imu_service;
+    let Some(value) = get(server, setting) else {
+        return Ok(());
+    };
+    match (setting, value) {
+        (Setting::MotionReadDuration, Value::U16(v)) => {
+            s.motion_read_duration.notify(conn, &v).await
+        }
+        (Setting::MotionSampleInterval, Value::U64(v)) => {
+            s.motion_sample_interval.notify(conn, &v).await
+        }
+        (Setting::ContinuousSampleInterval, Value::U64(v)) => {
+            s.continuous_sample_interval.notify(conn, &v).await
+        }
+        (Setting::PlaySound, Value::Bool(v)) => s.play_sound.notify(conn, &v).await,
+        (Setting::AccelScale, Value::U8(v)) => s.accel_scale.notify(conn, &v).await,
+        (Setting::GyroScale, Value::U8(v)) => s.gyro_scale.notify(conn, &v).await,
+        (Setting::BuzzFrequencyMode, Value::U8(v)) => s.buzz_frequency_mode.notify(conn, &v).await,
+        (Setting::MinBuzzValue, Value::F32(v)) => s.min_buzz_value.notify(conn, &v).await,
+        (Setting::MaxBuzzValue, Value::F32(v)) => s.max_buzz_value.notify(conn, &v).await,
+        (Setting::Filter, Value::U8(v)) => s.digital_low_pass_filter.notify(conn, &v).await,
+        (Setting::MotionDetection, Value::Bool(v)) => s.motion_detection.notify(conn, &v).await,
+        (Setting::StreamMode, Value::U8(v)) => s.stream_mode.notify(conn, &v).await,
+        (Setting::Security, Value::U8(v)) => s.security.notify(conn, &v).await,
+        (Setting::BatteryDivider, Value::F32(v)) => s.battery_divider.notify(conn, &v).await,
+        (Setting::BroadcastInterval, Value::U16(v)) => s.broadcast_interval.notify(conn, &v).await,
+        (Setting::PeriodicInterval, Value::U16(v)) => s.periodic_interval.notify(conn, &v).await,
+        (Setting::Recording, Value::Bool(v)) => s.recording.notify(conn, &v).await,
+        (Setting::PreTrigger, Value::U16(v)) => s.pre_trigger.notify(conn, &v).await,
+        _ => Ok(()),
+    }
+}
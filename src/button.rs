@@ -0,0 +1,23 @@
+//! The BOOT button, held to open a pairing window.
+use defmt::info;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::Input;
+
+use crate::shared::PAIRING_WINDOW;
+
+/// How long the button has to be held down.
+const HOLD: Duration = Duration::from_secs(3);
+
+/// Open a pairing window whenever the (active low) button is held for [`HOLD`].
+#[embassy_executor::task]
+pub async fn pairing_button_task(mut button: Input<'static>) {
+    loop {
+        button.wait_for_low().await;
+        if let Either::Second(()) = select(button.wait_for_high(), Timer::after(HOLD)).await {
+            info!("[button] held, opening a pairing window");
+            PAIRING_WINDOW.signal(());
+            button.wait_for_high().await;
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::error_log;
 use crate::shared::{BUZZ_FREQUENCY, MAX_BUZZ_VALUE, MIN_BUZZ_VALUE, PLAY_SOUND};
 use defmt::{error, info};
 use esp_hal::gpio::AnyPin;
@@ -17,9 +18,11 @@ pub async fn buzzer_task(mut ledc: Ledc<'static>, gpio: AnyPin<'static>) {
     );
     buzzer.play(1).unwrap_or_else(|e| {
         error!("Failed to initialize buzzer: {}", e);
+        error_log::record("buzzer init failed");
     });
     buzzer.play(0).unwrap_or_else(|e| {
         error!("Failed to initialize buzzer: {}", e);
+        error_log::record("buzzer init failed");
     });
 
     let mut min_value = MIN_BUZZ_VALUE.wait().await;
@@ -46,6 +49,7 @@ pub async fn buzzer_task(mut ledc: Ledc<'static>, gpio: AnyPin<'static>) {
             // Play a tone based on the frequency
             buzzer.play(freq).unwrap_or_else(|e| {
                 error!("Failed to play tone: {}", e);
+                error_log::record("buzzer tone failed");
             });
             play_sound = PLAY_SOUND.try_take().unwrap_or(play_sound);
         }
@@ -0,0 +1,47 @@
+//! The most recent errors, kept in RAM so they can be read back over the console.
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::Deque;
+
+/// Number of errors kept; older ones are dropped.
+const CAPACITY: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct ErrorEntry {
+    /// Milliseconds since boot.
+    pub uptime_ms: u32,
+    pub message: &'static str,
+}
+
+static ENTRIES: Mutex<CriticalSectionRawMutex, RefCell<Deque<ErrorEntry, CAPACITY>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+/// Errors recorded since boot, including the dropped ones.
+static TOTAL: AtomicU32 = AtomicU32::new(0);
+
+/// Remember an error. Call it next to the `error!` that logs the details.
+pub fn record(message: &'static str) {
+    let entry = ErrorEntry {
+        uptime_ms: Instant::now().as_millis() as u32,
+        message,
+    };
+    ENTRIES.lock(|entries| {
+        let mut entries = entries.borrow_mut();
+        if entries.is_full() {
+            entries.pop_front();
+        }
+        entries.push_back(entry).ok();
+    });
+    TOTAL.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn total() -> u32 {
+    TOTAL.load(Ordering::Relaxed)
+}
+
+/// A copy of the kept errors, oldest first.
+pub fn entries() -> Deque<ErrorEntry, CAPACITY> {
+    ENTRIES.lock(|entries| entries.borrow().clone())
+}
@@ -9,6 +9,7 @@ pub enum LedState {
     Error,
     Calibrating,
     Reading,
+    LowBattery,
     Off,
 }
 pub enum LedPhase {
@@ -55,6 +56,12 @@ const READING_PHASES: &[LedPhase] = &[
     LedPhase::On(Duration::from_millis(200)),
     LedPhase::Off(Duration::from_millis(200)),
 ];
+const LOW_BATTERY_PHASES: &[LedPhase] = &[
+    LedPhase::On(Duration::from_millis(50)),
+    LedPhase::Off(Duration::from_millis(150)),
+    LedPhase::On(Duration::from_millis(50)),
+    LedPhase::Off(Duration::from_millis(3750)),
+];
 const OFF_PHASES: &[LedPhase] = &[];
 
 impl LedSignaler for DefaultLedSignaler {
@@ -76,6 +83,10 @@ impl LedSignaler for DefaultLedSignaler {
                 phases: READING_PHASES,
                 repeat: true,
             },
+            LedState::LowBattery => LedPattern {
+                phases: LOW_BATTERY_PHASES,
+                repeat: true,
+            },
             LedState::Off => LedPattern {
                 phases: OFF_PHASES,
                 repeat: false,
@@ -1,6 +1,12 @@
 #![no_std]
+pub mod battery;
 pub mod ble;
+pub mod button;
 pub mod buzzer;
+pub mod error_log;
 pub mod led;
+pub mod ota;
+pub mod recording;
 pub mod sensor;
 pub mod shared;
+pub mod storage;
@@ -0,0 +1,145 @@
+//! Over-the-air updates: the OTA app partition a new image is written to, and the OTA data that
+//! selects which partition boots. Receiving the image is up to `ble::dfu`.
+//!
+//! A new image boots in the `New` state and confirms itself once it ran for [`CONFIRM_AFTER`]. If
+//! it is reset before that, a bootloader built with rollback support boots the previous image.
+use defmt::{info, warn, Debug2Format};
+use embassy_time::{Duration, Timer};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::ota::{Ota, OtaImageState, Slot};
+use esp_bootloader_esp_idf::partitions::{
+    self, AppPartitionSubType, DataPartitionSubType, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use mpu_protocol::dfu::{ImageFlash, SECTOR_SIZE};
+
+use crate::error_log;
+use crate::storage::{self, Flash, StorageError};
+
+/// How long a new image has to run before it is kept.
+pub const CONFIRM_AFTER: Duration = Duration::from_secs(60);
+
+/// The OTA app partition that is not running, where the next image goes.
+pub struct ImagePartition {
+    offset: u32,
+    len: u32,
+    slot: Slot,
+}
+
+impl ImagePartition {
+    pub fn inactive() -> Result<ImagePartition, StorageError> {
+        // Without OTA data the first OTA partition boots, there is no factory partition.
+        let slot = match with_ota(running_slot)? {
+            Slot::None | Slot::Slot0 => Slot::Slot1,
+            Slot::Slot1 => Slot::Slot0,
+        };
+        let kind = match slot {
+            Slot::Slot1 => AppPartitionSubType::Ota1,
+            _ => AppPartitionSubType::Ota0,
+        };
+        let (offset, len) = storage::find_partition(PartitionType::App(kind))?;
+        info!("[ota] writing to ota_{} at {:#x}", slot.number(), offset);
+        Ok(ImagePartition { offset, len, slot })
+    }
+}
+
+impl ImageFlash for ImagePartition {
+    type Error = StorageError;
+
+    fn capacity(&self) -> u32 {
+        self.len
+    }
+
+    fn write_sector(&mut self, offset: u32, data: &[u8; SECTOR_SIZE]) -> Result<(), StorageError> {
+        if offset + SECTOR_SIZE as u32 > self.len {
+            return Err(StorageError::OutOfBounds);
+        }
+        // Erases first: interrupts are off for tens of milliseconds, see `storage`.
+        Flash.write(self.offset + offset, data).inspect_err(|e| {
+            warn!("[ota] error writing at {:#x}: {:?}", offset, e);
+        })
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        if offset + buf.len() as u32 > self.len {
+            return Err(StorageError::OutOfBounds);
+        }
+        Flash.read(self.offset + offset, buf)
+    }
+
+    fn activate(&mut self) -> Result<(), StorageError> {
+        let slot = self.slot;
+        with_ota(|ota| {
+            ota.set_current_slot(slot)?;
+            ota.set_current_ota_state(OtaImageState::New)
+        })?;
+        info!("[ota] booting ota_{} from now on", slot.number());
+        Ok(())
+    }
+}
+
+/// Keep the running image once it ran for [`CONFIRM_AFTER`].
+#[embassy_executor::task]
+pub async fn confirm_task() {
+    Timer::after(CONFIRM_AFTER).await;
+    let confirmed = with_ota(|ota| {
+        if ota.current_slot()? == Slot::None {
+            return Ok(false);
+        }
+        match ota.current_ota_state()? {
+            OtaImageState::New | OtaImageState::PendingVerify => {
+                ota.set_current_ota_state(OtaImageState::Valid)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    });
+    match confirmed {
+        Ok(true) => info!("[ota] new image confirmed"),
+        Ok(false) => {}
+        Err(e) => {
+            warn!("[ota] error confirming the image: {:?}", e);
+            error_log::record("confirming the firmware image failed");
+        }
+    }
+}
+
+/// The slot that booted. After a rollback the OTA data still selects the rejected one.
+fn running_slot(ota: &mut Ota<'_, Flash>) -> Result<Slot, partitions::Error> {
+    let current = ota.current_slot()?;
+    if current == Slot::None {
+        return Ok(current);
+    }
+    Ok(match ota.current_ota_state()? {
+        OtaImageState::Invalid | OtaImageState::Aborted => current.next(),
+        _ => current,
+    })
+}
+
+/// Run `f` on the OTA data partition.
+fn with_ota<T>(
+    f: impl FnOnce(&mut Ota<'_, Flash>) -> Result<T, partitions::Error>,
+) -> Result<T, StorageError> {
+    let mut table = [0; PARTITION_TABLE_MAX_LEN];
+    let entry = partitions::read_partition_table(&mut Flash, &mut table)
+        .ok()
+        .and_then(|table| {
+            table
+                .find_partition(PartitionType::Data(DataPartitionSubType::Ota))
+                .ok()
+                .flatten()
+        })
+        .ok_or_else(|| {
+            warn!("[ota] no otadata partition");
+            StorageError::NoPartition
+        })?;
+    let mut flash = Flash;
+    let mut region = entry.as_embedded_storage(&mut flash);
+    let mut ota = Ota::new(&mut region).map_err(|e| {
+        warn!("[ota] unusable otadata partition: {:?}", Debug2Format(&e));
+        StorageError::NoPartition
+    })?;
+    f(&mut ota).map_err(|e| {
+        warn!("[ota] error accessing otadata: {:?}", Debug2Format(&e));
+        StorageError::Flash
+    })
+}
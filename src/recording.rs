@@ -0,0 +1,248 @@
+//! On-device recording: every read window is written to the `log` partition while `recording` is
+//! on, as a session of the log described in [`mpu_protocol::recording`].
+//!
+//! Samples are recorded as compressed combined frames, the same as the stream carries, whatever
+//! the stream mode. Flash writes stall the device, see the README.
+use defmt::{error, info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::pubsub::WaitResult;
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions::{DataPartitionSubType, PartitionType};
+use mpu_protocol::config::ConfigPacket;
+use mpu_protocol::frame::{FrameWriter, FLAG_COMPRESSED};
+use mpu_protocol::recording::{LogError, LogFlash, Recorder, SessionHeader, BLOCK_LEN};
+
+use crate::battery;
+use crate::ble::notify_task::{combined_header, same_frame, MAX_FRAME_LEN};
+use crate::error_log;
+use crate::shared::{
+    SensorData, SensorSubscriber, StreamingReceiver, EPOCH, MOTION_SAMPLE_INTERVAL_MS, RECORDING,
+    SENSOR_CHANNEL, SENSOR_CONFIG, STREAMING,
+};
+use crate::storage::{self, Flash, StorageError};
+
+/// The log, mounted by [`recorder_task`].
+pub static RECORDER: Mutex<CriticalSectionRawMutex, Recorder> = Mutex::new(Recorder::new());
+
+/// The `log` data partition.
+pub struct LogPartition {
+    offset: u32,
+    len: u32,
+}
+
+impl LogPartition {
+    pub fn find() -> Result<LogPartition, StorageError> {
+        let (offset, len) =
+            storage::find_partition(PartitionType::Data(DataPartitionSubType::Undefined))?;
+        Ok(LogPartition { offset, len })
+    }
+}
+
+impl LogFlash for LogPartition {
+    type Error = StorageError;
+
+    fn capacity(&self) -> u32 {
+        self.len
+    }
+
+    fn write_sector(&mut self, offset: u32, data: &[u8; BLOCK_LEN]) -> Result<(), StorageError> {
+        if offset + BLOCK_LEN as u32 > self.len {
+            return Err(StorageError::OutOfBounds);
+        }
+        // Erases first: interrupts are off for tens of milliseconds, see `storage`.
+        Flash.write(self.offset + offset, data).inspect_err(|e| {
+            warn!("[recorder] error writing at {:#x}: {:?}", offset, e);
+        })
+    }
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), StorageError> {
+        if offset + BLOCK_LEN as u32 > self.len {
+            return Err(StorageError::OutOfBounds);
+        }
+        // Interrupts are off for tens of milliseconds, see `storage`.
+        Flash.erase_sector(self.offset + offset).inspect_err(|e| {
+            warn!("[recorder] error erasing at {:#x}: {:?}", offset, e);
+        })
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        if offset + buf.len() as u32 > self.len {
+            return Err(StorageError::OutOfBounds);
+        }
+        Flash.read(self.offset + offset, buf)
+    }
+}
+
+/// Record each read window that starts while `recording` is on, until it ends or the log is full.
+#[embassy_executor::task]
+pub async fn recorder_task() {
+    let mut partition = match LogPartition::find() {
+        Ok(partition) => partition,
+        Err(e) => {
+            warn!(
+                "[recorder] no log partition, recording is unavailable: {:?}",
+                e
+            );
+            return;
+        }
+    };
+    {
+        let mut recorder = RECORDER.lock().await;
+        if let Err(e) = recorder.mount(&mut partition) {
+            warn!("[recorder] error mounting the log: {:?}", e);
+            error_log::record("mounting the recording log failed");
+            return;
+        }
+        info!(
+            "[recorder] log uses {} of {} blocks",
+            recorder.used(),
+            recorder.capacity()
+        );
+    }
+    let Some(streaming) = STREAMING.receiver() else {
+        error!("[recorder] no receiver left for the read windows");
+        return;
+    };
+    // Subscribed for good: the pre-trigger samples are published as soon as a window starts.
+    let Ok(queue) = SENSOR_CHANNEL.subscriber() else {
+        error!("[recorder] no subscriber left for the sensor stream");
+        return;
+    };
+    let mut window = Window {
+        queue,
+        streaming,
+        ended: true,
+    };
+    loop {
+        window.wait_start().await;
+        if !*RECORDING.lock().await {
+            continue;
+        }
+        let header = SessionHeader {
+            // Timestamps count from the epoch, which may be before the window started.
+            start_ms: *EPOCH.lock().await,
+            sample_interval_ms: battery::limit_sample_interval(
+                *MOTION_SAMPLE_INTERVAL_MS.lock().await,
+            )
+            .min(u16::MAX as u64) as u16,
+            config: ConfigPacket::from(SENSOR_CONFIG.try_get().unwrap_or_default()),
+        };
+        match RECORDER.lock().await.start(&mut partition, header) {
+            Ok(id) => info!("[recorder] recording session {}", id),
+            Err(e) => {
+                report(e);
+                continue;
+            }
+        }
+        let result = match record_session(&mut partition, &mut window).await {
+            Ok(()) => RECORDER.lock().await.finish(&mut partition),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => info!("[recorder] session recorded"),
+            Err(e) => report(e),
+        }
+        // After a full log the rest of the window is not recorded.
+        while !window.ended {
+            window.next().await;
+        }
+    }
+}
+
+/// The samples of the read windows.
+struct Window {
+    queue: SensorSubscriber,
+    streaming: StreamingReceiver,
+    ended: bool,
+}
+
+impl Window {
+    /// Wait for the next window to start, dropping the samples taken meanwhile.
+    async fn wait_start(&mut self) {
+        loop {
+            // Looked at first, the window's first samples are queued right behind its start.
+            if let Either::First(true) =
+                select(self.streaming.changed(), self.queue.next_message_pure()).await
+            {
+                self.ended = false;
+                return;
+            }
+        }
+    }
+
+    /// The next sample, or `None` once the window ended and every sample was taken.
+    async fn next(&mut self) -> Option<SensorData> {
+        loop {
+            // The window's samples are all published before it ends.
+            while let Some(result) = self.queue.try_next_message() {
+                match result {
+                    WaitResult::Message(data) => return Some(data),
+                    WaitResult::Lagged(missed) => lagged(missed),
+                }
+            }
+            if self.ended {
+                return None;
+            }
+            match select(self.queue.next_message(), self.streaming.changed()).await {
+                Either::First(WaitResult::Message(data)) => return Some(data),
+                Either::First(WaitResult::Lagged(missed)) => lagged(missed),
+                Either::Second(streaming) => self.ended = !streaming,
+            }
+        }
+    }
+}
+
+/// Append frames of the samples of one read window until it ends.
+async fn record_session(partition: &mut LogPartition, window: &mut Window) -> Result<(), LogError> {
+    let mut frame = [0; MAX_FRAME_LEN];
+    let mut sequence: u16 = 0;
+    // Sample that had to start a new frame because it didn't fit or its scales changed.
+    let mut pending: Option<SensorData> = None;
+    loop {
+        let first = match pending.take() {
+            Some(first) => first,
+            None => match window.next().await {
+                Some(first) => first,
+                None => return Ok(()),
+            },
+        };
+        let header = combined_header(&first, sequence, FLAG_COMPRESSED);
+        // The header and a keyframe always fit.
+        let mut writer = FrameWriter::new(&mut frame, header).unwrap();
+        writer.push(&first.combined_sample()).ok();
+        let mut last_ms = first.timestamp_ms;
+        while let Some(sample) = window.next().await {
+            if !same_frame(&first, &sample) || writer.push(&sample.combined_sample()).is_err() {
+                pending = Some(sample);
+                break;
+            }
+            last_ms = sample.timestamp_ms;
+        }
+        let samples = writer.sample_count() as u16;
+        let len = writer.finish();
+        RECORDER
+            .lock()
+            .await
+            .append(partition, &frame[..len], samples, last_ms)?;
+        sequence = sequence.wrapping_add(1);
+    }
+}
+
+fn lagged(missed: u64) {
+    warn!("[recorder] fell behind, {} samples not recorded", missed);
+}
+
+fn report(e: LogError) {
+    match e {
+        LogError::Full => {
+            warn!("[recorder] the log is full, nothing more is recorded");
+            error_log::record("recording log full");
+        }
+        e => {
+            warn!("[recorder] error recording: {:?}", e);
+            error_log::record("recording failed");
+        }
+    }
+}
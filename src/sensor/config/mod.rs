@@ -1,5 +1,6 @@
 use defmt::info;
 use mpu6050_dmp::{accel::AccelFullScale, config::DigitalLowPassFilter, gyro::GyroFullScale};
+use mpu_protocol::config::{ConfigError, ConfigPacket};
 pub mod buzzer_config;
 
 use crate::{
@@ -7,6 +8,8 @@ use crate::{
     shared::{
         ACCEL_SCALE,
         BUZZ_FREQUENCY_MODE,
+        CONFIG_APPLIED,
+        CONFIG_PACKET,
         DEFAULT_ACCEL_SCALE,
         DEFAULT_BUZZ_FREQUENCY_MODE,
         DEFAULT_FILTER,
@@ -14,9 +17,11 @@ use crate::{
         DEFAULT_MOTION_DETECTION,
         FILTER,
         GYRO_SCALE,
-        MOTION_DETECTION, //SENSOR_CHANNEL,
+        MOTION_DETECTION,
+        SENSOR_CONFIG, //SENSOR_CHANNEL,
     },
 };
+#[derive(Clone, Copy)]
 pub struct SensorConfig {
     pub accel_scale: AccelFullScale,
     pub gyro_scale: GyroFullScale,
@@ -25,18 +30,52 @@ pub struct SensorConfig {
     pub motion_detection: bool, // use 0 = false, 1 = true
 }
 
-impl Into<[u8; 5]> for SensorConfig {
-    fn into(self) -> [u8; 5] {
+impl From<SensorConfig> for [u8; 5] {
+    fn from(config: SensorConfig) -> Self {
         [
-            self.accel_scale as u8,
-            self.gyro_scale as u8,
-            self.buzz_frequency_mode as u8,
-            self.filter as u8,
-            self.motion_detection as u8,
+            config.accel_scale as u8,
+            config.gyro_scale as u8,
+            config.buzz_frequency_mode as u8,
+            config.filter as u8,
+            config.motion_detection as u8,
         ]
     }
 }
 
+impl From<SensorConfig> for ConfigPacket {
+    fn from(config: SensorConfig) -> Self {
+        let [accel_scale, gyro_scale, buzz_frequency_mode, filter, motion_detection] =
+            config.into();
+        ConfigPacket {
+            accel_scale,
+            gyro_scale,
+            buzz_frequency_mode,
+            filter,
+            motion_detection: motion_detection != 0,
+        }
+    }
+}
+
+/// Checks every field, so a packet is either applied as a whole or not at all.
+impl TryFrom<ConfigPacket> for SensorConfig {
+    type Error = ConfigError;
+
+    fn try_from(packet: ConfigPacket) -> Result<Self, Self::Error> {
+        let field = ConfigError::Field;
+        Ok(SensorConfig {
+            accel_scale: AccelFullScale::from_u8(packet.accel_scale)
+                .ok_or(field(ConfigPacket::ACCEL_SCALE_OFFSET))?,
+            gyro_scale: GyroFullScale::from_u8(packet.gyro_scale)
+                .ok_or(field(ConfigPacket::GYRO_SCALE_OFFSET))?,
+            buzz_frequency_mode: BuzzFrequencyMode::from_u8(packet.buzz_frequency_mode)
+                .ok_or(field(ConfigPacket::BUZZ_FREQUENCY_MODE_OFFSET))?,
+            filter: DigitalLowPassFilter::from_u8(packet.filter)
+                .ok_or(field(ConfigPacket::FILTER_OFFSET))?,
+            motion_detection: packet.motion_detection,
+        })
+    }
+}
+
 impl SensorConfig {
     pub fn apply_buzz_frequency_mode(&mut self, mode_source: Option<BuzzFrequencyMode>) {
         if let Some(new_mode) = mode_source {
@@ -88,6 +127,20 @@ impl SensorConfig {
             }
         }
     }
+    /// Apply a whole config packet, then report the config in effect.
+    pub async fn apply_packet<'a>(&mut self, sensor: &mut Sensor<'a>, config: SensorConfig) {
+        info!("Applying config packet");
+        self.apply_buzz_frequency_mode(Some(config.buzz_frequency_mode));
+        self.apply_accel_scale(sensor, Some(config.accel_scale))
+            .await;
+        self.apply_gyro_scale(sensor, Some(config.gyro_scale)).await;
+        self.apply_filter(sensor, Some(config.filter)).await;
+        self.apply_motion_detection(Some(config.motion_detection));
+        publish(self);
+        CONFIG_APPLIED
+            .immediate_publisher()
+            .publish_immediate(*self);
+    }
     pub fn apply_motion_detection(&mut self, motion_detection: Option<bool>) {
         if let Some(new_detection) = motion_detection {
             if new_detection != self.motion_detection {
@@ -160,8 +213,23 @@ pub async fn update_sensor_settings<'a>(sensor: &mut Sensor<'a>, sensor_config:
     sensor_config.apply_filter(sensor, FILTER.try_take()).await;
 
     sensor_config.apply_motion_detection(MOTION_DETECTION.try_take());
+
+    // Applied last, so a packet overrides single settings written before it.
+    if let Some(config) = CONFIG_PACKET.try_take() {
+        sensor_config.apply_packet(sensor, config).await;
+    }
+    publish(sensor_config);
+}
+
+/// Publish `sensor_config` as the config in effect, if it changed.
+fn publish(sensor_config: &SensorConfig) {
+    let config = *sensor_config;
+    SENSOR_CONFIG.sender().send_if_modified(|current| {
+        let changed = current.map(<[u8; 5]>::from) != Some(config.into());
+        *current = Some(config);
+        changed
+    });
 }
-// could be rewritten as a single signal of type SENSORCONFIGPACKET, and apply all at once?
 
 impl Default for SensorConfig {
     fn default() -> Self {
@@ -1,20 +1,33 @@
+use core::pin::pin;
+
 use defmt::{debug, error, info, warn, Debug2Format};
 use embassy_futures::select::{select, select3, Either, Either3};
-use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use embassy_time::{Delay, Duration, Instant, Timer, WithTimeout};
 use esp_hal::gpio::Input;
+use mpu6050_dmp::accel::Accel;
+use mpu6050_dmp::calibration::{CalibrationParameters, ReferenceGravity};
+use mpu6050_dmp::gyro::Gyro;
+use mpu_protocol::pre_trigger::PreTrigger;
 
 use crate::{
+    battery,
+    ble::broadcast,
+    error_log,
     led::LedState,
     sensor::{
         config::{buzzer_config::compute_buzz_frequency, update_sensor_settings, SensorConfig},
         Sensor,
     },
     shared::{
-        SensorData, BUZZ_FREQUENCY, CONTINUOUS_SAMPLE_INTERVAL_MS, EPOCH, LED_STATE, MARK_EPOCH,
-        MOTION_DETECTION, MOTION_READ_DURATION_S, MOTION_SAMPLE_INTERVAL_MS, READ, SENSOR_CHANNEL,
+        SensorData, BUZZ_FREQUENCY, CALIBRATE, CONFIG_PACKET, CONTINUOUS_SAMPLE_INTERVAL_MS, EPOCH,
+        LED_STATE, MARK_EPOCH, MOTION_DETECTION, MOTION_READ_DURATION_S, MOTION_SAMPLE_INTERVAL_MS,
+        PRE_TRIGGER_MS, READ, SENSOR_CHANNEL, STREAMING, STREAM_MODE,
     },
 };
 
+/// How often settings are picked up between read windows while the pre-trigger buffer is filled.
+const SETTINGS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 #[embassy_executor::task]
 pub async fn motion_reading(
     mut sensor: Sensor<'static>,
@@ -23,23 +36,33 @@ pub async fn motion_reading(
 ) {
     info!("Starting motion reading");
     info!("Waiting for motion detection interrupt or READ signal");
+    let mut pre_trigger = PreTrigger::new();
+    let mut last_report = Instant::from_ticks(0);
 
     loop {
-        let min_interval = *CONTINUOUS_SAMPLE_INTERVAL_MS.lock().await as u64;
+        let min_interval =
+            battery::limit_sample_interval(*CONTINUOUS_SAMPLE_INTERVAL_MS.lock().await);
         update_sensor_settings(&mut sensor, &mut sensor_config).await;
-
-        info!(
-            "Waiting: INT (high->low), READ==true, or {}ms timeout",
-            min_interval
-        );
+        // Fill the pre-trigger buffer between windows, only the motion interrupt needs it.
+        let pre_interval = if *PRE_TRIGGER_MS.lock().await != 0 && sensor_config.motion_detection {
+            battery::limit_sample_interval(*MOTION_SAMPLE_INTERVAL_MS.lock().await)
+        } else {
+            pre_trigger.clear();
+            0
+        };
 
         // Build the three competing futures:
-        let timer_fut = match min_interval {
-            0 => {
-                Timer::after(Duration::from_secs(60)) // check settings after 1 sec
+        let timeout = match (min_interval, pre_interval) {
+            (0, 0) => {
+                Duration::from_secs(60) // check settings after 1 sec
             }
-            _ => Timer::after(Duration::from_millis(min_interval)),
+            (_, 0) => Duration::from_millis(min_interval),
+            _ => Duration::from_millis(pre_interval),
         };
+        debug!(
+            "Waiting: INT (high->low), READ==true, or {}ms timeout",
+            timeout.as_millis()
+        );
 
         // Motion INT: wait for high, then low (edge cycle)
         let motion_fut = async {
@@ -65,35 +88,98 @@ pub async fn motion_reading(
 
         // Optional: you can do this here or inside each branch before sampling
 
-        match select3(timer_fut, motion_fut, read_true_fut).await {
-            // 1) Periodic timeout: take one sample and loop
-            Either3::First(_) => {
-                if min_interval != 0 {
-                    report_motion(&mut sensor, &sensor_config).await;
+        let request_fut = select(CALIBRATE.wait(), CONFIG_PACKET.wait());
+
+        let event = {
+            // Kept alive across the pre-trigger samples, so the motion interrupt stays armed
+            // while the sensor is read and a short pulse is not missed.
+            let mut events = pin!(select3(motion_fut, read_true_fut, request_fut));
+            let settings_due = Instant::now() + SETTINGS_CHECK_INTERVAL;
+            loop {
+                match select(Timer::after(timeout), &mut events).await {
+                    // 1) Periodic timeout: take one sample
+                    Either::First(_) => {
+                        let report_due = min_interval != 0
+                            && last_report.elapsed() >= Duration::from_millis(min_interval);
+                        if report_due {
+                            // Not kept for the pre-trigger buffer as well, it was sent already.
+                            report_motion(&mut sensor, &sensor_config, min_interval).await;
+                            last_report = Instant::now();
+                        } else if pre_interval != 0 {
+                            if let Some((_, _, data)) =
+                                read_sample(&mut sensor, &sensor_config, pre_interval, 0).await
+                            {
+                                pre_trigger.push(data.timestamp_ms, data);
+                            }
+                        }
+                        // Pick up setting changes, while filling the pre-trigger buffer only
+                        // now and then.
+                        if pre_interval == 0 || Instant::now() >= settings_due {
+                            break None;
+                        }
+                    }
+                    Either::Second(event) => break Some(event),
                 }
-                continue;
             }
+        };
+
+        match event {
+            None => continue,
 
             // 2) Motion-triggered read window
-            Either3::Second(_) => {
-                run_read_window(&mut sensor, &mut sensor_config, /*manual*/ false).await;
+            Some(Either3::First(_)) => {
+                run_read_window(
+                    &mut sensor,
+                    &mut sensor_config,
+                    &mut pre_trigger,
+                    /*manual*/ false,
+                )
+                .await;
             }
 
             // 3) Manual READ-triggered read window
-            Either3::Third(_) => {
-                run_read_window(&mut sensor, &mut sensor_config, /*manual*/ true).await;
+            Some(Either3::Second(_)) => {
+                run_read_window(
+                    &mut sensor,
+                    &mut sensor_config,
+                    &mut pre_trigger,
+                    /*manual*/ true,
+                )
+                .await;
                 // Auto-reset READ back to false at the end of the window
                 READ.signal(false);
             }
+
+            // 4) Calibration requested from the console
+            Some(Either3::Third(Either::First(_))) => calibrate(&mut sensor, &sensor_config).await,
+
+            // 5) Config packet written: apply it now rather than at the next timeout
+            Some(Either3::Third(Either::Second(config))) => {
+                sensor_config.apply_packet(&mut sensor, config).await
+            }
         }
     }
 }
 
-async fn run_read_window(sensor: &mut Sensor<'_>, sensor_config: &mut SensorConfig, manual: bool) {
+/// Stream samples until no motion was seen for the read duration. A window opened by the motion
+/// interrupt (`manual` false) starts with the pre-trigger buffer.
+async fn run_read_window(
+    sensor: &mut Sensor<'_>,
+    sensor_config: &mut SensorConfig,
+    pre_trigger: &mut PreTrigger<SensorData>,
+    manual: bool,
+) {
     let duration_s = *MOTION_READ_DURATION_S.lock().await as u64;
 
-    // Reset EPOCH to "now"
-    *EPOCH.lock().await = embassy_time::Instant::now().as_millis() as u32;
+    // Reset EPOCH to "now", or to the first sample of the lead-up so timestamps stay positive.
+    let now_ms = embassy_time::Instant::now().as_millis() as u32;
+    if manual {
+        pre_trigger.clear();
+    }
+    let epoch = pre_trigger
+        .trim(now_ms, *PRE_TRIGGER_MS.lock().await)
+        .unwrap_or(now_ms);
+    *EPOCH.lock().await = epoch;
 
     info!(
         "Reading sensor data for {} seconds (trigger: {})",
@@ -101,6 +187,13 @@ async fn run_read_window(sensor: &mut Sensor<'_>, sensor_config: &mut SensorConf
         if manual { "READ" } else { "INT" }
     );
     LED_STATE.signal(LedState::Reading);
+    STREAMING.sender().send(true);
+    // Right after the window starts, so every subscriber sees them as part of it.
+    let publisher = SENSOR_CHANNEL.immediate_publisher();
+    for mut data in pre_trigger.drain() {
+        data.timestamp_ms -= epoch;
+        publisher.publish_immediate(data);
+    }
 
     let mut start = Instant::now();
     while Instant::now() - start < Duration::from_secs(duration_s) {
@@ -108,8 +201,9 @@ async fn run_read_window(sensor: &mut Sensor<'_>, sensor_config: &mut SensorConf
         update_sensor_settings(sensor, sensor_config).await; // could settings change wait for next read window?
 
         // One sample
-        report_motion(sensor, &*sensor_config).await;
-        let interval = Duration::from_millis(*MOTION_SAMPLE_INTERVAL_MS.lock().await as u64);
+        let interval_ms = battery::limit_sample_interval(*MOTION_SAMPLE_INTERVAL_MS.lock().await);
+        report_motion(sensor, &*sensor_config, interval_ms).await;
+        let interval = Duration::from_millis(interval_ms);
 
         // Extend window if motion continues
         if sensor_config.motion_detection {
@@ -121,10 +215,16 @@ async fn run_read_window(sensor: &mut Sensor<'_>, sensor_config: &mut SensorConf
                             info!("Motion detected, resetting start time");
                         }
                     }
-                    Err(e) => error!("Error when reading motion_check: {}", e),
+                    Err(e) => {
+                        error!("Error when reading motion_check: {}", e);
+                        error_log::record("motion check failed");
+                    }
                 },
 
-                Err(e) => error!("Timeout when reading motion_check: {}", e),
+                Err(e) => {
+                    error!("Timeout when reading motion_check: {}", e);
+                    error_log::record("motion check timed out");
+                }
             }
         }
 
@@ -154,36 +254,86 @@ async fn run_read_window(sensor: &mut Sensor<'_>, sensor_config: &mut SensorConf
     }
 
     info!("No more motion detected");
-    LED_STATE.signal(LedState::Ready);
+    LED_STATE.signal(battery::idle_led_state());
+    STREAMING.sender().send(false);
     BUZZ_FREQUENCY.signal(0.0); // Stop buzzer
 }
 
-async fn report_motion(sensor: &mut Sensor<'_>, sensor_config: &SensorConfig) {
-    let motion = sensor.motion6().await;
-    if let Ok((accel, gyro)) = motion {
-        let frequency = compute_buzz_frequency(&accel, &gyro, &sensor_config);
-
-        BUZZ_FREQUENCY.signal(frequency);
-        let data = SensorData {
-            accel_scale: sensor_config.accel_scale as u8,
-            accel_x: accel.x(),
-            accel_y: accel.y(),
-            accel_z: accel.z(),
-            gyro_scale: sensor_config.gyro_scale as u8,
-            gyro_x: gyro.x(),
-            gyro_y: gyro.y(),
-            gyro_z: gyro.z(),
-            timestamp_ms: embassy_time::Instant::now().as_millis() as u32 - *EPOCH.lock().await,
-        };
-        if SENSOR_CHANNEL.is_full() {
-            //remove oldest data
-            warn!("SENSOR_CHANNEL is full, popping oldest data");
-            SENSOR_CHANNEL.receive().await;
+/// Recalibrate the sensor offsets for the current scales. The sensor has to be kept still.
+async fn calibrate(sensor: &mut Sensor<'_>, sensor_config: &SensorConfig) {
+    info!("Calibrating Sensor");
+    LED_STATE.signal(LedState::Calibrating);
+    let params = CalibrationParameters::new(
+        sensor_config.accel_scale,
+        sensor_config.gyro_scale,
+        ReferenceGravity::Zero,
+    );
+    match sensor.calibrate(&mut Delay, &params).await {
+        Ok(_) => info!("Sensor Calibrated"),
+        Err(e) => {
+            error!("Error calibrating sensor: {:?}", Debug2Format(&e));
+            error_log::record("calibration failed");
         }
-        debug!("Reporting motion data: {:?}", Debug2Format(&data));
-        let send_result = SENSOR_CHANNEL.try_send(data);
-        if let Err(send_error) = send_result {
-            error!("Send error : {:?}", Debug2Format(&send_error));
-        };
     }
+    LED_STATE.signal(battery::idle_led_state());
+}
+
+/// Nominal rate for samples taken every `interval_ms`. Rates below 1 Hz are reported as 0.
+fn sample_rate_hz(interval_ms: u64) -> u16 {
+    match interval_ms {
+        0 => 0,
+        ms => (1000 / ms).min(u16::MAX as u64) as u16,
+    }
+}
+
+async fn report_motion(sensor: &mut Sensor<'_>, sensor_config: &SensorConfig, interval_ms: u64) {
+    let epoch = *EPOCH.lock().await;
+    let Some((accel, gyro, data)) = read_sample(sensor, sensor_config, interval_ms, epoch).await
+    else {
+        return;
+    };
+    let frequency = compute_buzz_frequency(&accel, &gyro, sensor_config);
+
+    BUZZ_FREQUENCY.signal(frequency);
+    broadcast::record(
+        &accel.scaled(sensor_config.accel_scale),
+        &gyro.scaled(sensor_config.gyro_scale),
+    );
+    debug!("Reporting motion data: {:?}", Debug2Format(&data));
+    // Connections that fell behind lose their oldest sample, see `notify_task`.
+    SENSOR_CHANNEL.immediate_publisher().publish_immediate(data);
+}
+
+/// Read one sample, timestamped in milliseconds since `epoch_ms`.
+async fn read_sample(
+    sensor: &mut Sensor<'_>,
+    sensor_config: &SensorConfig,
+    interval_ms: u64,
+    epoch_ms: u32,
+) -> Option<(Accel, Gyro, SensorData)> {
+    let motion = sensor.motion6().await;
+    let Ok((accel, gyro)) = motion else {
+        error!("Error reading motion: {:?}", Debug2Format(&motion));
+        error_log::record("sensor read failed");
+        return None;
+    };
+    let temperature = if STREAM_MODE.lock().await.temperature {
+        sensor.temperature().await.ok().map(|t| t.raw())
+    } else {
+        None
+    };
+    let data = SensorData {
+        accel_scale: sensor_config.accel_scale as u8,
+        accel_x: accel.x(),
+        accel_y: accel.y(),
+        accel_z: accel.z(),
+        gyro_scale: sensor_config.gyro_scale as u8,
+        gyro_x: gyro.x(),
+        gyro_y: gyro.y(),
+        gyro_z: gyro.z(),
+        timestamp_ms: embassy_time::Instant::now().as_millis() as u32 - epoch_ms,
+        sample_rate_hz: sample_rate_hz(interval_ms),
+        temperature,
+    };
+    Some((accel, gyro, data))
 }
@@ -1,17 +1,23 @@
+use core::sync::atomic::AtomicU32;
+
 use defmt::Format;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
 use embassy_sync::signal::Signal;
-use heapless::Vec;
+use embassy_sync::watch::{Receiver, Watch};
 use mpu6050_dmp::accel::AccelFullScale;
 use mpu6050_dmp::config::DigitalLowPassFilter;
 use mpu6050_dmp::gyro::GyroFullScale;
+use mpu_protocol::frame::{CombinedSample, Sample, StreamMode};
 
+use crate::battery::BatteryStatus;
+use crate::ble::CONNECTIONS_MAX;
 use crate::led::LedState;
 use crate::sensor::config::buzzer_config::BuzzFrequencyMode;
+use crate::sensor::config::SensorConfig;
 
-#[derive(Debug, Format)]
+#[derive(Debug, Format, Clone, Copy)]
 pub struct SensorData {
     pub accel_x: i16,
     pub accel_y: i16,
@@ -22,6 +28,8 @@ pub struct SensorData {
     pub gyro_z: i16,
     pub gyro_scale: u8,
     pub timestamp_ms: u32, // Milliseconds since read start - will overflow after ~49 days
+    pub sample_rate_hz: u16, // 0 when the sample was not taken periodically
+    pub temperature: Option<i16>, // Raw register value, only read for the combined stream
 }
 impl SensorData {
     pub const fn zero() -> Self {
@@ -35,35 +43,33 @@ impl SensorData {
             gyro_z: 0,
             gyro_scale: 0,
             timestamp_ms: 0,
+            sample_rate_hz: 0,
+            temperature: None,
         }
     }
-}
-pub trait ToBytes {
-    fn write_to_vec(&self, vec: &mut Vec<u8, 18>);
-}
-
-impl ToBytes for SensorData {
-    fn write_to_vec(&self, vec: &mut Vec<u8, 18>) {
-        vec.clear();
-
-        // accel_scale (u8)
-        vec.push(self.accel_scale).ok();
-
-        // accel_x/y/z (i16)
-        vec.extend_from_slice(&self.accel_x.to_le_bytes()).ok();
-        vec.extend_from_slice(&self.accel_y.to_le_bytes()).ok();
-        vec.extend_from_slice(&self.accel_z.to_le_bytes()).ok();
 
-        // gyro_scale (u8)
-        vec.push(self.gyro_scale).ok();
+    pub fn accel_sample(&self) -> Sample {
+        Sample {
+            timestamp_ms: self.timestamp_ms,
+            xyz: [self.accel_x, self.accel_y, self.accel_z],
+        }
+    }
 
-        // gyro_x/y/z (i16)
-        vec.extend_from_slice(&self.gyro_x.to_le_bytes()).ok();
-        vec.extend_from_slice(&self.gyro_y.to_le_bytes()).ok();
-        vec.extend_from_slice(&self.gyro_z.to_le_bytes()).ok();
+    pub fn combined_sample(&self) -> CombinedSample {
+        CombinedSample {
+            timestamp_ms: self.timestamp_ms,
+            accel: [self.accel_x, self.accel_y, self.accel_z],
+            gyro: [self.gyro_x, self.gyro_y, self.gyro_z],
+            temperature: self.temperature,
+            quaternion: None,
+        }
+    }
 
-        // timestamp_ms (u32)
-        vec.extend_from_slice(&self.timestamp_ms.to_le_bytes()).ok();
+    pub fn gyro_sample(&self) -> Sample {
+        Sample {
+            timestamp_ms: self.timestamp_ms,
+            xyz: [self.gyro_x, self.gyro_y, self.gyro_z],
+        }
     }
 }
 
@@ -79,8 +85,43 @@ pub const DEFAULT_BUZZ_FREQUENCY_MODE: BuzzFrequencyMode = BuzzFrequencyMode::Ac
 pub const DEFAULT_MIN_BUZZ_VALUE: f32 = 0.5;
 pub const DEFAULT_MAX_BUZZ_VALUE: f32 = 2.0;
 pub const DEFAULT_PLAY_SOUND: bool = false;
+/// Battery voltage over the voltage at the ADC pin, 2 for two equal resistors.
+pub const DEFAULT_BATTERY_DIVIDER: f32 = 2.0;
+pub const DEFAULT_BROADCAST_INTERVAL_MS: u16 = 0; // 0 means off.
+pub const DEFAULT_PERIODIC_INTERVAL_MS: u16 = 0; // 0 means off.
+pub const DEFAULT_RECORDING: bool = false;
+pub const DEFAULT_PRE_TRIGGER_MS: u16 = 0; // 0 means off.
+pub const DEFAULT_STREAM_MODE: StreamMode = StreamMode {
+    combined: false,
+    temperature: false,
+    quaternion: false,
+    compressed: false,
+    framed: false,
+};
 
-pub static SENSOR_CHANNEL: Channel<CriticalSectionRawMutex, SensorData, 100> = Channel::new();
+/// Samples a connection may fall behind before it loses the oldest.
+const SENSOR_CHANNEL_LEN: usize = 100;
+/// A subscriber per connection, one for the periodic advertising train and one for the recorder.
+const SENSOR_SUBSCRIBERS: usize = CONNECTIONS_MAX + 2;
+/// Samples for every connection.
+pub static SENSOR_CHANNEL: PubSubChannel<
+    CriticalSectionRawMutex,
+    SensorData,
+    SENSOR_CHANNEL_LEN,
+    SENSOR_SUBSCRIBERS,
+    0,
+> = PubSubChannel::new();
+pub type SensorSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    SensorData,
+    SENSOR_CHANNEL_LEN,
+    SENSOR_SUBSCRIBERS,
+    0,
+>;
+/// Samples a connection missed because it fell behind SENSOR_CHANNEL, summed over connections,
+/// since boot.
+pub static DROPPED_SAMPLES: AtomicU32 = AtomicU32::new(0);
 pub static LED_STATE: Signal<CriticalSectionRawMutex, LedState> = Signal::new();
 pub static MOTION_SAMPLE_INTERVAL_MS: Mutex<CriticalSectionRawMutex, u64> =
     Mutex::new(DEFAULT_MOTION_SAMPLE_INTERVAL_MS);
@@ -89,6 +130,22 @@ pub static CONTINUOUS_SAMPLE_INTERVAL_MS: Mutex<CriticalSectionRawMutex, u64> =
 pub static MOTION_READ_DURATION_S: Mutex<CriticalSectionRawMutex, u16> =
     Mutex::new(DEFAULT_MOTION_READ_DURATION_S);
 pub static EPOCH: Mutex<CriticalSectionRawMutex, u32> = Mutex::new(0);
+pub static BATTERY_DIVIDER: Mutex<CriticalSectionRawMutex, f32> =
+    Mutex::new(DEFAULT_BATTERY_DIVIDER);
+/// The latest battery reading, none without a battery.
+pub static BATTERY: Watch<CriticalSectionRawMutex, BatteryStatus, CONNECTIONS_MAX> = Watch::new();
+/// How often the broadcast summary is refreshed, for the advertiser.
+pub static BROADCAST_INTERVAL_MS: Watch<CriticalSectionRawMutex, u16, 1> =
+    Watch::new_with(DEFAULT_BROADCAST_INTERVAL_MS);
+/// Interval of the periodic advertising train, for the advertiser.
+pub static PERIODIC_INTERVAL_MS: Watch<CriticalSectionRawMutex, u16, 1> =
+    Watch::new_with(DEFAULT_PERIODIC_INTERVAL_MS);
+/// Whether read windows are recorded to flash, see `recording`.
+pub static RECORDING: Mutex<CriticalSectionRawMutex, bool> = Mutex::new(DEFAULT_RECORDING);
+/// Length of the pre-trigger buffer, see `mpu_protocol::pre_trigger`.
+pub static PRE_TRIGGER_MS: Mutex<CriticalSectionRawMutex, u16> = Mutex::new(DEFAULT_PRE_TRIGGER_MS);
+pub static STREAM_MODE: Mutex<CriticalSectionRawMutex, StreamMode> =
+    Mutex::new(DEFAULT_STREAM_MODE);
 pub static BUZZ_FREQUENCY: Signal<CriticalSectionRawMutex, f32> = Signal::new();
 pub static BUZZ_FREQUENCY_MODE: Signal<CriticalSectionRawMutex, BuzzFrequencyMode> = Signal::new();
 pub static MIN_BUZZ_VALUE: Signal<CriticalSectionRawMutex, f32> = Signal::new();
@@ -97,6 +154,27 @@ pub static PLAY_SOUND: Signal<CriticalSectionRawMutex, bool> = Signal::new();
 pub static ACCEL_SCALE: Signal<CriticalSectionRawMutex, AccelFullScale> = Signal::new();
 pub static GYRO_SCALE: Signal<CriticalSectionRawMutex, GyroFullScale> = Signal::new();
 pub static READ: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+/// A receiver per connection, and one for the recorder.
+const STREAMING_RECEIVERS: usize = CONNECTIONS_MAX + 1;
+/// Raised when a read window starts and cleared when it ends.
+pub static STREAMING: Watch<CriticalSectionRawMutex, bool, STREAMING_RECEIVERS> = Watch::new();
+pub type StreamingReceiver = Receiver<'static, CriticalSectionRawMutex, bool, STREAMING_RECEIVERS>;
 pub static MOTION_DETECTION: Signal<CriticalSectionRawMutex, bool> = Signal::new();
 pub static MARK_EPOCH: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Recalibrate the sensor offsets, once no read window is open.
+pub static CALIBRATE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Let any central connect and pair for a while, see `ble::security`.
+pub static PAIRING_WINDOW: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 pub static FILTER: Signal<CriticalSectionRawMutex, DigitalLowPassFilter> = Signal::new();
+/// A validated config packet, applied as a whole at the next sample boundary.
+pub static CONFIG_PACKET: Signal<CriticalSectionRawMutex, SensorConfig> = Signal::new();
+/// The config in effect after a config packet was applied, acked on every connection.
+pub static CONFIG_APPLIED: PubSubChannel<
+    CriticalSectionRawMutex,
+    SensorConfig,
+    1,
+    CONNECTIONS_MAX,
+    0,
+> = PubSubChannel::new();
+/// The sensor settings in effect, published by the sensor task whenever they change.
+pub static SENSOR_CONFIG: Watch<CriticalSectionRawMutex, SensorConfig, 1> = Watch::new();
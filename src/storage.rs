@@ -0,0 +1,219 @@
+//! Persistent storage in the `nvs` data partition of the flash.
+//!
+//! The firmware does not use ESP-IDF's NVS format. The partition is split into 4 KiB sectors
+//! instead, each owned by one [`Area`] which always rewrites it as a whole.
+//!
+//! [`Flash`] reaches the whole flash through the ROM routines, with interrupts off while one runs:
+//! the flash can't be read for code while it is busy. Erasing a sector holds them off for its
+//! whole duration, typically 30 to 50 ms and a few hundred at worst, so the BLE controller and
+//! every task wait that long. Programming is split into 256 byte pages of about a millisecond each.
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{warn, Debug2Format, Format};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_rom_sys::rom::spiflash::{
+    esp_rom_spiflash_erase_sector, esp_rom_spiflash_read, esp_rom_spiflash_unlock,
+    esp_rom_spiflash_write,
+};
+
+/// Size of a flash sector, the unit of erasing.
+pub const SECTOR_SIZE: usize = 4096;
+/// Size of a flash page, the most one program operation writes.
+const PAGE_SIZE: usize = 256;
+/// Bytes read per ROM call, through a buffer on the stack.
+const READ_CHUNK: usize = 256;
+
+/// The sectors of the `nvs` partition, by owner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Area {
+    /// Security mode and bonds, see `ble::security`.
+    Security = 0,
+    /// Custom device name, see `ble::name`.
+    Name = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum StorageError {
+    /// The partition table could not be read or lacks the partition.
+    NoPartition,
+    /// The partition is too small for the area, or the data for its sector.
+    OutOfBounds,
+    /// A flash routine in ROM failed.
+    Flash,
+    /// The sector buffer is in use, see [`SectorBuffer`].
+    Busy,
+}
+
+/// Read the start of `area` into `buf`. Never written areas read as `0xff`.
+pub fn read(area: Area, buf: &mut [u8]) -> Result<(), StorageError> {
+    let offset = area_offset(area, buf.len())?;
+    Flash.read(offset, buf)
+}
+
+/// Replace the contents of `area` with `data`.
+///
+/// Erasing a sector takes tens of milliseconds during which nothing else runs, so only write on
+/// rare events.
+pub fn write(area: Area, data: &[u8]) -> Result<(), StorageError> {
+    let offset = area_offset(area, data.len())?;
+    SECTOR.with(|sector| {
+        sector.0.fill(0xff);
+        sector.0[..data.len()].copy_from_slice(data);
+        program_sector(offset, sector)
+    })
+}
+
+/// Flash address of `area`, if `len` bytes fit in its sector.
+fn area_offset(area: Area, len: usize) -> Result<u32, StorageError> {
+    if len > SECTOR_SIZE {
+        return Err(StorageError::OutOfBounds);
+    }
+    let (start, size) = find_partition(PartitionType::Data(DataPartitionSubType::Nvs))?;
+    let offset = area as u32 * SECTOR_SIZE as u32;
+    if offset + SECTOR_SIZE as u32 > size {
+        return Err(StorageError::OutOfBounds);
+    }
+    Ok(start + offset)
+}
+
+/// Flash address and size of the first partition of type `kind`.
+pub(crate) fn find_partition(kind: PartitionType) -> Result<(u32, u32), StorageError> {
+    let mut table = [0; PARTITION_TABLE_MAX_LEN];
+    partitions::read_partition_table(&mut Flash, &mut table)
+        .ok()
+        .and_then(|table| table.find_partition(kind).ok().flatten())
+        .map(|partition| (partition.offset(), partition.len()))
+        .ok_or_else(|| {
+            warn!("[storage] no {:?} partition", Debug2Format(&kind));
+            StorageError::NoPartition
+        })
+}
+
+/// A buffer word aligned as the ROM routines need.
+#[repr(align(4))]
+struct Aligned<const N: usize>([u8; N]);
+
+impl<const N: usize> Aligned<N> {
+    fn words(&mut self) -> *mut u32 {
+        self.0.as_mut_ptr().cast()
+    }
+}
+
+type Sector = Aligned<SECTOR_SIZE>;
+
+/// The one buffer sectors are put together in before they are written, so no task needs 4 KiB
+/// of stack for it.
+static SECTOR: SectorBuffer = SectorBuffer {
+    taken: AtomicBool::new(false),
+    sector: UnsafeCell::new(Aligned([0xff; SECTOR_SIZE])),
+};
+
+struct SectorBuffer {
+    taken: AtomicBool,
+    sector: UnsafeCell<Sector>,
+}
+
+// Safety: `with` lends the sector to one caller at a time.
+unsafe impl Sync for SectorBuffer {}
+
+impl SectorBuffer {
+    /// Run `f` with the buffer. The flash is only used from tasks, which run one at a time, and `f`
+    /// doesn't yield, so it is never taken twice; if it were, that fails as [`StorageError::Busy`].
+    fn with<T>(
+        &self,
+        f: impl FnOnce(&mut Sector) -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        if self.taken.swap(true, Ordering::Acquire) {
+            return Err(StorageError::Busy);
+        }
+        // Safety: `taken` keeps out every other borrow until this one ends.
+        let result = f(unsafe { &mut *self.sector.get() });
+        self.taken.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// The whole flash, through the ROM routines.
+pub(crate) struct Flash;
+
+impl ReadStorage for Flash {
+    type Error = StorageError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), StorageError> {
+        // The ROM reads whole words from word aligned addresses, go through an aligned buffer.
+        let mut chunk = Aligned([0; READ_CHUNK]);
+        let mut done = 0;
+        while done < bytes.len() {
+            let address = offset + done as u32;
+            let start = address & !3;
+            let skip = (address - start) as usize;
+            let len = (READ_CHUNK - skip).min(bytes.len() - done);
+            let words = (skip + len).div_ceil(4) * 4;
+            rom(|| unsafe { esp_rom_spiflash_read(start, chunk.words(), words as u32) })?;
+            bytes[done..done + len].copy_from_slice(&chunk.0[skip..skip + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        // Only used through the partition table, which knows the partition sizes.
+        u32::MAX as usize
+    }
+}
+
+impl Storage for Flash {
+    /// Write `bytes` at `offset`, erasing the sectors they touch while keeping the rest of them.
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), StorageError> {
+        SECTOR.with(|sector| {
+            let mut done = 0;
+            while done < bytes.len() {
+                let address = offset + done as u32;
+                let start = address - address % SECTOR_SIZE as u32;
+                let skip = (address - start) as usize;
+                let len = (SECTOR_SIZE - skip).min(bytes.len() - done);
+                if skip != 0 || len != SECTOR_SIZE {
+                    rom(|| unsafe {
+                        esp_rom_spiflash_read(start, sector.words(), SECTOR_SIZE as u32)
+                    })?;
+                }
+                sector.0[skip..skip + len].copy_from_slice(&bytes[done..done + len]);
+                program_sector(start, sector)?;
+                done += len;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Erase the sector at `start` and write `sector` to it, a page at a time.
+fn program_sector(start: u32, sector: &mut Sector) -> Result<(), StorageError> {
+    rom(|| unsafe { esp_rom_spiflash_unlock() })?;
+    rom(|| unsafe { esp_rom_spiflash_erase_sector(start / SECTOR_SIZE as u32) })?;
+    for page in (0..SECTOR_SIZE).step_by(PAGE_SIZE) {
+        let words = sector.0[page..].as_mut_ptr().cast();
+        rom(|| unsafe { esp_rom_spiflash_write(start + page as u32, words, PAGE_SIZE as u32) })?;
+    }
+    Ok(())
+}
+
+impl Flash {
+    /// Erase the sector at `offset`, a multiple of [`SECTOR_SIZE`].
+    pub(crate) fn erase_sector(&mut self, offset: u32) -> Result<(), StorageError> {
+        rom(|| unsafe { esp_rom_spiflash_unlock() })?;
+        rom(|| unsafe { esp_rom_spiflash_erase_sector(offset / SECTOR_SIZE as u32) })
+    }
+}
+
+/// Run a ROM flash routine with interrupts off, so no other code uses the flash while it is busy.
+/// See the module documentation for how long that lasts.
+fn rom(routine: impl FnOnce() -> i32) -> Result<(), StorageError> {
+    match critical_section::with(|_| routine()) {
+        0 => Ok(()),
+        _ => Err(StorageError::Flash),
+    }
+}